@@ -1,18 +1,319 @@
 use axum::body::Bytes;
+use blake2::{Blake2s256, Digest};
+use bytes::BytesMut;
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
+use futures::future;
+use futures::stream::{self, StreamExt};
+use infer::MatcherType;
+use orion::hazardous::aead::xchacha20poly1305;
 use rand::distributions::{Alphanumeric, DistString};
+use rand::RngCore;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crate::error::Error as RestError;
 use crate::state::Keys;
+use crate::storage::trait_storage::ObjectStream;
+
+// Encrypted objects are written as a random nonce prefix, followed by fixed-size chunks that are each
+// sealed on their own. A chunk's nonce is the prefix plus the chunk index, and the final chunk is flagged
+// in the additional data, so chunks can neither be reordered nor dropped from the end of an object.
+// Segmented uploads restart this every segment, with a new prefix, so each can be opened on its own.
+pub const CHUNK_SIZE: usize = 65536;
+pub const CHUNK_PREFIX_SIZE: usize = 16;
+pub const CHUNK_TAG_SIZE: usize = 16;
+
+// Number of leading bytes inspected to detect the mime type of an upload
+const MIME_PEEK_SIZE: usize = 8192;
+
+// Codec recorded in a document's facts, documents without one were stored uncompressed
+pub const COMPRESSION_GZIP: &str = "gzip";
 
-#[derive(Clone, Debug)]
 pub struct Data {
-    pub data: Vec<u8>,
+    pub data: ObjectStream,
+    // Size of the upload as received
+    pub original: Arc<AtomicUsize>,
+    // Size of what is written to storage, after compression and encryption
+    pub bytes: Arc<AtomicUsize>,
+    pub compression: Option<String>,
     pub mime_type: Option<String>,
     pub key: Option<String>,
     pub encrypted_key: Option<Vec<u8>>,
     pub encrypted_key_version: Option<u8>,
 }
 
+#[derive(Clone)]
+pub struct ChunkCipher {
+    key: Arc<xchacha20poly1305::SecretKey>,
+    prefix: [u8; CHUNK_PREFIX_SIZE],
+}
+
+struct Sealer {
+    cipher: ChunkCipher,
+    input: ObjectStream,
+    buffer: BytesMut,
+    index: u64,
+    // Plaintext bytes per separately sealed segment, 0 seals everything as one
+    segment: usize,
+    sealed: usize,
+    started: bool,
+    done: bool,
+}
+
+// Where plaintext bytes start to end, inclusive, of an object written by encrypt_stream are stored
+#[derive(Debug, PartialEq)]
+pub struct ChunkSpan {
+    // First chunk holding the range, and whether the last one holding it is the object's final chunk
+    pub first: u64,
+    pub reaches_end: bool,
+    // Stored bytes of those chunks, inclusive
+    pub from: u64,
+    pub to: u64,
+    // Plaintext to drop from the start of the first chunk, and then to keep
+    pub skip: usize,
+    pub take: usize,
+}
+
+// Gzip in either direction, draining output from the inner buffer as it is produced
+enum Gzip {
+    Encoder(GzEncoder<Vec<u8>>),
+    Decoder(GzDecoder<Vec<u8>>),
+}
+
+struct Opener {
+    key: Arc<xchacha20poly1305::SecretKey>,
+    cipher: Option<ChunkCipher>,
+    input: ObjectStream,
+    buffer: BytesMut,
+    index: u64,
+    // False when only reading up to a chunk before the end of an object
+    final_at_eof: bool,
+    done: bool,
+}
+
+impl ChunkCipher {
+    pub fn new(key: &str) -> Result<ChunkCipher, RestError> {
+        Ok(ChunkCipher {
+            key: Arc::new(Self::secret_key(key)?),
+            prefix: [0u8; CHUNK_PREFIX_SIZE],
+        }
+        .renew())
+    }
+
+    // Same key under a new random nonce prefix
+    fn renew(&self) -> ChunkCipher {
+        let mut prefix = [0u8; CHUNK_PREFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut prefix);
+        ChunkCipher {
+            key: self.key.clone(),
+            prefix,
+        }
+    }
+
+    pub fn secret_key(key: &str) -> Result<xchacha20poly1305::SecretKey, RestError> {
+        Ok(xchacha20poly1305::SecretKey::from_slice(key.as_bytes())?)
+    }
+
+    pub fn with_prefix(
+        key: Arc<xchacha20poly1305::SecretKey>,
+        prefix: &[u8],
+    ) -> Result<ChunkCipher, RestError> {
+        Ok(ChunkCipher {
+            key,
+            prefix: prefix
+                .try_into()
+                .map_err(|_| RestError::CryptoError(orion::errors::UnknownCryptoError))?,
+        })
+    }
+
+    fn nonce(&self, index: u64) -> Result<xchacha20poly1305::Nonce, RestError> {
+        let mut nonce = [0u8; CHUNK_PREFIX_SIZE + 8];
+        nonce[..CHUNK_PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[CHUNK_PREFIX_SIZE..].copy_from_slice(&index.to_be_bytes());
+        Ok(xchacha20poly1305::Nonce::from_slice(&nonce)?)
+    }
+
+    pub fn seal(&self, index: u64, last: bool, plaintext: &[u8]) -> Result<Bytes, RestError> {
+        let mut out = vec![0u8; plaintext.len() + CHUNK_TAG_SIZE];
+        xchacha20poly1305::seal(
+            &self.key,
+            &self.nonce(index)?,
+            plaintext,
+            Some(&[last as u8]),
+            &mut out,
+        )?;
+        Ok(out.into())
+    }
+
+    pub fn open(&self, index: u64, last: bool, ciphertext: &[u8]) -> Result<Bytes, RestError> {
+        let mut out = vec![0u8; ciphertext.len().saturating_sub(CHUNK_TAG_SIZE)];
+        if let Err(e) = xchacha20poly1305::open(
+            &self.key,
+            &self.nonce(index)?,
+            ciphertext,
+            Some(&[last as u8]),
+            &mut out,
+        ) {
+            log::error!("\"Error decrypting chunk {}: {}\"", index, e);
+            return Err(RestError::CryptoError(e));
+        }
+        Ok(out.into())
+    }
+}
+
+impl Sealer {
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, RestError>> {
+        if self.done {
+            return None;
+        }
+
+        if !self.started {
+            self.started = true;
+            return Some(Ok(Bytes::copy_from_slice(&self.cipher.prefix)));
+        }
+
+        loop {
+            // Chunks never cross the end of a segment
+            let size = match self.segment {
+                0 => CHUNK_SIZE,
+                segment => std::cmp::min(CHUNK_SIZE, segment - self.sealed),
+            };
+
+            // Only seal a full chunk once more data is known to follow, so the last one can be flagged
+            if self.buffer.len() > size {
+                let chunk = self.buffer.split_to(size);
+                self.sealed += size;
+                let last = self.sealed == self.segment;
+                let sealed = self.cipher.seal(self.index, last, &chunk);
+                self.index += 1;
+
+                // The next segment starts over under a new prefix
+                if last {
+                    self.cipher = self.cipher.renew();
+                    self.index = 0;
+                    self.sealed = 0;
+                    self.started = false;
+                }
+                return Some(sealed);
+            }
+
+            match self.input.next().await {
+                Some(Ok(bytes)) => self.buffer.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    let chunk = self.buffer.split();
+                    return Some(self.cipher.seal(self.index, true, &chunk));
+                }
+            }
+        }
+    }
+}
+
+impl Opener {
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, RestError>> {
+        let block_size = CHUNK_SIZE + CHUNK_TAG_SIZE;
+        if self.done {
+            return None;
+        }
+
+        loop {
+            if self.cipher.is_none() && self.buffer.len() >= CHUNK_PREFIX_SIZE {
+                let prefix = self.buffer.split_to(CHUNK_PREFIX_SIZE);
+                match ChunkCipher::with_prefix(self.key.clone(), &prefix) {
+                    Ok(c) => self.cipher = Some(c),
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+
+            if let Some(cipher) = self.cipher.as_ref() {
+                if self.buffer.len() > block_size {
+                    let block = self.buffer.split_to(block_size);
+                    let opened = cipher.open(self.index, false, &block);
+                    self.index += 1;
+                    self.done = opened.is_err();
+                    return Some(opened);
+                }
+            }
+
+            match self.input.next().await {
+                Some(Ok(bytes)) => self.buffer.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    return match self.cipher.as_ref() {
+                        Some(cipher) => {
+                            Some(cipher.open(self.index, self.final_at_eof, &self.buffer))
+                        }
+                        None => Some(Err(RestError::CryptoError(
+                            orion::errors::UnknownCryptoError,
+                        ))),
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl Gzip {
+    fn write(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Gzip::Encoder(e) => {
+                e.write_all(chunk)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+            Gzip::Decoder(d) => {
+                d.write_all(chunk)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Gzip::Encoder(e) => e.finish(),
+            Gzip::Decoder(d) => d.finish(),
+        }
+    }
+
+    fn stream(self, input: ObjectStream) -> ObjectStream {
+        Box::pin(stream::unfold(
+            (input, Some(self)),
+            |(mut input, gzip)| async move {
+                let mut gzip = gzip?;
+                loop {
+                    match input.next().await {
+                        Some(Ok(chunk)) => match gzip.write(&chunk) {
+                            Ok(out) if out.is_empty() => continue,
+                            Ok(out) => return Some((Ok(Bytes::from(out)), (input, Some(gzip)))),
+                            Err(e) => return Some((Err(e.into()), (input, None))),
+                        },
+                        Some(Err(e)) => return Some((Err(e), (input, None))),
+                        None => {
+                            return match gzip.finish() {
+                                Ok(out) if out.is_empty() => None,
+                                Ok(out) => Some((Ok(Bytes::from(out)), (input, None))),
+                                Err(e) => Some((Err(e.into()), (input, None))),
+                            }
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
 impl Data {
     pub fn encrypt(key: String, value: Bytes) -> Result<Vec<u8>, RestError> {
         let secret_key = orion::aead::SecretKey::from_slice(key.as_bytes())?;
@@ -29,15 +330,212 @@ impl Data {
         Ok(ciphertext)
     }
 
-    pub fn create(
-        value: Bytes,
+    // Encrypt a stream chunk by chunk, emitting the nonce prefix first
+    pub fn encrypt_stream(key: &str, input: ObjectStream) -> Result<ObjectStream, RestError> {
+        Data::encrypt_segments(key, input, 0)
+    }
+
+    // Encrypt a stream as consecutive segments of segment bytes, each written as if by
+    // encrypt_stream, so a segment's stored bytes can be decrypted without the ones before it
+    pub fn encrypt_segments(
+        key: &str,
+        input: ObjectStream,
+        segment: usize,
+    ) -> Result<ObjectStream, RestError> {
+        let sealer = Sealer {
+            cipher: ChunkCipher::new(key)?,
+            input,
+            buffer: BytesMut::new(),
+            index: 0,
+            segment,
+            sealed: 0,
+            started: false,
+            done: false,
+        };
+
+        Ok(Box::pin(stream::unfold(sealer, |mut s| async move {
+            let chunk = s.next_chunk().await?;
+            Some((chunk, s))
+        })))
+    }
+
+    // Decrypt a complete object that was written by encrypt_stream
+    pub fn decrypt_stream(key: &str, input: ObjectStream) -> Result<ObjectStream, RestError> {
+        let opener = Opener {
+            key: Arc::new(ChunkCipher::secret_key(key)?),
+            cipher: None,
+            input,
+            buffer: BytesMut::new(),
+            index: 0,
+            final_at_eof: true,
+            done: false,
+        };
+
+        Ok(Data::open_stream(opener))
+    }
+
+    // Decrypt a run of chunks starting at index, as read from the middle of an object
+    pub fn decrypt_chunks(
+        key: &str,
+        prefix: &[u8],
+        index: u64,
+        final_at_eof: bool,
+        input: ObjectStream,
+    ) -> Result<ObjectStream, RestError> {
+        let key = Arc::new(ChunkCipher::secret_key(key)?);
+        let opener = Opener {
+            cipher: Some(ChunkCipher::with_prefix(key.clone(), prefix)?),
+            key,
+            input,
+            buffer: BytesMut::new(),
+            index,
+            final_at_eof,
+            done: false,
+        };
+
+        Ok(Data::open_stream(opener))
+    }
+
+    fn open_stream(opener: Opener) -> ObjectStream {
+        Box::pin(stream::unfold(opener, |mut s| async move {
+            let chunk = s.next_chunk().await?;
+            Some((chunk, s))
+        }))
+    }
+
+    pub fn compress_stream(input: ObjectStream) -> ObjectStream {
+        Gzip::Encoder(GzEncoder::new(Vec::new(), Compression::default())).stream(input)
+    }
+
+    pub fn decompress_stream(codec: &str, input: ObjectStream) -> Result<ObjectStream, RestError> {
+        match codec {
+            COMPRESSION_GZIP => Ok(Gzip::Decoder(GzDecoder::new(Vec::new())).stream(input)),
+            _ => {
+                // The object exists but can't be read back, which is a server side problem
+                log::error!("\"Unknown compression codec: {}\"", codec);
+                Err(RestError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unknown compression codec: {}", codec),
+                )))
+            }
+        }
+    }
+
+    // Drop the first skip bytes of a stream, then end it after take bytes
+    pub fn slice(input: ObjectStream, skip: usize, take: usize) -> ObjectStream {
+        Box::pin(stream::unfold(
+            (input, skip, take),
+            |(mut input, mut skip, take)| async move {
+                loop {
+                    if take == 0 {
+                        return None;
+                    }
+                    let mut bytes = match input.next().await? {
+                        Ok(b) => b,
+                        Err(e) => return Some((Err(e), (input, 0, 0))),
+                    };
+                    if skip >= bytes.len() {
+                        skip -= bytes.len();
+                        continue;
+                    }
+                    let mut bytes = bytes.split_off(skip);
+                    bytes.truncate(take);
+                    let take = take - bytes.len();
+                    return Some((Ok(bytes), (input, 0, take)));
+                }
+            },
+        ))
+    }
+
+    // Read enough of the stream to sniff its mime type, then hand back the stream intact
+    async fn peek(mut input: ObjectStream) -> Result<(Bytes, ObjectStream), RestError> {
+        let mut head = BytesMut::new();
+        while head.len() < MIME_PEEK_SIZE {
+            match input.next().await {
+                Some(Ok(bytes)) => head.extend_from_slice(&bytes),
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+
+        let head = head.freeze();
+        let stream = stream::once(future::ready(Ok(head.clone())))
+            .chain(input)
+            .boxed();
+        Ok((head, stream))
+    }
+
+    // Pull the first item off a stream so errors surface before a response is committed to
+    pub async fn prime(mut input: ObjectStream) -> Result<ObjectStream, RestError> {
+        match input.next().await {
+            Some(Ok(first)) => Ok(stream::once(future::ready(Ok(first))).chain(input).boxed()),
+            Some(Err(e)) => Err(e),
+            None => Ok(input),
+        }
+    }
+
+    // Size of the plaintext held in an object written by encrypt_stream
+    pub fn plaintext_len(stored: usize) -> usize {
+        let body = stored.saturating_sub(CHUNK_PREFIX_SIZE);
+        let chunks = std::cmp::max(1, body.div_ceil(CHUNK_SIZE + CHUNK_TAG_SIZE));
+        body.saturating_sub(chunks * CHUNK_TAG_SIZE)
+    }
+
+    // Size of the object encrypt_stream writes for plaintext bytes
+    pub fn sealed_len(plaintext: usize) -> usize {
+        let chunks = std::cmp::max(1, plaintext.div_ceil(CHUNK_SIZE));
+        CHUNK_PREFIX_SIZE + plaintext + chunks * CHUNK_TAG_SIZE
+    }
+
+    // Map plaintext bytes start to end, inclusive, onto the chunks of an object of stored bytes
+    pub fn chunk_span(stored: usize, start: u64, end: u64) -> ChunkSpan {
+        let chunk = CHUNK_SIZE as u64;
+        let block = (CHUNK_SIZE + CHUNK_TAG_SIZE) as u64;
+        let prefix = CHUNK_PREFIX_SIZE as u64;
+        let stored = stored as u64;
+        let (first, last) = (start / chunk, end / chunk);
+        let chunks = std::cmp::max(1, stored.saturating_sub(prefix).div_ceil(block));
+        ChunkSpan {
+            first,
+            reaches_end: last + 1 == chunks,
+            from: prefix + first * block,
+            to: std::cmp::min(prefix + (last + 1) * block, stored) - 1,
+            skip: (start - first * chunk) as usize,
+            take: (end - start + 1) as usize,
+        }
+    }
+
+    // Hash data as it passes through, the digest is complete once the stream has been consumed
+    pub fn digest(input: ObjectStream, hasher: Arc<Mutex<Blake2s256>>) -> ObjectStream {
+        Box::pin(input.inspect(move |chunk| {
+            if let Ok(bytes) = chunk {
+                hasher.lock().unwrap().update(bytes);
+            }
+        }))
+    }
+
+    pub fn count(input: ObjectStream, counter: Arc<AtomicUsize>) -> ObjectStream {
+        Box::pin(input.inspect(move |chunk| {
+            if let Ok(bytes) = chunk {
+                counter.fetch_add(bytes.len(), Ordering::Relaxed);
+            }
+        }))
+    }
+
+    pub async fn create(
+        value: ObjectStream,
         key: Option<String>,
         keys: &Keys,
         encrypt_key: bool,
         encrypt_data: bool,
+        compress: bool,
+        segment_size: usize,
     ) -> Result<Data, RestError> {
+        let (head, value) = Data::peek(value).await?;
+        let kind = infer::get(&head);
+
         // Detect binary mime-type, this could drop the debug bit in the future
-        let content_type = match infer::get(&value) {
+        let content_type = match kind {
             Some(t) => {
                 let mime_type = t.mime_type().to_owned();
                 log::debug!("\"Detected mime type as {}\"", &mime_type);
@@ -46,6 +544,27 @@ impl Data {
             None => None,
         };
 
+        // Stored bytes are only known once the stream has been written out
+        let bytes = Arc::new(AtomicUsize::new(0));
+        let original = Arc::new(AtomicUsize::new(0));
+        let value = Data::count(value, original.clone());
+
+        // Media and archives are compressed already, so leave them as they are
+        let compression = match kind.map(|k| k.matcher_type()) {
+            _ if !compress => None,
+            Some(
+                MatcherType::Archive | MatcherType::Audio | MatcherType::Image | MatcherType::Video,
+            ) => None,
+            _ => Some(COMPRESSION_GZIP.to_owned()),
+        };
+        let value = match compression {
+            Some(_) => {
+                log::debug!("Data payload is being compressed");
+                Data::compress_stream(value)
+            }
+            None => value,
+        };
+
         if encrypt_data {
             log::debug!("Data payload is being encrypted");
             // Generate random encryption key is None is passed
@@ -54,8 +573,11 @@ impl Data {
                 None => Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
             };
 
-            // Encrypt data Bytes
-            let ciphertext = Data::encrypt(key.clone(), value)?;
+            // Encrypt data stream, sealing each segment it will be stored as on its own
+            let ciphertext = Data::count(
+                Data::encrypt_segments(&key, value, segment_size)?,
+                bytes.clone(),
+            );
 
             if encrypt_key {
                 log::debug!("Encryption key is being encrypted");
@@ -65,6 +587,9 @@ impl Data {
 
                 Ok(Data {
                     data: ciphertext,
+                    original,
+                    bytes,
+                    compression,
                     mime_type: content_type,
                     key: Some(latest_encrypt_key.key),
                     encrypted_key: Some(encrypted_key),
@@ -73,6 +598,9 @@ impl Data {
             } else {
                 Ok(Data {
                     data: ciphertext,
+                    original,
+                    bytes,
+                    compression,
                     mime_type: content_type,
                     key: Some(key),
                     encrypted_key: None,
@@ -83,7 +611,10 @@ impl Data {
             log::debug!("Data payload is NOT being encrypted");
             // Return data unchanged
             Ok(Data {
-                data: value.into(),
+                data: Data::count(value, bytes.clone()),
+                original,
+                bytes,
+                compression,
                 mime_type: content_type,
                 key: None,
                 encrypted_key: None,
@@ -92,3 +623,326 @@ impl Data {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Key;
+    use futures::TryStreamExt;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn stream_of(parts: Vec<Vec<u8>>) -> ObjectStream {
+        stream::iter(parts.into_iter().map(|p| Ok(Bytes::from(p)))).boxed()
+    }
+
+    async fn collect(input: ObjectStream) -> Result<Vec<u8>, RestError> {
+        input.map_ok(|b| b.to_vec()).try_concat().await
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    async fn sealed(plaintext: &[u8]) -> Vec<u8> {
+        let input = stream_of(plaintext.chunks(10000).map(|c| c.to_vec()).collect());
+        collect(Data::encrypt_stream(KEY, input).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn slice_skips_and_takes_across_chunks() {
+        let data: Vec<u8> = (0..30).collect();
+        let parts = || data.chunks(10).map(|c| c.to_vec()).collect::<Vec<_>>();
+
+        let sliced = collect(Data::slice(stream_of(parts()), 5, 17))
+            .await
+            .unwrap();
+        assert_eq!(sliced, &data[5..22]);
+        let sliced = collect(Data::slice(stream_of(parts()), 10, 10))
+            .await
+            .unwrap();
+        assert_eq!(sliced, &data[10..20]);
+        let sliced = collect(Data::slice(stream_of(parts()), 25, 100))
+            .await
+            .unwrap();
+        assert_eq!(sliced, &data[25..]);
+        let sliced = collect(Data::slice(stream_of(parts()), 40, 5))
+            .await
+            .unwrap();
+        assert!(sliced.is_empty());
+        let sliced = collect(Data::slice(stream_of(parts()), 0, 0))
+            .await
+            .unwrap();
+        assert!(sliced.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slice_passes_errors_through() {
+        let input = stream::iter(vec![
+            Ok(Bytes::from_static(b"abc")),
+            Err(RestError::NotFound),
+        ]);
+        let result = collect(Data::slice(input.boxed(), 1, 10)).await;
+        assert!(matches!(result, Err(RestError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn sealed_and_plaintext_lengths_match_encryption() {
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE + 7,
+        ] {
+            let stored = sealed(&payload(len)).await.len();
+            assert_eq!(stored, Data::sealed_len(len), "sealed length of {}", len);
+            assert_eq!(
+                Data::plaintext_len(stored),
+                len,
+                "plaintext length of {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn chunk_span_maps_onto_chunks() {
+        let stored = Data::sealed_len(3 * CHUNK_SIZE + 100);
+        let block = (CHUNK_SIZE + CHUNK_TAG_SIZE) as u64;
+        let prefix = CHUNK_PREFIX_SIZE as u64;
+        let start = CHUNK_SIZE as u64 + 5;
+
+        assert_eq!(
+            Data::chunk_span(stored, start, start + 4),
+            ChunkSpan {
+                first: 1,
+                reaches_end: false,
+                from: prefix + block,
+                to: prefix + 2 * block - 1,
+                skip: 5,
+                take: 5,
+            }
+        );
+        // The last chunk is shorter than the others
+        let end = 3 * CHUNK_SIZE as u64 + 99;
+        assert_eq!(
+            Data::chunk_span(stored, end, end),
+            ChunkSpan {
+                first: 3,
+                reaches_end: true,
+                from: prefix + 3 * block,
+                to: stored as u64 - 1,
+                skip: 99,
+                take: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn chunk_span_decrypts_ranges() {
+        let len = 3 * CHUNK_SIZE + 100;
+        let plaintext = payload(len);
+        let stored = sealed(&plaintext).await;
+        let chunk = CHUNK_SIZE as u64;
+        let last = len as u64 - 1;
+
+        for (start, end) in [
+            (0, 0),
+            (0, last),
+            (100, 200),
+            (chunk - 1, chunk),
+            (chunk, 2 * chunk - 1),
+            (chunk + 1, 3 * chunk + 1),
+            (last - 10, last),
+            (last, last),
+        ] {
+            let span = Data::chunk_span(stored.len(), start, end);
+            let ciphertext = stored[span.from as usize..=span.to as usize].to_vec();
+            let opened = Data::decrypt_chunks(
+                KEY,
+                &stored[..CHUNK_PREFIX_SIZE],
+                span.first,
+                span.reaches_end,
+                stream_of(vec![ciphertext]),
+            )
+            .unwrap();
+            let range = collect(Data::slice(opened, span.skip, span.take))
+                .await
+                .unwrap();
+            assert_eq!(
+                range,
+                &plaintext[start as usize..=end as usize],
+                "range {}-{}",
+                start,
+                end
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn encrypt_stream_seals_whole_chunks_however_input_arrives() {
+        let plaintext = payload(2 * CHUNK_SIZE + 10);
+
+        // Parts that straddle chunk boundaries, including an empty one
+        let mut parts = Vec::new();
+        let mut rest = &plaintext[..];
+        for size in [1, 0, CHUNK_SIZE - 2, 3, CHUNK_SIZE, 8] {
+            let (part, tail) = rest.split_at(size);
+            parts.push(part.to_vec());
+            rest = tail;
+        }
+        assert!(rest.is_empty());
+
+        let sealed: Vec<Bytes> = Data::encrypt_stream(KEY, stream_of(parts))
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let lengths: Vec<usize> = sealed.iter().map(|b| b.len()).collect();
+        let block = CHUNK_SIZE + CHUNK_TAG_SIZE;
+        assert_eq!(
+            lengths,
+            [CHUNK_PREFIX_SIZE, block, block, 10 + CHUNK_TAG_SIZE]
+        );
+
+        let stored: Vec<u8> = sealed.concat();
+        let opened = Data::decrypt_stream(KEY, stream_of(vec![stored])).unwrap();
+        assert_eq!(collect(opened).await.unwrap(), plaintext);
+    }
+
+    #[tokio::test]
+    async fn encrypt_stream_passes_errors_through() {
+        let input = stream::iter(vec![
+            Ok(Bytes::from_static(b"abc")),
+            Err(RestError::PayloadTooLarge),
+        ]);
+        let result = collect(Data::encrypt_stream(KEY, input.boxed()).unwrap()).await;
+        assert!(matches!(result, Err(RestError::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn decrypt_stream_opens_objects_however_they_are_read() {
+        for len in [0, CHUNK_SIZE, 2 * CHUNK_SIZE + 10] {
+            let plaintext = payload(len);
+            let stored = sealed(&plaintext).await;
+            for size in [
+                1,
+                7,
+                CHUNK_PREFIX_SIZE,
+                CHUNK_SIZE + CHUNK_TAG_SIZE,
+                stored.len(),
+            ] {
+                let parts = stored.chunks(size).map(|c| c.to_vec()).collect();
+                let opened = Data::decrypt_stream(KEY, stream_of(parts)).unwrap();
+                assert_eq!(
+                    collect(opened).await.unwrap(),
+                    plaintext,
+                    "{} bytes read in parts of {}",
+                    len,
+                    size
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn decrypt_stream_rejects_altered_objects() {
+        let stored = sealed(&payload(2 * CHUNK_SIZE)).await;
+        let block = CHUNK_SIZE + CHUNK_TAG_SIZE;
+        let (prefix, chunks) = stored.split_at(CHUNK_PREFIX_SIZE);
+        let (first, second) = chunks.split_at(block);
+
+        let mut flipped = stored.clone();
+        flipped[CHUNK_PREFIX_SIZE + block + 5] ^= 1;
+        for (altered, what) in [
+            ([prefix, first].concat(), "dropped final chunk"),
+            ([prefix, second, first].concat(), "reordered chunks"),
+            (flipped, "flipped bit"),
+            (prefix[..10].to_vec(), "truncated prefix"),
+        ] {
+            let opened = Data::decrypt_stream(KEY, stream_of(vec![altered])).unwrap();
+            assert!(collect(opened).await.is_err(), "{}", what);
+        }
+
+        let opened =
+            Data::decrypt_stream("fedcba9876543210fedcba9876543210", stream_of(vec![stored]))
+                .unwrap();
+        assert!(collect(opened).await.is_err(), "wrong key");
+    }
+
+    #[tokio::test]
+    async fn compression_round_trips_streams() {
+        let text = b"tackd ".repeat(50000);
+        let parts = text.chunks(777).map(|c| c.to_vec()).collect();
+        let compressed = collect(Data::compress_stream(stream_of(parts)))
+            .await
+            .unwrap();
+        assert!(compressed.len() < text.len() / 10);
+
+        let parts = compressed.chunks(100).map(|c| c.to_vec()).collect();
+        let decompressed = Data::decompress_stream(COMPRESSION_GZIP, stream_of(parts)).unwrap();
+        assert_eq!(collect(decompressed).await.unwrap(), text);
+        assert!(matches!(
+            Data::decompress_stream("zstd", stream_of(vec![])),
+            Err(RestError::Io(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn create_compresses_before_encrypting_and_skips_media() {
+        let keys = Keys {
+            keys: vec![Key {
+                ver: 1,
+                key: KEY.to_owned(),
+            }],
+        };
+
+        let text = b"tackd ".repeat(10000);
+        let data = Data::create(
+            stream_of(vec![text.clone()]),
+            None,
+            &keys,
+            false,
+            true,
+            true,
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(data.compression.as_deref(), Some(COMPRESSION_GZIP));
+        let key = data.key.unwrap();
+        let stored = collect(data.data).await.unwrap();
+        assert_eq!(data.original.load(Ordering::Relaxed), text.len());
+        assert_eq!(data.bytes.load(Ordering::Relaxed), stored.len());
+        assert!(stored.len() < text.len() / 10);
+        let opened = Data::decompress_stream(
+            COMPRESSION_GZIP,
+            Data::decrypt_stream(&key, stream_of(vec![stored])).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(collect(opened).await.unwrap(), text);
+
+        // Images are compressed already
+        let png = [&b"\x89PNG\r\n\x1a\n"[..], &payload(1000)].concat();
+        let data = Data::create(
+            stream_of(vec![png.clone()]),
+            None,
+            &keys,
+            false,
+            false,
+            true,
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(data.compression, None);
+        assert_eq!(data.mime_type.as_deref(), Some("image/png"));
+        assert_eq!(collect(data.data).await.unwrap(), png);
+    }
+}
@@ -0,0 +1,16 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// Unencrypted uploads with the same content share one stored object, which is kept until the last
+// document referencing it is deactivated
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Blob {
+    pub digest: String,
+    // Storage id of the shared object, which is the id of the upload that stored it first
+    pub object: String,
+    pub refs: i64,
+    // Uploads that have dropped their reference, so releasing the same upload twice only counts once
+    #[serde(default)]
+    pub released: Vec<String>,
+    pub created: chrono::DateTime<Utc>,
+}
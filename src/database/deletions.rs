@@ -0,0 +1,66 @@
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+
+// Queue states, dead entries have run out of attempts and are only retried occasionally
+pub const DELETION_PENDING: &str = "pending";
+pub const DELETION_DEAD: &str = "dead";
+
+// Failed attempts before an entry is dead-lettered
+pub const DELETION_MAX_ATTEMPTS: i32 = 10;
+
+// Delay before the first retry, doubled after each failure up to the maximum
+const DELETION_BACKOFF_SECONDS: i64 = 60;
+const DELETION_MAX_BACKOFF_SECONDS: i64 = 21600;
+const DELETION_DEAD_RETRY_SECONDS: i64 = 86400;
+
+// An object that couldn't be deleted from storage, kept until a retry removes it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Deletion {
+    pub object: String,
+    pub status: String,
+    pub attempts: i32,
+    pub created: bson::DateTime,
+    pub next_attempt: bson::DateTime,
+    pub last_error: String,
+}
+
+impl Deletion {
+    // Delay before the next attempt, after the given number of failed ones
+    pub fn backoff(attempts: i32) -> Duration {
+        if attempts >= DELETION_MAX_ATTEMPTS {
+            return Duration::seconds(DELETION_DEAD_RETRY_SECONDS);
+        }
+        let seconds = DELETION_BACKOFF_SECONDS << attempts.clamp(0, 16);
+        Duration::seconds(seconds.min(DELETION_MAX_BACKOFF_SECONDS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(Deletion::backoff(0), Duration::seconds(60));
+        assert_eq!(Deletion::backoff(1), Duration::seconds(120));
+        assert_eq!(Deletion::backoff(2), Duration::seconds(240));
+        assert_eq!(Deletion::backoff(8), Duration::seconds(15360));
+        assert_eq!(
+            Deletion::backoff(9),
+            Duration::seconds(DELETION_MAX_BACKOFF_SECONDS)
+        );
+        assert_eq!(Deletion::backoff(-1), Duration::seconds(60));
+    }
+
+    #[test]
+    fn backoff_slows_down_dead_entries() {
+        assert_eq!(
+            Deletion::backoff(DELETION_MAX_ATTEMPTS),
+            Duration::seconds(DELETION_DEAD_RETRY_SECONDS)
+        );
+        assert_eq!(
+            Deletion::backoff(i32::MAX),
+            Duration::seconds(DELETION_DEAD_RETRY_SECONDS)
+        );
+    }
+}
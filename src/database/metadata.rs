@@ -1,7 +1,7 @@
-use axum::body::Bytes;
 use axum::extract::Query;
 use blake2::{Blake2s256, Digest};
 use chrono::{Duration, Utc};
+use futures::{stream, StreamExt};
 use hex::encode;
 use hyper::header::{CONTENT_TYPE, USER_AGENT};
 use hyper::HeaderMap;
@@ -9,13 +9,16 @@ use ms_converter::ms;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::From;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::data::Data;
+use crate::data::{Data, CHUNK_SIZE};
 use crate::database::links::{Link, LinkScrubbed, Links};
 use crate::error::Error as RestError;
 use crate::handlers::QueriesSet;
 use crate::state::Configs;
+use crate::storage::trait_storage::ObjectStream;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MetaData {
@@ -45,17 +48,37 @@ pub struct Meta {
     pub user_agent: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub x_forwarded_for: Option<String>,
-    pub bytes: usize,
+    pub bytes: Size,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<Vec<String>>,
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "SizeRecord")]
+pub struct Size {
+    // Bytes as uploaded
+    pub original: usize,
+    // Bytes held in storage, after compression and encryption
+    pub stored: usize,
+}
+
+// Older documents only recorded the stored size
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeRecord {
+    Stored(usize),
+    Sizes { original: usize, stored: usize },
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Lifecycle {
     pub max: LifecycleMax,
     pub current: LifecycleCurrent,
+    // When the upload was deleted, expired or abandoned, older documents didn't record it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deactivated: Option<bson::DateTime>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -81,6 +104,9 @@ pub struct LifecycleMaxJson {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LifecycleCurrent {
     pub reads: i64,
+    // Bytes sent or reserved by downloads, partial reads are limited by this rather than by reads
+    #[serde(default)]
+    pub bytes: i64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -90,6 +116,9 @@ pub struct Encryption {
     #[serde(with = "serde_bytes")]
     pub key: Option<Vec<u8>>,
     pub version: Option<u8>,
+    // Set for data encrypted in chunks, older documents were sealed as a single block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -100,15 +129,70 @@ pub struct Facts {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pwd: Option<String>,
     pub encryption: Encryption,
+    // Codec applied before encryption, older documents were stored uncompressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
     pub ignore_link_key: bool,
+    // Content digest, set when the stored object is shared through the blobs collection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    // Storage id of the object, when it isn't the document id. Set for objects first stored by
+    // another upload with the same content, and for opaque or sharded storage keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object: Option<String>,
+    // Version of the encryption key an opaque storage id was derived from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_key_ver: Option<u8>,
+    // Set while a direct upload waits for the client to write its object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direct: Option<Direct>,
+    // Manifest of the objects holding the stored data in order, for uploads split into segments.
+    // The object id isn't stored itself then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<Segment>>,
+}
+
+// A direct upload that hasn't been completed yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Direct {
+    // Time by which the upload has to be completed
+    pub complete_by: bson::DateTime,
+    // Storage id the client writes to. It is copied to the upload's own object on completion, so
+    // writes through the signed url after that can't change what was checked.
+    pub staging: String,
+    // Set while the upload is being completed, so only one completion runs at a time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completing: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Segment {
+    pub id: String,
+    pub bytes: u64,
+}
+
+// What is kept of a purged upload, which records that it existed without anything about its
+// content, its uploader or how it could be read
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub id: String,
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub created: chrono::DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deactivated: Option<bson::DateTime>,
+    pub purged: bson::DateTime,
 }
 
-#[derive(Clone, Debug)]
 pub struct MetaDataPayload {
     pub metadata: MetaData,
     // If data is not encrypted, then this will be None
     pub key: Option<String>,
-    pub data: Vec<u8>,
+    pub data: ObjectStream,
+    // Incremented as data is streamed to storage
+    pub original: Arc<AtomicUsize>,
+    pub bytes: Arc<AtomicUsize>,
     pub url: String,
 }
 
@@ -128,6 +212,31 @@ impl From<LifecycleMax> for LifecycleMaxJson {
     }
 }
 
+impl From<SizeRecord> for Size {
+    fn from(item: SizeRecord) -> Self {
+        match item {
+            SizeRecord::Stored(stored) => Size {
+                original: stored,
+                stored,
+            },
+            SizeRecord::Sizes { original, stored } => Size { original, stored },
+        }
+    }
+}
+
+impl From<&MetaData> for Tombstone {
+    fn from(item: &MetaData) -> Self {
+        Tombstone {
+            id: item.id.clone(),
+            active: false,
+            owner: item.facts.owner.clone(),
+            created: item.meta.created,
+            deactivated: item.lifecycle.deactivated,
+            purged: bson::DateTime::now(),
+        }
+    }
+}
+
 impl From<Lifecycle> for LifecyclePublic {
     fn from(item: Lifecycle) -> Self {
         LifecyclePublic {
@@ -147,8 +256,43 @@ impl MetaData {
         }
     }
 
-    pub fn create(
-        payload: Bytes,
+    // Id of the object holding this document's data in storage
+    pub fn object_id(&self) -> &str {
+        self.facts.object.as_deref().unwrap_or(&self.id)
+    }
+
+    // Storage id a pending direct upload's client writes to
+    pub fn staging_id(&self) -> Option<&str> {
+        self.facts.direct.as_ref().map(|d| d.staging.as_str())
+    }
+
+    // Every object holding this document's data, in order
+    pub fn object_ids(&self) -> Vec<String> {
+        match &self.facts.segments {
+            Some(segments) => segments.iter().map(|s| s.id.clone()).collect(),
+            None => vec![self.object_id().to_owned()],
+        }
+    }
+
+    // Metadata stored alongside the object itself
+    pub fn object_metadata(&self) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            "filename".to_string(),
+            self.meta
+                .filename
+                .clone()
+                .unwrap_or("not specified".to_owned()),
+        );
+        metadata.insert(
+            "expires".to_string(),
+            self.lifecycle.max.expires.clone().to_string(),
+        );
+        metadata
+    }
+
+    pub async fn create(
+        payload: ObjectStream,
         queries: &Query<QueriesSet>,
         headers: HeaderMap,
         current_user: Option<String>,
@@ -165,8 +309,52 @@ impl MetaData {
             &configs.keys,
             current_user.is_some(),
             configs.encrypt_data,
-        )?;
+            configs.compress,
+            configs.segment_size,
+        )
+        .await?;
+
+        MetaData::describe(id, data, queries, headers, current_user, configs)
+    }
+
+    // Document for a direct upload, whose object is written straight to storage by the client. It
+    // can't be compressed or encrypted, and stays inactive until the upload is completed. The
+    // direct upload record is given the document id to name its staging object.
+    pub fn create_pending(
+        queries: &Query<QueriesSet>,
+        headers: HeaderMap,
+        current_user: Option<String>,
+        configs: Configs,
+        direct: impl FnOnce(&str) -> Direct,
+    ) -> Result<MetaDataPayload, RestError> {
+        let id = Uuid::new_v4().to_string();
+        log::debug!("Reserving object {} for a direct upload", &id);
+
+        let data = Data {
+            data: stream::empty().boxed(),
+            original: Arc::new(AtomicUsize::new(0)),
+            bytes: Arc::new(AtomicUsize::new(0)),
+            compression: None,
+            mime_type: None,
+            key: None,
+            encrypted_key: None,
+            encrypted_key_version: None,
+        };
+        let direct = direct(&id);
+        let mut payload = MetaData::describe(id, data, queries, headers, current_user, configs)?;
+        payload.metadata.active = false;
+        payload.metadata.facts.direct = Some(direct);
+        Ok(payload)
+    }
 
+    fn describe(
+        id: String,
+        data: Data,
+        queries: &Query<QueriesSet>,
+        headers: HeaderMap,
+        current_user: Option<String>,
+        configs: Configs,
+    ) -> Result<MetaDataPayload, RestError> {
         // Create initial link to brand new document
         let link = Link::new(current_user.as_ref(), &configs, None)?;
 
@@ -262,7 +450,7 @@ impl MetaData {
                 created: Utc::now(),
                 content_type,
                 expires: queries.expires.clone(),
-                bytes: data.data.len(),
+                bytes: Size::default(),
                 x_forwarded_for: headers
                     .get("x-forwarded-for")
                     .map(|s| s.to_str().unwrap_or("error").to_string()),
@@ -278,7 +466,11 @@ impl MetaData {
                     seconds: expire_seconds,
                     expires: (Utc::now() + Duration::seconds(expire_seconds)).into(), // Secret expiration is now + expiration seconds
                 },
-                current: LifecycleCurrent { reads: 0i64 },
+                current: LifecycleCurrent {
+                    reads: 0i64,
+                    bytes: 0i64,
+                },
+                deactivated: None,
             },
             facts: Facts {
                 owner: current_user,
@@ -289,8 +481,15 @@ impl MetaData {
                     managed: data.encrypted_key.is_some(),
                     key: data.encrypted_key,
                     version: data.encrypted_key_version,
+                    chunk_size: data.key.as_ref().map(|_| CHUNK_SIZE as u32),
                 },
+                compression: data.compression,
                 ignore_link_key: configs.ignore_link_key,
+                digest: None,
+                object: None,
+                object_key_ver: None,
+                direct: None,
+                segments: None,
             },
             links: Links(vec![link.link]),
         };
@@ -307,6 +506,8 @@ impl MetaData {
             metadata,
             key: initial_url_key,
             data: data.data,
+            original: data.original,
+            bytes: data.bytes,
             url,
         })
     }
@@ -0,0 +1,14 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// Records an object that has been copied to and verified in another storage backend, so an
+// interrupted migration can pick up where it left off
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Migration {
+    pub object: String,
+    // Destination backend and bucket, as "<backend>/<bucket>"
+    pub target: String,
+    pub bytes: i64,
+    pub digest: String,
+    pub migrated: chrono::DateTime<Utc>,
+}
@@ -1,6 +1,13 @@
 pub mod auth;
+pub mod blobs;
+pub mod deletions;
 pub mod links;
+pub mod migrations;
 pub mod mongo;
+pub mod mongo_store;
 //pub mod secret;
 pub mod metadata;
+pub mod resumable;
+pub mod sqlite;
+pub mod store;
 pub mod users;
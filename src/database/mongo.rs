@@ -2,8 +2,11 @@
 //use mongodb::options::{FindOptions, IndexOptions};
 //use bson::{doc, from_document, to_document, Document};
 use bson::Document;
+use mongodb::change_stream::event::ChangeStreamEvent;
+use mongodb::change_stream::ChangeStream;
 use mongodb::options::{
-    CreateIndexOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertOneOptions,
+    ChangeStreamOptions, CreateIndexOptions, DeleteOptions, FindOneAndUpdateOptions,
+    FindOneOptions, FindOptions, InsertOneOptions, ReplaceOptions,
 };
 //use serde::{Deserialize, Serialize};
 use futures::StreamExt;
@@ -116,6 +119,37 @@ impl MongoClient {
         Ok(result)
     }
 
+    pub async fn count_documents(
+        &self,
+        collection: &str,
+        filter: Document,
+    ) -> Result<u64, RestError> {
+        let collection_handle = self
+            .client
+            .database(&self.database)
+            .collection::<Document>(collection);
+        log::debug!("Running count_documents with filter: {}", filter);
+        Ok(collection_handle.count_documents(filter, None).await?)
+    }
+
+    pub async fn aggregate(
+        &self,
+        collection: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Vec<Document>, RestError> {
+        let collection_handle = self
+            .client
+            .database(&self.database)
+            .collection::<Document>(collection);
+        log::debug!("Running aggregate on {}", collection);
+        let mut cursor = collection_handle.aggregate(pipeline, None).await?;
+        let mut result: Vec<Document> = Vec::new();
+        while let Some(document) = cursor.next().await {
+            result.push(document?);
+        }
+        Ok(result)
+    }
+
     pub async fn insert_one<
         T: DeserializeOwned + Unpin + std::marker::Send + Sync + Clone + Serialize,
     >(
@@ -152,4 +186,89 @@ impl MongoClient {
             }
         }
     }
+
+    pub async fn delete_one(
+        &self,
+        collection: &str,
+        filter: Document,
+        options: Option<DeleteOptions>,
+    ) -> Result<(), RestError> {
+        let collection_handle = self
+            .client
+            .database(&self.database)
+            .collection::<Document>(collection);
+        log::debug!("Running delete_one with filter: {}", filter);
+        match collection_handle.delete_one(filter.clone(), options).await {
+            Ok(r) if r.deleted_count > 0 => Ok(()),
+            Ok(_) => {
+                log::debug!("Filter did not return any docs: {}", filter);
+                Err(RestError::NotFound)
+            }
+            Err(e) => {
+                log::error!("Error delete_one: {}. filter: {}", e, filter);
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn replace_one<T: Serialize + std::marker::Send + Sync>(
+        &self,
+        collection: &str,
+        filter: Document,
+        replacement: &T,
+        options: Option<ReplaceOptions>,
+    ) -> Result<(), RestError> {
+        let collection_handle = self
+            .client
+            .database(&self.database)
+            .collection::<T>(collection);
+        log::debug!("Running replace_one with filter: {}", filter);
+        match collection_handle
+            .replace_one(filter.clone(), replacement, options)
+            .await
+        {
+            Ok(r) if r.matched_count > 0 => Ok(()),
+            Ok(_) => {
+                log::debug!("Filter did not return any docs: {}", filter);
+                Err(RestError::NotFound)
+            }
+            Err(e) => {
+                log::error!("Error replace_one: {}. filter: {}", e, filter);
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn drop_index(&self, collection: &str, name: &str) -> Result<(), RestError> {
+        let collection_handle = self
+            .client
+            .database(&self.database)
+            .collection::<Document>(collection);
+        log::debug!("Dropping index {} on {}", name, collection);
+        collection_handle.drop_index(name, None).await?;
+        Ok(())
+    }
+
+    pub async fn run_command(&self, command: Document) -> Result<Document, RestError> {
+        log::debug!("Running command: {}", command);
+        Ok(self
+            .client
+            .database(&self.database)
+            .run_command(command, None)
+            .await?)
+    }
+
+    pub async fn watch<T: DeserializeOwned + Unpin + std::marker::Send + Sync>(
+        &self,
+        collection: &str,
+        pipeline: Vec<Document>,
+        options: Option<ChangeStreamOptions>,
+    ) -> Result<ChangeStream<ChangeStreamEvent<T>>, RestError> {
+        let collection_handle = self
+            .client
+            .database(&self.database)
+            .collection::<T>(collection);
+        log::debug!("Watching {} for changes", collection);
+        Ok(collection_handle.watch(pipeline, options).await?)
+    }
 }
@@ -0,0 +1,1134 @@
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use bson::{doc, from_bson, from_document, to_bson, to_document, Document};
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::{self, StreamExt};
+use mongodb::change_stream::event::ResumeToken;
+use mongodb::options::{
+    ChangeStreamOptions, ClientOptions, FindOneAndUpdateOptions, FindOptions,
+    FullDocumentBeforeChangeType, IndexOptions, ReturnDocument,
+};
+use mongodb::IndexModel;
+use std::collections::HashSet;
+
+use crate::database::blobs::Blob;
+use crate::database::deletions::{Deletion, DELETION_DEAD, DELETION_PENDING};
+use crate::database::links::Link;
+use crate::database::metadata::{MetaData, Tombstone};
+use crate::database::migrations::Migration;
+use crate::database::mongo::MongoClient;
+use crate::database::resumable::{UploadPart, UploadSession, SESSION_COMPLETING, SESSION_OPEN};
+use crate::database::store::{
+    Collections, Eviction, ExpiredUpload, ExpiredUploads, MetadataStore, Page, PAGE_INVALID_CURSOR,
+};
+use crate::database::users::{ApiKeyHashed, User};
+use crate::error::Error as RestError;
+
+// Name of the index that removes uploads once they expire
+const TTL_INDEX: &str = "lifecycle_max_expires_ttl";
+
+#[derive(Clone, Debug)]
+pub struct MongoStore {
+    db: MongoClient,
+    collections: Collections,
+}
+
+impl MongoStore {
+    pub async fn connect(
+        url: &str,
+        database: &str,
+        collections: Collections,
+    ) -> Result<MongoStore, RestError> {
+        let client_options = ClientOptions::parse(url).await?;
+        let mongo_client = mongodb::Client::with_options(client_options)?;
+        // Fail startup with an error if MongoDB can't be reached
+        mongo_client.list_database_names(None, None).await?;
+        Ok(MongoStore {
+            db: MongoClient::new(mongo_client, database),
+            collections,
+        })
+    }
+
+    async fn create_uploads_indexes(&self) -> Result<(), RestError> {
+        log::debug!("Creating upload collection indexes");
+        let mut indexes = Vec::new();
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"id":1, "active": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        );
+
+        indexes.push(
+            IndexModel::builder()
+                .keys(
+                    doc! {"active":1, "facts.owner": 1, "lifecycle.expires_at": 1, "meta.tags": 1},
+                )
+                .build(),
+        );
+
+        // Listings page through an owner's uploads newest first
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"facts.owner": 1, "active": 1, "_id": -1})
+                .build(),
+        );
+
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"facts.direct.complete_by": 1})
+                .options(IndexOptions::builder().sparse(true).build())
+                .build(),
+        );
+
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"active": 1, "lifecycle.deactivated": 1})
+                .build(),
+        );
+
+        self.db
+            .create_indexes(&self.collections.uploads, indexes, None)
+            .await
+    }
+
+    async fn create_users_indexes(&self) -> Result<(), RestError> {
+        log::debug!("Creating users collection indexes");
+        let mut indexes = Vec::new();
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"api_keys.key":1, "api_keys.secret": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        );
+
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"id":1, "pwd": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        );
+
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"email":1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        );
+
+        self.db
+            .create_indexes(&self.collections.users, indexes, None)
+            .await
+    }
+
+    async fn create_resumable_indexes(&self) -> Result<(), RestError> {
+        log::debug!("Creating resumable collection indexes");
+        let indexes = vec![
+            IndexModel::builder()
+                .keys(doc! {"id": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder().keys(doc! {"expires": 1}).build(),
+        ];
+
+        self.db
+            .create_indexes(&self.collections.resumable, indexes, None)
+            .await
+    }
+
+    async fn create_blobs_indexes(&self) -> Result<(), RestError> {
+        log::debug!("Creating blobs collection indexes");
+        let indexes = vec![IndexModel::builder()
+            .keys(doc! {"digest": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build()];
+
+        self.db
+            .create_indexes(&self.collections.blobs, indexes, None)
+            .await
+    }
+
+    async fn create_deletions_indexes(&self) -> Result<(), RestError> {
+        log::debug!("Creating deletions collection indexes");
+        let indexes = vec![
+            IndexModel::builder()
+                .keys(doc! {"object": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder().keys(doc! {"next_attempt": 1}).build(),
+        ];
+
+        self.db
+            .create_indexes(&self.collections.deletions, indexes, None)
+            .await
+    }
+
+    // Uploads matching query, in sort order and up to limit when they are given
+    async fn find_uploads(
+        &self,
+        query: Document,
+        sort: Option<Document>,
+        limit: Option<i64>,
+    ) -> Result<Vec<MetaData>, RestError> {
+        let find_options = FindOptions::builder().sort(sort).limit(limit).build();
+        self.db
+            .find::<MetaData>(&self.collections.uploads, query, Some(find_options))
+            .await
+    }
+}
+
+#[async_trait]
+impl MetadataStore for MongoStore {
+    async fn create_indexes(&self) -> Result<(), RestError> {
+        if self.create_uploads_indexes().await.is_err() {
+            log::error!("Error creating upload collection indexes");
+        };
+        if self.create_users_indexes().await.is_err() {
+            log::error!("Error creating users collection indexes");
+        };
+        if self.create_resumable_indexes().await.is_err() {
+            log::error!("Error creating resumable collection indexes");
+        };
+        if self.create_blobs_indexes().await.is_err() {
+            log::error!("Error creating blobs collection indexes");
+        };
+        if self.create_deletions_indexes().await.is_err() {
+            log::error!("Error creating deletions collection indexes");
+        };
+        Ok(())
+    }
+
+    //
+    // Uploads
+    //
+
+    async fn insert_upload(&self, upload: MetaData) -> Result<MetaData, RestError> {
+        self.db
+            .insert_one::<MetaData>(&self.collections.uploads, upload, None)
+            .await
+    }
+
+    async fn find_upload_by_link(&self, link_id: &str) -> Result<MetaData, RestError> {
+        let filter = doc! {"links.id": link_id, "active": true };
+        self.db
+            .find_one::<MetaData>(&self.collections.uploads, filter, None)
+            .await
+    }
+
+    async fn find_owned_upload(&self, owner: &str, id: &str) -> Result<MetaData, RestError> {
+        let filter = doc! {"active": true, "facts.owner": owner, "id": id };
+        self.db
+            .find_one::<MetaData>(&self.collections.uploads, filter, None)
+            .await
+    }
+
+    async fn owned_uploads(
+        &self,
+        owner: &str,
+        tags: Option<Vec<String>>,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<MetaData>, RestError> {
+        let mut query = match tags {
+            Some(t) => {
+                doc! {"active": true, "facts.owner": owner, "lifecycle.max.expires": {"$gt": Utc::now()}, "meta.tags": { "$all": t } }
+            }
+            None => {
+                doc! {"active": true, "facts.owner": owner, "lifecycle.max.expires": {"$gt": Utc::now()}}
+            }
+        };
+        let total = match after {
+            Some(_) => None,
+            None => Some(
+                self.db
+                    .count_documents(&self.collections.uploads, query.clone())
+                    .await?,
+            ),
+        };
+        // Pages continue after the _id of the last upload listed
+        if let Some(after) = after {
+            let after = ObjectId::parse_str(after)
+                .map_err(|_| RestError::BadRequest(PAGE_INVALID_CURSOR))?;
+            query.insert("_id", doc! {"$lt": after});
+        }
+
+        // Fetch one more than asked for, to tell whether there is another page
+        let find_options = FindOptions::builder()
+            .sort(doc! { "_id": -1 })
+            .limit(limit + 1)
+            .build();
+        let mut docs = self
+            .db
+            .find::<Document>(&self.collections.uploads, query, Some(find_options))
+            .await?;
+        let more = docs.len() as i64 > limit;
+        docs.truncate(limit as usize);
+        let next = match (more, docs.last()) {
+            (true, Some(last)) => Some(last.get_object_id("_id")?.to_hex()),
+            _ => None,
+        };
+        let items = docs
+            .into_iter()
+            .map(from_document::<MetaData>)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Page { items, next, total })
+    }
+
+    async fn active_uploads(&self) -> Result<Vec<MetaData>, RestError> {
+        self.find_uploads(doc! {"active": true}, None, None).await
+    }
+
+    async fn active_uploads_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError> {
+        let query = match after {
+            Some(after) => doc! {"active": true, "id": {"$gt": after}},
+            None => doc! {"active": true},
+        };
+        self.find_uploads(query, Some(doc! {"id": 1}), Some(limit))
+            .await
+    }
+
+    async fn referenced_objects(&self, ids: &[String]) -> Result<HashSet<String>, RestError> {
+        let query = doc! {
+            "$and": [
+                {"$or": [{"active": true}, {"facts.direct": {"$exists": true}}]},
+                {"$or": [
+                    {"id": {"$in": ids}},
+                    {"facts.object": {"$in": ids}},
+                    {"facts.segments.id": {"$in": ids}},
+                    {"facts.direct.staging": {"$in": ids}},
+                ]},
+            ],
+        };
+        let mut referenced: HashSet<String> = self
+            .find_uploads(query, None, None)
+            .await?
+            .into_iter()
+            .flat_map(|u| {
+                let staging = u.staging_id().map(|s| s.to_owned());
+                u.object_ids().into_iter().chain(staging)
+            })
+            .collect();
+
+        let query = doc! {"refs": {"$gt": 0}, "object": {"$in": ids}};
+        let blobs = self
+            .db
+            .find::<Blob>(&self.collections.blobs, query, None)
+            .await?;
+        referenced.extend(blobs.into_iter().map(|b| b.object));
+
+        let query = doc! {"parts.id": {"$in": ids}};
+        let sessions = self
+            .db
+            .find::<UploadSession>(&self.collections.resumable, query, None)
+            .await?;
+        referenced.extend(sessions.into_iter().flat_map(|s| s.parts).map(|p| p.id));
+
+        referenced.retain(|id| ids.contains(id));
+        Ok(referenced)
+    }
+
+    async fn expired_uploads(&self, limit: i64) -> Result<Vec<MetaData>, RestError> {
+        let query = doc! {"active": true, "lifecycle.max.expires": {"$lt": Utc::now()}};
+        self.find_uploads(query, Some(doc! { "_id": -1 }), Some(limit))
+            .await
+    }
+
+    async fn eviction_candidates(
+        &self,
+        order: Eviction,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError> {
+        let sort = match order {
+            Eviction::Expiring => doc! {"lifecycle.max.expires": 1},
+            Eviction::Oldest => doc! {"meta.created": 1},
+        };
+        self.find_uploads(doc! {"active": true}, Some(sort), Some(limit))
+            .await
+    }
+
+    async fn deactivate_upload(&self, id: &str) -> Result<MetaData, RestError> {
+        let filter = doc! {"id": id, "active": true};
+        let update = doc! {"$set": {"active": false, "lifecycle.deactivated": Utc::now() }};
+        self.db
+            .find_one_and_update::<MetaData>(&self.collections.uploads, filter, update, None)
+            .await
+    }
+
+    async fn record_read(&self, id: &str, link_id: &str) -> Result<MetaData, RestError> {
+        let filter = doc! {"id": id, "active": true, "links.id": link_id};
+        let update = doc! { "$inc": { "lifecycle.current.reads": 1, "links.$.reads": 1 } };
+        self.db
+            .find_one_and_update::<MetaData>(&self.collections.uploads, filter, update, None)
+            .await
+    }
+
+    async fn reserve_read_bytes(&self, id: &str, bytes: i64, limit: i64) -> Result<(), RestError> {
+        let filter = doc! {
+            "id": id,
+            "active": true,
+            "$or": [
+                { "lifecycle.current.bytes": { "$exists": false } },
+                { "lifecycle.current.bytes": { "$lte": limit - bytes } },
+            ],
+        };
+        let update = doc! { "$inc": { "lifecycle.current.bytes": bytes } };
+        self.db
+            .find_one_and_update::<Document>(&self.collections.uploads, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn refund_read_bytes(&self, id: &str, bytes: i64) -> Result<(), RestError> {
+        let filter = doc! {"id": id};
+        let update = doc! { "$inc": { "lifecycle.current.bytes": -bytes } };
+        self.db
+            .find_one_and_update::<Document>(&self.collections.uploads, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn init_budget(&self, used: usize) -> Result<(), RestError> {
+        let filter = doc! {"name": "budget"};
+        let update = doc! {"$setOnInsert": {"used": used as i64}};
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        self.db
+            .find_one_and_update::<Document>(&self.collections.admin, filter, update, Some(options))
+            .await?;
+        Ok(())
+    }
+
+    async fn budget_used(&self) -> Result<usize, RestError> {
+        let budget = self
+            .db
+            .find_one::<Document>(&self.collections.admin, doc! {"name": "budget"}, None)
+            .await?;
+        Ok(budget.get_i64("used")?.max(0) as usize)
+    }
+
+    async fn reserve_storage(&self, bytes: i64, limit: i64) -> Result<(), RestError> {
+        let filter = match limit {
+            0 => doc! {"name": "budget"},
+            _ => doc! {"name": "budget", "used": {"$lte": limit - bytes}},
+        };
+        let update = doc! {"$inc": {"used": bytes}};
+        self.db
+            .find_one_and_update::<Document>(&self.collections.admin, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_storage(&self, bytes: i64) -> Result<(), RestError> {
+        let filter = doc! {"name": "budget"};
+        let update = doc! {"$inc": {"used": -bytes}};
+        self.db
+            .find_one_and_update::<Document>(&self.collections.admin, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn stored_bytes(&self) -> Result<usize, RestError> {
+        let pipeline = vec![
+            doc! {"$match": {"active": true}},
+            // Older documents hold the stored size directly in meta.bytes
+            doc! {"$group": {
+                "_id": null,
+                "bytes": {"$sum": {"$ifNull": ["$meta.bytes.stored", "$meta.bytes"]}},
+            }},
+        ];
+        let used = match self
+            .db
+            .aggregate(&self.collections.uploads, pipeline)
+            .await?
+            .first()
+        {
+            Some(d) => match d.get("bytes") {
+                Some(bson::Bson::Int32(b)) => *b as usize,
+                Some(bson::Bson::Int64(b)) => *b as usize,
+                Some(bson::Bson::Double(b)) => *b as usize,
+                _ => 0,
+            },
+            None => 0,
+        };
+        Ok(used)
+    }
+
+    async fn add_tags(&self, owner: &str, id: &str, tags: &[String]) -> Result<(), RestError> {
+        let filter = doc! {"active": true, "facts.owner": owner, "id": id };
+        let update = doc! { "$addToSet": { "meta.tags": { "$each": tags } } };
+        self.db
+            .find_one_and_update::<MetaData>(&self.collections.uploads, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_tags(&self, owner: &str, id: &str, tags: &[String]) -> Result<(), RestError> {
+        let filter = doc! {"active": true, "facts.owner": owner, "id": id };
+        let update = doc! { "$pull": { "meta.tags": { "$in": tags } } };
+        self.db
+            .find_one_and_update::<MetaData>(&self.collections.uploads, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    //
+    // Links
+    //
+
+    async fn push_link(&self, owner: &str, id: &str, link: &Link) -> Result<MetaData, RestError> {
+        let filter = doc! {"active": true, "facts.owner": owner, "id": id };
+        let update = doc! { "$push": { "links": to_document(link)? } };
+        self.db
+            .find_one_and_update::<MetaData>(&self.collections.uploads, filter, update, None)
+            .await
+    }
+
+    async fn pull_link(&self, owner: &str, id: &str, link_id: &str) -> Result<(), RestError> {
+        let filter = doc! {"active": true, "facts.owner": owner, "id": id, "links.id": link_id };
+        let update = doc! { "$pull": { "links": { "id": link_id } } };
+        self.db
+            .find_one_and_update::<MetaData>(&self.collections.uploads, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    //
+    // Direct uploads
+    //
+
+    async fn claim_pending_upload(
+        &self,
+        owner: Option<&str>,
+        id: &str,
+    ) -> Result<MetaData, RestError> {
+        let filter = doc! {
+            "id": id,
+            "active": false,
+            "facts.owner": owner,
+            "facts.direct.complete_by": {"$gt": Utc::now()},
+            "facts.direct.completing": {"$exists": false},
+        };
+        let update = doc! {"$set": {"facts.direct.completing": true}};
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        self.db
+            .find_one_and_update::<MetaData>(
+                &self.collections.uploads,
+                filter,
+                update,
+                Some(options),
+            )
+            .await
+    }
+
+    async fn release_pending_upload(&self, id: &str) -> Result<(), RestError> {
+        let filter = doc! {"id": id, "active": false, "facts.direct.completing": true};
+        let update = doc! {"$unset": {"facts.direct.completing": ""}};
+        self.db
+            .find_one_and_update::<Document>(&self.collections.uploads, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn activate_pending_upload(&self, id: &str) -> Result<MetaData, RestError> {
+        let filter = doc! {"id": id, "active": false, "facts.direct": {"$exists": true}};
+        let update = doc! {
+            "$set": {"active": true},
+            "$unset": {"facts.direct": ""},
+        };
+        self.db
+            .find_one_and_update::<MetaData>(&self.collections.uploads, filter, update, None)
+            .await
+    }
+
+    async fn drop_pending_upload(&self, id: &str) -> Result<(), RestError> {
+        let filter = doc! {"id": id, "active": false, "facts.direct": {"$exists": true}};
+        let update = doc! {
+            "$set": {"lifecycle.deactivated": Utc::now()},
+            "$unset": {"facts.direct": ""},
+        };
+        self.db
+            .find_one_and_update::<Document>(&self.collections.uploads, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn pending_uploads(&self) -> Result<Vec<MetaData>, RestError> {
+        let query = doc! {"active": false, "facts.direct": {"$exists": true}};
+        self.find_uploads(query, None, None).await
+    }
+
+    async fn expired_pending_uploads(&self, limit: i64) -> Result<Vec<MetaData>, RestError> {
+        let query = doc! {"active": false, "facts.direct.complete_by": {"$lt": Utc::now()}};
+        self.find_uploads(query, None, Some(limit)).await
+    }
+
+    //
+    // Users and API keys
+    //
+
+    async fn insert_user(&self, user: User) -> Result<User, RestError> {
+        self.db
+            .insert_one::<User>(&self.collections.users, user, None)
+            .await
+    }
+
+    async fn find_user_by_email(&self, email: &str) -> Result<User, RestError> {
+        let filter = doc! {"email": email };
+        self.db
+            .find_one::<User>(&self.collections.users, filter, None)
+            .await
+    }
+
+    async fn find_user_by_login(&self, email: &str, pwd: &str) -> Result<User, RestError> {
+        let filter = doc! {"email": email, "pwd": pwd };
+        self.db
+            .find_one::<User>(&self.collections.users, filter, None)
+            .await
+    }
+
+    async fn find_user(&self, id: &str) -> Result<User, RestError> {
+        let filter = doc! {"id": id };
+        self.db
+            .find_one::<User>(&self.collections.users, filter, None)
+            .await
+    }
+
+    async fn find_user_by_credentials(&self, id: &str, pwd: &str) -> Result<User, RestError> {
+        let filter = doc! {"$or": [ {"id": id, "pwd": pwd }, { "api_keys.key": id, "api_keys.secret": pwd } ] };
+        self.db
+            .find_one::<User>(&self.collections.users, filter, None)
+            .await
+    }
+
+    async fn push_api_key(&self, user_id: &str, api_key: &ApiKeyHashed) -> Result<(), RestError> {
+        let filter = doc! {"id": user_id };
+        let update = doc! {"$push": {"api_keys": to_document(api_key)? }};
+        self.db
+            .find_one_and_update::<User>(&self.collections.users, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn pull_api_key(&self, user_id: &str, key: &str) -> Result<(), RestError> {
+        let filter = doc! {"id": user_id, "api_keys.key": key };
+        let update = doc! {"$pull": {"api_keys": { "key": key } }};
+        self.db
+            .find_one_and_update::<User>(&self.collections.users, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    //
+    // Locks
+    //
+
+    async fn init_locks(&self, stale: DateTime<Utc>) -> Result<(), RestError> {
+        // Create reconcile lock, backdated so the first scheduled run isn't held up
+        if self
+            .db
+            .find_one::<Document>(&self.collections.admin, doc! {"name": "reconcile"}, None)
+            .await
+            .is_err()
+        {
+            log::debug!("Reconcile lock doc does not exist, creating");
+            let reconcile_doc =
+                doc! {"name": "reconcile", "modified": Utc::now() - Duration::days(1)};
+            self.db
+                .insert_one(&self.collections.admin, reconcile_doc, None)
+                .await?;
+        }
+
+        // Create expiry lock, backdated so it can be taken straight away
+        if self
+            .db
+            .find_one::<Document>(&self.collections.admin, doc! {"name": "expiry"}, None)
+            .await
+            .is_err()
+        {
+            log::debug!("Expiry lock doc does not exist, creating");
+            let expiry_doc = doc! {"name": "expiry", "modified": Utc::now() - Duration::days(1)};
+            self.db
+                .insert_one(&self.collections.admin, expiry_doc, None)
+                .await?;
+        }
+
+        // Create cleanup lock
+        let filter_lock = doc! {"name":"cleanup"};
+
+        // Check if cleanup doc already exists, and create it if it does not
+        if self
+            .db
+            .find_one::<Document>(&self.collections.admin, filter_lock, None)
+            .await
+            .is_err()
+        {
+            log::debug!("Cleanup lock doc does not exist, creating");
+            let cleanup_doc = doc! {"name":"cleanup", "active": false, "modified": Utc::now() };
+
+            self.db
+                .insert_one(&self.collections.admin, cleanup_doc, None)
+                .await?;
+            return Ok(());
+        }
+
+        // Ensure cleanup doc is not in a "failed" state
+        let filter_lock = doc! {"name":"cleanup", "active": true, "modified": { "$lt" : stale } };
+        let update_lock = doc! {"$set": {"active": false, "modified": Utc::now() }};
+        if self
+            .db
+            .find_one_and_update::<Document>(
+                &self.collections.admin,
+                filter_lock,
+                update_lock,
+                None,
+            )
+            .await
+            .is_err()
+        {
+            log::debug!("Cleanup doc already is correct");
+        };
+
+        Ok(())
+    }
+
+    async fn lock_cleanup(&self, since: DateTime<Utc>) -> Result<(), RestError> {
+        let filter_lock = doc! {"active": false, "name":"cleanup", "modified": {"$lt": since }};
+        let update_lock = doc! {"$set": {"active": true, "modified": Utc::now()}};
+        self.db
+            .find_one_and_update::<Document>(
+                &self.collections.admin,
+                filter_lock,
+                update_lock,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn unlock_cleanup(&self) -> Result<(), RestError> {
+        let filter_unlock = doc! {"active": true, "name":"cleanup"};
+        let update_unlock = doc! {"$set": {"active": false}};
+        self.db
+            .find_one_and_update::<Document>(
+                &self.collections.admin,
+                filter_unlock,
+                update_unlock,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn lock_reconcile(&self, since: DateTime<Utc>) -> Result<(), RestError> {
+        let filter = doc! {"name": "reconcile", "modified": {"$lt": since}};
+        let update = doc! {"$set": {"modified": Utc::now()}};
+        self.db
+            .find_one_and_update::<Document>(&self.collections.admin, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn lock_expiry(&self, owner: &str, since: DateTime<Utc>) -> Result<(), RestError> {
+        let filter =
+            doc! {"name": "expiry", "$or": [{"owner": owner}, {"modified": {"$lt": since}}]};
+        let update = doc! {"$set": {"owner": owner, "modified": Utc::now()}};
+        self.db
+            .find_one_and_update::<Document>(&self.collections.admin, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    //
+    // Shared objects
+    //
+
+    async fn share_blob(&self, digest: &str, object: &str) -> Result<Blob, RestError> {
+        let filter = doc! {"digest": digest, "refs": {"$gt": 0}};
+        let update = doc! {
+            "$inc": {"refs": 1},
+            "$setOnInsert": {"object": object, "created": Utc::now()},
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        self.db
+            .find_one_and_update::<Blob>(&self.collections.blobs, filter, update, Some(options))
+            .await
+    }
+
+    async fn release_blob(&self, digest: &str, upload: &str) -> Result<Blob, RestError> {
+        let filter = doc! {"digest": digest, "refs": {"$gt": 0}, "released": {"$ne": upload}};
+        let update = doc! {"$inc": {"refs": -1}, "$push": {"released": upload}};
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        self.db
+            .find_one_and_update::<Blob>(&self.collections.blobs, filter, update, Some(options))
+            .await
+    }
+
+    async fn delete_blob(&self, digest: &str) -> Result<(), RestError> {
+        self.db
+            .delete_one(
+                &self.collections.blobs,
+                doc! {"digest": digest, "refs": {"$lte": 0}},
+                None,
+            )
+            .await
+    }
+
+    //
+    // Resumable upload sessions
+    //
+
+    async fn insert_session(&self, session: UploadSession) -> Result<UploadSession, RestError> {
+        self.db
+            .insert_one::<UploadSession>(&self.collections.resumable, session, None)
+            .await
+    }
+
+    async fn find_open_session(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<UploadSession, RestError> {
+        let filter =
+            doc! {"id": id, "owner": owner, "status": SESSION_OPEN, "expires": {"$gt": Utc::now()}};
+        self.db
+            .find_one::<UploadSession>(&self.collections.resumable, filter, None)
+            .await
+    }
+
+    async fn append_session_part(
+        &self,
+        id: &str,
+        offset: i64,
+        part: &UploadPart,
+    ) -> Result<(), RestError> {
+        let filter = doc! {"id": id, "status": SESSION_OPEN, "offset": offset};
+        let update = doc! {
+            "$inc": {"offset": part.bytes},
+            "$push": {"parts": {"id": &part.id, "bytes": part.bytes}},
+        };
+        self.db
+            .find_one_and_update::<Document>(&self.collections.resumable, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_session(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<UploadSession, RestError> {
+        let filter =
+            doc! {"id": id, "owner": owner, "status": SESSION_OPEN, "expires": {"$gt": Utc::now()}};
+        let update = doc! {"$set": {"status": SESSION_COMPLETING}};
+        self.db
+            .find_one_and_update::<UploadSession>(&self.collections.resumable, filter, update, None)
+            .await
+    }
+
+    async fn reopen_session(&self, id: &str) -> Result<(), RestError> {
+        let filter = doc! {"id": id, "status": SESSION_COMPLETING};
+        let update = doc! {"$set": {"status": SESSION_OPEN}};
+        self.db
+            .find_one_and_update::<Document>(&self.collections.resumable, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_session(&self, id: &str) -> Result<(), RestError> {
+        self.db
+            .delete_one(&self.collections.resumable, doc! {"id": id}, None)
+            .await
+    }
+
+    async fn sessions(&self) -> Result<Vec<UploadSession>, RestError> {
+        self.db
+            .find::<UploadSession>(&self.collections.resumable, doc! {}, None)
+            .await
+    }
+
+    async fn expired_sessions(&self, limit: i64) -> Result<Vec<UploadSession>, RestError> {
+        let query = doc! {"expires": {"$lt": Utc::now()}};
+        let find_options = FindOptions::builder().limit(limit).build();
+        self.db
+            .find::<UploadSession>(&self.collections.resumable, query, Some(find_options))
+            .await
+    }
+
+    //
+    // Deletion queue
+    //
+
+    async fn queue_deletion(&self, object: &str, error: &str) -> Result<(), RestError> {
+        let filter = doc! {"object": object};
+        let update = doc! {
+            "$set": {"last_error": error},
+            "$setOnInsert": {
+                "status": DELETION_PENDING,
+                "attempts": 0,
+                "created": Utc::now(),
+                "next_attempt": Utc::now() + Deletion::backoff(0),
+            },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        self.db
+            .find_one_and_update::<Deletion>(
+                &self.collections.deletions,
+                filter,
+                update,
+                Some(options),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn due_deletions(&self, limit: i64) -> Result<Vec<Deletion>, RestError> {
+        let query = doc! {"next_attempt": {"$lte": Utc::now()}};
+        let find_options = FindOptions::builder()
+            .sort(doc! {"next_attempt": 1})
+            .limit(limit)
+            .build();
+        self.db
+            .find::<Deletion>(&self.collections.deletions, query, Some(find_options))
+            .await
+    }
+
+    async fn reschedule_deletion(&self, deletion: &Deletion) -> Result<(), RestError> {
+        let update = doc! {"$set": {
+            "status": &deletion.status,
+            "attempts": deletion.attempts,
+            "next_attempt": deletion.next_attempt,
+            "last_error": &deletion.last_error,
+        }};
+        self.db
+            .find_one_and_update::<Document>(
+                &self.collections.deletions,
+                doc! {"object": &deletion.object},
+                update,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_deletion(&self, object: &str) -> Result<(), RestError> {
+        self.db
+            .delete_one(&self.collections.deletions, doc! {"object": object}, None)
+            .await
+    }
+
+    async fn deletion_counts(&self) -> Result<(usize, usize), RestError> {
+        let pipeline = vec![doc! {"$group": {"_id": "$status", "count": {"$sum": 1}}}];
+        let counts = self
+            .db
+            .aggregate(&self.collections.deletions, pipeline)
+            .await?;
+        let count = |status: &str| {
+            counts
+                .iter()
+                .find(|c| c.get_str("_id") == Ok(status))
+                .and_then(|c| c.get_i32("count").ok())
+                .unwrap_or(0) as usize
+        };
+        Ok((count(DELETION_PENDING), count(DELETION_DEAD)))
+    }
+
+    //
+    // Purging
+    //
+
+    async fn purgeable_uploads(
+        &self,
+        cutoff: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError> {
+        let query = doc! {
+            "active": false,
+            "facts.direct": {"$exists": false},
+            "purged": {"$exists": false},
+            "$or": [
+                {"lifecycle.deactivated": {"$lt": cutoff}},
+                {"lifecycle.deactivated": {"$exists": false}, "lifecycle.max.expires": {"$lt": cutoff}},
+            ],
+        };
+        self.find_uploads(query, None, Some(limit)).await
+    }
+
+    async fn delete_upload(&self, id: &str) -> Result<(), RestError> {
+        let filter = doc! {"id": id, "active": false, "facts.direct": {"$exists": false}, "purged": {"$exists": false}};
+        self.db
+            .delete_one(&self.collections.uploads, filter, None)
+            .await
+    }
+
+    async fn tombstone_upload(&self, tombstone: &Tombstone) -> Result<(), RestError> {
+        let filter = doc! {"id": &tombstone.id, "active": false, "facts.direct": {"$exists": false}, "purged": {"$exists": false}};
+        self.db
+            .replace_one(&self.collections.uploads, filter, tombstone, None)
+            .await
+    }
+
+    //
+    // TTL expiry
+    //
+
+    async fn set_ttl_expiry(&self, enabled: bool) -> Result<(), RestError> {
+        // Removals carry the upload as it was before, so its objects can be found
+        let images = doc! {
+            "collMod": &self.collections.uploads,
+            "changeStreamPreAndPostImages": {"enabled": enabled},
+        };
+        if !enabled {
+            // Nothing to undo unless TTL expiry was enabled before
+            if self
+                .db
+                .drop_index(&self.collections.uploads, TTL_INDEX)
+                .await
+                .is_ok()
+            {
+                log::info!("\"Removed TTL index, expired uploads are left to the cleanup\"");
+                self.db.run_command(images).await?;
+            }
+            return Ok(());
+        }
+
+        // Creating the index creates the collection, which has to exist to be modified
+        let indexes = vec![IndexModel::builder()
+            .keys(doc! {"lifecycle.max.expires": 1})
+            .options(
+                IndexOptions::builder()
+                    .name(TTL_INDEX.to_owned())
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build()];
+        self.db
+            .create_indexes(&self.collections.uploads, indexes, None)
+            .await?;
+        self.db.run_command(images).await?;
+        Ok(())
+    }
+
+    async fn watch_expired(&self, owner: &str) -> Result<ExpiredUploads, RestError> {
+        // Resume after the last upload handled, by whichever instance handled it
+        let lock = self
+            .db
+            .find_one::<Document>(&self.collections.admin, doc! {"name": "expiry"}, None)
+            .await?;
+        let token = match lock.get("token") {
+            Some(t) => Some(from_bson::<ResumeToken>(t.clone())?),
+            None => None,
+        };
+        let options = ChangeStreamOptions::builder()
+            .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
+            .start_after(token)
+            .build();
+        let pipeline = vec![doc! {"$match": {"operationType": "delete"}}];
+        let events = self
+            .db
+            .watch::<Document>(&self.collections.uploads, pipeline, Some(options))
+            .await?;
+
+        let (store, owner) = (self.clone(), owner.to_owned());
+        Ok(stream::unfold(events, move |mut events| {
+            let (store, owner) = (store.clone(), owner.clone());
+            async move {
+                loop {
+                    let event = match events.next().await? {
+                        Ok(e) => e,
+                        Err(e) => return Some((Err(e.into()), events)),
+                    };
+                    let position = match serde_json::to_string(&event.id) {
+                        Ok(p) => p,
+                        Err(e) => return Some((Err(e.into()), events)),
+                    };
+                    // Documents that aren't uploads, such as tombstones removed by hand, hold no objects
+                    match event.full_document_before_change.map(from_document::<MetaData>) {
+                        Some(Ok(upload)) => {
+                            return Some((Ok(ExpiredUpload { upload, position }), events))
+                        }
+                        Some(Err(_)) => {
+                            log::debug!("\"Removed document {:?} isn't an upload\"", event.document_key);
+                        }
+                        None => {
+                            log::warn!(
+                                "\"Upload {:?} was removed without its previous state, its objects are left to reconciliation\"",
+                                event.document_key
+                            );
+                        }
+                    }
+                    if let Err(e) = store.save_expiry_position(&owner, &position).await {
+                        return Some((Err(e), events));
+                    }
+                }
+            }
+        })
+        .boxed())
+    }
+
+    // Record the change the holder of the expiry lock has handled, failing if it lost the lock
+    async fn save_expiry_position(&self, owner: &str, position: &str) -> Result<(), RestError> {
+        let token: ResumeToken = serde_json::from_str(position)?;
+        let filter = doc! {"name": "expiry", "owner": owner};
+        let update = doc! {"$set": {"token": to_bson(&token)?}};
+        self.db
+            .find_one_and_update::<Document>(&self.collections.admin, filter, update, None)
+            .await?;
+        Ok(())
+    }
+
+    //
+    // Storage migrations
+    //
+
+    async fn migrated_objects(
+        &self,
+        collection: &str,
+        target: &str,
+    ) -> Result<Vec<Migration>, RestError> {
+        let indexes = vec![IndexModel::builder()
+            .keys(doc! {"object": 1, "target": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build()];
+        self.db.create_indexes(collection, indexes, None).await?;
+        self.db
+            .find::<Migration>(collection, doc! {"target": target}, None)
+            .await
+    }
+
+    async fn record_migration(
+        &self,
+        collection: &str,
+        migration: &Migration,
+    ) -> Result<(), RestError> {
+        let filter = doc! {"object": &migration.object, "target": &migration.target};
+        let update = doc! {"$set": {
+            "bytes": migration.bytes,
+            "digest": &migration.digest,
+            "migrated": migration.migrated,
+        }};
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        self.db
+            .find_one_and_update::<Migration>(collection, filter, update, Some(options))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::{Duration, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::data::Data;
+use crate::error::Error as RestError;
+use crate::state::Configs;
+
+// Session states, a session is claimed while its parts are being assembled into an upload
+pub const SESSION_OPEN: &str = "open";
+pub const SESSION_COMPLETING: &str = "completing";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub owner: Option<String>,
+    pub status: String,
+    pub created: chrono::DateTime<Utc>,
+    pub expires: bson::DateTime,
+    pub length: i64,
+    pub offset: i64,
+    pub parts: Vec<UploadPart>,
+    pub encryption: SessionEncryption,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadPart {
+    pub id: String,
+    pub bytes: i64,
+}
+
+// Parts are encrypted at rest under a per-session key, stored sealed with the latest server key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionEncryption {
+    #[serde(with = "serde_bytes")]
+    pub key: Option<Vec<u8>>,
+    pub version: Option<u8>,
+}
+
+impl UploadSession {
+    pub fn new(
+        length: i64,
+        owner: Option<String>,
+        configs: &Configs,
+    ) -> Result<UploadSession, RestError> {
+        let encryption = if configs.encrypt_data {
+            let key = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+            let latest_encrypt_key = configs.keys.latest_key();
+            SessionEncryption {
+                key: Some(Data::encrypt(latest_encrypt_key.key, key.into())?),
+                version: Some(latest_encrypt_key.ver),
+            }
+        } else {
+            SessionEncryption {
+                key: None,
+                version: None,
+            }
+        };
+
+        Ok(UploadSession {
+            id: Uuid::new_v4().to_string(),
+            owner,
+            status: SESSION_OPEN.to_owned(),
+            created: Utc::now(),
+            expires: (Utc::now() + Duration::seconds(configs.resumable_expires)).into(),
+            length,
+            offset: 0,
+            parts: Vec::new(),
+            encryption,
+        })
+    }
+
+    // Parts are stored next to regular objects, so prefix them with the session they belong to
+    pub fn part_id(&self) -> String {
+        format!("{}-{}", self.id, Uuid::new_v4())
+    }
+
+    pub fn key(&self, configs: &Configs) -> Result<Option<String>, RestError> {
+        let (encrypted_key, version) = match (&self.encryption.key, self.encryption.version) {
+            (Some(k), Some(v)) => (k, v),
+            _ => return Ok(None),
+        };
+        let decrypt_key = match configs.keys.get_ver(version) {
+            Some(k) => k,
+            None => {
+                log::error!("\"Missing encryption key version {} for session\"", version);
+                return Err(RestError::NotFound);
+            }
+        };
+
+        let secret_key = orion::aead::SecretKey::from_slice(decrypt_key.key.as_bytes())?;
+        let key = orion::aead::open(&secret_key, encrypted_key)?;
+        Ok(Some(std::str::from_utf8(&key)?.to_owned()))
+    }
+}
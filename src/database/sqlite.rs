@@ -0,0 +1,2124 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, Params, TransactionBehavior};
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::database::blobs::Blob;
+use crate::database::deletions::{Deletion, DELETION_DEAD, DELETION_PENDING};
+use crate::database::links::Link;
+use crate::database::metadata::{MetaData, Tombstone};
+use crate::database::migrations::Migration;
+use crate::database::resumable::{UploadPart, UploadSession, SESSION_COMPLETING, SESSION_OPEN};
+use crate::database::store::{
+    Collections, Eviction, ExpiredUploads, MetadataStore, Page, PAGE_INVALID_CURSOR,
+};
+use crate::database::users::{ApiKeyHashed, User};
+use crate::error::Error as RestError;
+
+// Writers wait this long for another process to release the database
+const BUSY_TIMEOUT_SECONDS: u64 = 5;
+
+// SQLite has no way to remove rows as they expire, or to report removals to another process
+const TTL_UNSUPPORTED: &str = "TTL expiry needs the mongo metadata store";
+
+// Keeps metadata in a single SQLite file, for deployments too small to warrant a MongoDB cluster.
+// Documents are stored as JSON, next to the fields they are looked up by.
+#[derive(Clone, Debug)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    tables: Arc<Tables>,
+}
+
+// Quoted table names. Links and API keys get tables of their own, so downloads and logins can
+// find their upload or user through an index, and the storage budget's running total gets one
+// beside the locks.
+#[derive(Debug)]
+struct Tables {
+    uploads: String,
+    links: String,
+    admin: String,
+    budget: String,
+    users: String,
+    api_keys: String,
+    resumable: String,
+    blobs: String,
+    deletions: String,
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+impl SqliteStore {
+    pub async fn open(path: &str, collections: Collections) -> Result<SqliteStore, RestError> {
+        let tables = Tables {
+            uploads: quote(&collections.uploads),
+            links: quote(&format!("{}_links", collections.uploads)),
+            admin: quote(&collections.admin),
+            budget: quote(&format!("{}_budget", collections.admin)),
+            users: quote(&collections.users),
+            api_keys: quote(&format!("{}_api_keys", collections.users)),
+            resumable: quote(&collections.resumable),
+            blobs: quote(&collections.blobs),
+            deletions: quote(&collections.deletions),
+        };
+        let path = path.to_owned();
+        let conn = tokio::task::spawn_blocking(move || -> Result<Connection, RestError> {
+            let conn = Connection::open(path)?;
+            conn.busy_timeout(std::time::Duration::from_secs(BUSY_TIMEOUT_SECONDS))?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            Ok(conn)
+        })
+        .await??;
+
+        let store = SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+            tables: Arc::new(tables),
+        };
+        store.create_tables(&collections).await?;
+        Ok(store)
+    }
+
+    // Tables have to exist before anything is read, unlike indexes in MongoDB
+    async fn create_tables(&self, collections: &Collections) -> Result<(), RestError> {
+        let index = |table: &str, name: &str| quote(&format!("{}_{}", table, name));
+        let schema = format!(
+            "CREATE TABLE IF NOT EXISTS {uploads} (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT NOT NULL UNIQUE,
+                active INTEGER NOT NULL,
+                owner TEXT,
+                expires INTEGER NOT NULL,
+                created INTEGER NOT NULL,
+                pending INTEGER,
+                deactivated INTEGER,
+                purged INTEGER,
+                doc TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {uploads_owner} ON {uploads} (owner, active, expires);
+            CREATE INDEX IF NOT EXISTS {uploads_listing} ON {uploads} (owner, active, seq);
+            CREATE INDEX IF NOT EXISTS {uploads_expires} ON {uploads} (active, expires);
+            CREATE INDEX IF NOT EXISTS {uploads_pending} ON {uploads} (pending) WHERE pending IS NOT NULL;
+            CREATE INDEX IF NOT EXISTS {uploads_purgeable} ON {uploads} (deactivated) WHERE active = 0 AND purged IS NULL;
+            CREATE TABLE IF NOT EXISTS {links} (
+                id TEXT PRIMARY KEY,
+                upload TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {links_upload} ON {links} (upload);
+            CREATE TABLE IF NOT EXISTS {admin} (
+                name TEXT PRIMARY KEY,
+                active INTEGER NOT NULL,
+                modified INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {budget} (
+                name TEXT PRIMARY KEY,
+                used INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {users} (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT NOT NULL UNIQUE,
+                email TEXT NOT NULL UNIQUE,
+                pwd TEXT NOT NULL,
+                doc TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {api_keys} (
+                key TEXT PRIMARY KEY,
+                secret TEXT NOT NULL,
+                user TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {api_keys_user} ON {api_keys} (user);
+            CREATE TABLE IF NOT EXISTS {resumable} (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT NOT NULL UNIQUE,
+                owner TEXT,
+                status TEXT NOT NULL,
+                expires INTEGER NOT NULL,
+                doc TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {resumable_expires} ON {resumable} (expires);
+            CREATE TABLE IF NOT EXISTS {blobs} (
+                digest TEXT PRIMARY KEY,
+                refs INTEGER NOT NULL,
+                doc TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {deletions} (
+                object TEXT PRIMARY KEY,
+                status TEXT NOT NULL,
+                next_attempt INTEGER NOT NULL,
+                doc TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {deletions_next_attempt} ON {deletions} (next_attempt);",
+            uploads = self.tables.uploads,
+            uploads_owner = index(&collections.uploads, "owner"),
+            uploads_listing = index(&collections.uploads, "listing"),
+            uploads_expires = index(&collections.uploads, "expires"),
+            uploads_pending = index(&collections.uploads, "pending"),
+            uploads_purgeable = index(&collections.uploads, "purgeable"),
+            links = self.tables.links,
+            links_upload = index(&collections.uploads, "links_upload"),
+            admin = self.tables.admin,
+            budget = self.tables.budget,
+            users = self.tables.users,
+            api_keys = self.tables.api_keys,
+            api_keys_user = index(&collections.users, "api_keys_user"),
+            resumable = self.tables.resumable,
+            resumable_expires = index(&collections.resumable, "expires"),
+            blobs = self.tables.blobs,
+            deletions = self.tables.deletions,
+            deletions_next_attempt = index(&collections.deletions, "next_attempt"),
+        );
+        self.call(move |conn, _| Ok(conn.execute_batch(&schema)?))
+            .await
+    }
+
+    // Run a query on the blocking pool, the connection is used by one query at a time
+    async fn call<T, F>(&self, f: F) -> Result<T, RestError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection, &Tables) -> Result<T, RestError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let tables = self.tables.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut conn, &tables)
+        })
+        .await?
+    }
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+fn find<T: DeserializeOwned, P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<Vec<T>, RestError> {
+    log::debug!("Running find: {}", sql);
+    let mut statement = conn.prepare(sql)?;
+    let docs = statement.query_map(params, |row| row.get::<_, String>(0))?;
+    docs.map(|doc| Ok(serde_json::from_str(&doc?)?)).collect()
+}
+
+fn find_one<T: DeserializeOwned, P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+) -> Result<T, RestError> {
+    log::debug!("Running find_one: {}", sql);
+    let doc: String = conn.query_row(sql, params, |row| row.get(0))?;
+    Ok(serde_json::from_str(&doc)?)
+}
+
+// Load the document a query matches, change it and write it back in one transaction, returning
+// the document as it was before the change
+fn modify<T, P, F, S>(
+    conn: &mut Connection,
+    sql: &str,
+    params: P,
+    change: F,
+    save: S,
+) -> Result<T, RestError>
+where
+    T: DeserializeOwned + Clone,
+    P: Params,
+    F: FnOnce(&mut T) -> Result<(), RestError>,
+    S: FnOnce(&Connection, &T) -> Result<(), RestError>,
+{
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let before: T = find_one(&tx, sql, params)?;
+    let mut doc = before.clone();
+    change(&mut doc)?;
+    save(&tx, &doc)?;
+    tx.commit()?;
+    Ok(before)
+}
+
+// Fails with NotFound when a delete or update didn't match anything
+fn changed(rows: usize) -> Result<(), RestError> {
+    match rows {
+        0 => Err(RestError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+fn save_upload(conn: &Connection, t: &Tables, upload: &MetaData) -> Result<(), RestError> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (id, active, owner, expires, created, pending, deactivated, doc)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT (id) DO UPDATE SET active = excluded.active, owner = excluded.owner,
+                expires = excluded.expires, pending = excluded.pending,
+                deactivated = excluded.deactivated, doc = excluded.doc",
+            t.uploads
+        ),
+        params![
+            upload.id,
+            upload.active,
+            upload.facts.owner,
+            upload.lifecycle.max.expires.timestamp_millis(),
+            upload.meta.created.timestamp_millis(),
+            upload
+                .facts
+                .direct
+                .as_ref()
+                .map(|d| d.complete_by.timestamp_millis()),
+            upload.lifecycle.deactivated.map(|d| d.timestamp_millis()),
+            serde_json::to_string(upload)?,
+        ],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM {} WHERE upload = ?1", t.links),
+        params![upload.id],
+    )?;
+    for link in upload.links.0.iter() {
+        conn.execute(
+            &format!("INSERT INTO {} (id, upload) VALUES (?1, ?2)", t.links),
+            params![link.id, upload.id],
+        )?;
+    }
+    Ok(())
+}
+
+fn save_user(conn: &Connection, t: &Tables, user: &User) -> Result<(), RestError> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (id, email, pwd, doc) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (id) DO UPDATE SET email = excluded.email, pwd = excluded.pwd,
+                doc = excluded.doc",
+            t.users
+        ),
+        params![user.id, user.email, user.pwd, serde_json::to_string(user)?],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM {} WHERE user = ?1", t.api_keys),
+        params![user.id],
+    )?;
+    for api_key in user.api_keys.iter() {
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (key, secret, user) VALUES (?1, ?2, ?3)",
+                t.api_keys
+            ),
+            params![api_key.key, api_key.secret, user.id],
+        )?;
+    }
+    Ok(())
+}
+
+fn save_session(conn: &Connection, t: &Tables, session: &UploadSession) -> Result<(), RestError> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (id, owner, status, expires, doc) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (id) DO UPDATE SET status = excluded.status, doc = excluded.doc",
+            t.resumable
+        ),
+        params![
+            session.id,
+            session.owner,
+            session.status,
+            session.expires.timestamp_millis(),
+            serde_json::to_string(session)?,
+        ],
+    )?;
+    Ok(())
+}
+
+fn save_blob(conn: &Connection, t: &Tables, blob: &Blob) -> Result<(), RestError> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (digest, refs, doc) VALUES (?1, ?2, ?3)
+            ON CONFLICT (digest) DO UPDATE SET refs = excluded.refs, doc = excluded.doc",
+            t.blobs
+        ),
+        params![blob.digest, blob.refs, serde_json::to_string(blob)?],
+    )?;
+    Ok(())
+}
+
+fn save_deletion(conn: &Connection, t: &Tables, deletion: &Deletion) -> Result<(), RestError> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (object, status, next_attempt, doc) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (object) DO UPDATE SET status = excluded.status,
+                next_attempt = excluded.next_attempt, doc = excluded.doc",
+            t.deletions
+        ),
+        params![
+            deletion.object,
+            deletion.status,
+            deletion.next_attempt.timestamp_millis(),
+            serde_json::to_string(deletion)?,
+        ],
+    )?;
+    Ok(())
+}
+
+// Inserts fail like they do in MongoDB, when a unique field is already taken
+fn insert_failed(e: RestError) -> RestError {
+    log::error!("Error inserting: {}", e);
+    RestError::BadInsert
+}
+
+#[async_trait]
+impl MetadataStore for SqliteStore {
+    // Indexes are created along with the tables
+    async fn create_indexes(&self) -> Result<(), RestError> {
+        Ok(())
+    }
+
+    //
+    // Uploads
+    //
+
+    async fn insert_upload(&self, upload: MetaData) -> Result<MetaData, RestError> {
+        self.call(move |conn, t| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let sql = format!("SELECT COUNT(*) FROM {} WHERE id = ?1", t.uploads);
+            let taken: i64 = tx.query_row(&sql, params![upload.id], |row| row.get(0))?;
+            if taken > 0 {
+                return Err(insert_failed(RestError::BadInsert));
+            }
+            save_upload(&tx, t, &upload).map_err(insert_failed)?;
+            tx.commit()?;
+            Ok(upload)
+        })
+        .await
+    }
+
+    async fn find_upload_by_link(&self, link_id: &str) -> Result<MetaData, RestError> {
+        let link_id = link_id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT u.doc FROM {} u JOIN {} l ON l.upload = u.id WHERE l.id = ?1 AND u.active = 1",
+                t.uploads, t.links
+            );
+            find_one(conn, &sql, params![link_id])
+        })
+        .await
+    }
+
+    async fn find_owned_upload(&self, owner: &str, id: &str) -> Result<MetaData, RestError> {
+        let (owner, id) = (owner.to_owned(), id.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner = ?2 AND active = 1",
+                t.uploads
+            );
+            find_one(conn, &sql, params![id, owner])
+        })
+        .await
+    }
+
+    async fn owned_uploads(
+        &self,
+        owner: &str,
+        tags: Option<Vec<String>>,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<MetaData>, RestError> {
+        // Pages continue after the seq of the last upload listed
+        let after = match after {
+            Some(a) => Some(
+                a.parse::<i64>()
+                    .map_err(|_| RestError::BadRequest(PAGE_INVALID_CURSOR))?,
+            ),
+            None => None,
+        };
+        let owner = owner.to_owned();
+        let tags = serde_json::to_string(&tags.unwrap_or_default())?;
+        self.call(move |conn, t| {
+            let filter = "owner = ?1 AND active = 1 AND expires > ?2
+                AND NOT EXISTS (
+                    SELECT 1 FROM json_each(?3) wanted WHERE wanted.value NOT IN
+                        (SELECT value FROM json_each(doc, '$.meta.tags'))
+                )";
+            let now = now();
+            let total = match after {
+                Some(_) => None,
+                None => {
+                    let sql = format!("SELECT COUNT(*) FROM {} WHERE {}", t.uploads, filter);
+                    let count: i64 =
+                        conn.query_row(&sql, params![owner, now, tags], |row| row.get(0))?;
+                    Some(count as u64)
+                }
+            };
+
+            // Fetch one more than asked for, to tell whether there is another page
+            let sql = format!(
+                "SELECT seq, doc FROM {} WHERE {} AND seq < ?4 ORDER BY seq DESC LIMIT ?5",
+                t.uploads, filter
+            );
+            log::debug!("Running find: {}", sql);
+            let mut statement = conn.prepare(&sql)?;
+            let mut rows = statement
+                .query_map(
+                    params![owner, now, tags, after.unwrap_or(i64::MAX), limit + 1],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            let more = rows.len() as i64 > limit;
+            rows.truncate(limit as usize);
+            let next = match (more, rows.last()) {
+                (true, Some((seq, _))) => Some(seq.to_string()),
+                _ => None,
+            };
+            let items = rows
+                .iter()
+                .map(|(_, doc)| serde_json::from_str(doc))
+                .collect::<Result<Vec<MetaData>, _>>()?;
+            Ok(Page { items, next, total })
+        })
+        .await
+    }
+
+    async fn active_uploads(&self) -> Result<Vec<MetaData>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE active = 1", t.uploads);
+            find(conn, &sql, [])
+        })
+        .await
+    }
+
+    async fn active_uploads_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError> {
+        let after = after.unwrap_or_default().to_owned();
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE active = 1 AND id > ?1 ORDER BY id LIMIT ?2",
+                t.uploads
+            );
+            find(conn, &sql, params![after, limit])
+        })
+        .await
+    }
+
+    // The ids are passed as one JSON array, which each query expands with json_each
+    async fn referenced_objects(&self, ids: &[String]) -> Result<HashSet<String>, RestError> {
+        let (ids, json) = (ids.to_vec(), serde_json::to_string(ids)?);
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE (active = 1 OR pending IS NOT NULL) AND (
+                    id IN (SELECT value FROM json_each(?1))
+                    OR json_extract(doc, '$.facts.object') IN (SELECT value FROM json_each(?1))
+                    OR json_extract(doc, '$.facts.direct.staging') IN (SELECT value FROM json_each(?1))
+                    OR EXISTS (SELECT 1 FROM json_each(doc, '$.facts.segments') s
+                        WHERE json_extract(s.value, '$.id') IN (SELECT value FROM json_each(?1))))",
+                t.uploads
+            );
+            let uploads: Vec<MetaData> = find(conn, &sql, params![json])?;
+            let mut referenced: HashSet<String> = uploads
+                .into_iter()
+                .flat_map(|u| {
+                    let staging = u.staging_id().map(|s| s.to_owned());
+                    u.object_ids().into_iter().chain(staging)
+                })
+                .collect();
+
+            let sql = format!(
+                "SELECT doc FROM {} WHERE refs > 0
+                AND json_extract(doc, '$.object') IN (SELECT value FROM json_each(?1))",
+                t.blobs
+            );
+            let blobs: Vec<Blob> = find(conn, &sql, params![json])?;
+            referenced.extend(blobs.into_iter().map(|b| b.object));
+
+            let sql = format!(
+                "SELECT doc FROM {} WHERE EXISTS (SELECT 1 FROM json_each(doc, '$.parts') p
+                    WHERE json_extract(p.value, '$.id') IN (SELECT value FROM json_each(?1)))",
+                t.resumable
+            );
+            let sessions: Vec<UploadSession> = find(conn, &sql, params![json])?;
+            referenced.extend(sessions.into_iter().flat_map(|s| s.parts).map(|p| p.id));
+
+            referenced.retain(|id| ids.contains(id));
+            Ok(referenced)
+        })
+        .await
+    }
+
+    async fn expired_uploads(&self, limit: i64) -> Result<Vec<MetaData>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE active = 1 AND expires < ?1 ORDER BY seq DESC LIMIT ?2",
+                t.uploads
+            );
+            find(conn, &sql, params![now(), limit])
+        })
+        .await
+    }
+
+    async fn eviction_candidates(
+        &self,
+        order: Eviction,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError> {
+        let column = match order {
+            Eviction::Expiring => "expires",
+            Eviction::Oldest => "created",
+        };
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE active = 1 ORDER BY {} LIMIT ?1",
+                t.uploads, column
+            );
+            find(conn, &sql, params![limit])
+        })
+        .await
+    }
+
+    async fn deactivate_upload(&self, id: &str) -> Result<MetaData, RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE id = ?1 AND active = 1", t.uploads);
+            modify(
+                conn,
+                &sql,
+                params![id],
+                |upload: &mut MetaData| {
+                    upload.active = false;
+                    upload.lifecycle.deactivated = Some(bson::DateTime::now());
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )
+        })
+        .await
+    }
+
+    async fn record_read(&self, id: &str, link_id: &str) -> Result<MetaData, RestError> {
+        let (id, link_id) = (id.to_owned(), link_id.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE id = ?1 AND active = 1", t.uploads);
+            modify(
+                conn,
+                &sql,
+                params![id],
+                |upload: &mut MetaData| {
+                    let link = upload.links.0.iter_mut().find(|l| l.id == link_id);
+                    link.ok_or(RestError::NotFound)?.reads += 1;
+                    upload.lifecycle.current.reads += 1;
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )
+        })
+        .await
+    }
+
+    async fn reserve_read_bytes(&self, id: &str, bytes: i64, limit: i64) -> Result<(), RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE id = ?1 AND active = 1", t.uploads);
+            modify(
+                conn,
+                &sql,
+                params![id],
+                |upload: &mut MetaData| {
+                    if upload.lifecycle.current.bytes > limit - bytes {
+                        return Err(RestError::NotFound);
+                    }
+                    upload.lifecycle.current.bytes += bytes;
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn refund_read_bytes(&self, id: &str, bytes: i64) -> Result<(), RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE id = ?1", t.uploads);
+            modify(
+                conn,
+                &sql,
+                params![id],
+                |upload: &mut MetaData| {
+                    upload.lifecycle.current.bytes -= bytes;
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn init_budget(&self, used: usize) -> Result<(), RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "INSERT OR IGNORE INTO {} (name, used) VALUES ('budget', ?1)",
+                t.budget
+            );
+            conn.execute(&sql, params![used as i64])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn budget_used(&self) -> Result<usize, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!("SELECT used FROM {} WHERE name = 'budget'", t.budget);
+            let used: i64 = conn.query_row(&sql, [], |row| row.get(0))?;
+            Ok(used.max(0) as usize)
+        })
+        .await
+    }
+
+    // The check and the increment are one statement, so concurrent reservations can't both fit
+    async fn reserve_storage(&self, bytes: i64, limit: i64) -> Result<(), RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "UPDATE {} SET used = used + ?1 WHERE name = 'budget' AND (?2 = 0 OR used + ?1 <= ?2)",
+                t.budget
+            );
+            changed(conn.execute(&sql, params![bytes, limit])?)
+        })
+        .await
+    }
+
+    async fn release_storage(&self, bytes: i64) -> Result<(), RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "UPDATE {} SET used = used - ?1 WHERE name = 'budget'",
+                t.budget
+            );
+            changed(conn.execute(&sql, params![bytes])?)
+        })
+        .await
+    }
+
+    async fn stored_bytes(&self) -> Result<usize, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT COALESCE(SUM(json_extract(doc, '$.meta.bytes.stored')), 0) FROM {} WHERE active = 1",
+                t.uploads
+            );
+            let used: i64 = conn.query_row(&sql, [], |row| row.get(0))?;
+            Ok(used as usize)
+        })
+        .await
+    }
+
+    async fn add_tags(&self, owner: &str, id: &str, tags: &[String]) -> Result<(), RestError> {
+        let (owner, id, tags) = (owner.to_owned(), id.to_owned(), tags.to_vec());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner = ?2 AND active = 1",
+                t.uploads
+            );
+            modify(
+                conn,
+                &sql,
+                params![id, owner],
+                |upload: &mut MetaData| {
+                    let current = upload.meta.tags.get_or_insert_with(Vec::new);
+                    for tag in tags {
+                        if !current.contains(&tag) {
+                            current.push(tag);
+                        }
+                    }
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_tags(&self, owner: &str, id: &str, tags: &[String]) -> Result<(), RestError> {
+        let (owner, id, tags) = (owner.to_owned(), id.to_owned(), tags.to_vec());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner = ?2 AND active = 1",
+                t.uploads
+            );
+            modify(
+                conn,
+                &sql,
+                params![id, owner],
+                |upload: &mut MetaData| {
+                    if let Some(current) = upload.meta.tags.as_mut() {
+                        current.retain(|tag| !tags.contains(tag));
+                    }
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    //
+    // Links
+    //
+
+    async fn push_link(&self, owner: &str, id: &str, link: &Link) -> Result<MetaData, RestError> {
+        let (owner, id, link) = (owner.to_owned(), id.to_owned(), link.clone());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner = ?2 AND active = 1",
+                t.uploads
+            );
+            modify(
+                conn,
+                &sql,
+                params![id, owner],
+                |upload: &mut MetaData| {
+                    upload.links.0.push(link);
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )
+        })
+        .await
+    }
+
+    async fn pull_link(&self, owner: &str, id: &str, link_id: &str) -> Result<(), RestError> {
+        let (owner, id, link_id) = (owner.to_owned(), id.to_owned(), link_id.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner = ?2 AND active = 1",
+                t.uploads
+            );
+            modify(
+                conn,
+                &sql,
+                params![id, owner],
+                |upload: &mut MetaData| {
+                    if !upload.links.0.iter().any(|l| l.id == link_id) {
+                        return Err(RestError::NotFound);
+                    }
+                    upload.links.0.retain(|l| l.id != link_id);
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    //
+    // Direct uploads
+    //
+
+    async fn claim_pending_upload(
+        &self,
+        owner: Option<&str>,
+        id: &str,
+    ) -> Result<MetaData, RestError> {
+        let (owner, id) = (owner.map(|o| o.to_owned()), id.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner IS ?2 AND active = 0 AND pending > ?3
+                AND json_extract(doc, '$.facts.direct.completing') IS NULL",
+                t.uploads
+            );
+            let mut upload: MetaData = modify(
+                conn,
+                &sql,
+                params![id, owner, now()],
+                |upload: &mut MetaData| {
+                    if let Some(direct) = upload.facts.direct.as_mut() {
+                        direct.completing = Some(true);
+                    }
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            if let Some(direct) = upload.facts.direct.as_mut() {
+                direct.completing = Some(true);
+            }
+            Ok(upload)
+        })
+        .await
+    }
+
+    async fn release_pending_upload(&self, id: &str) -> Result<(), RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND active = 0
+                AND json_extract(doc, '$.facts.direct.completing') IS NOT NULL",
+                t.uploads
+            );
+            modify(
+                conn,
+                &sql,
+                params![id],
+                |upload: &mut MetaData| {
+                    if let Some(direct) = upload.facts.direct.as_mut() {
+                        direct.completing = None;
+                    }
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn activate_pending_upload(&self, id: &str) -> Result<MetaData, RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND active = 0 AND pending IS NOT NULL",
+                t.uploads
+            );
+            modify(
+                conn,
+                &sql,
+                params![id],
+                |upload: &mut MetaData| {
+                    upload.active = true;
+                    upload.facts.direct = None;
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )
+        })
+        .await
+    }
+
+    async fn drop_pending_upload(&self, id: &str) -> Result<(), RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND active = 0 AND pending IS NOT NULL",
+                t.uploads
+            );
+            modify(
+                conn,
+                &sql,
+                params![id],
+                |upload: &mut MetaData| {
+                    upload.facts.direct = None;
+                    upload.lifecycle.deactivated = Some(bson::DateTime::now());
+                    Ok(())
+                },
+                |conn, upload| save_upload(conn, t, upload),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn pending_uploads(&self) -> Result<Vec<MetaData>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE active = 0 AND pending IS NOT NULL",
+                t.uploads
+            );
+            find(conn, &sql, [])
+        })
+        .await
+    }
+
+    async fn expired_pending_uploads(&self, limit: i64) -> Result<Vec<MetaData>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE active = 0 AND pending < ?1 LIMIT ?2",
+                t.uploads
+            );
+            find(conn, &sql, params![now(), limit])
+        })
+        .await
+    }
+
+    //
+    // Users and API keys
+    //
+
+    async fn insert_user(&self, user: User) -> Result<User, RestError> {
+        self.call(move |conn, t| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let sql = format!("SELECT COUNT(*) FROM {} WHERE id = ?1", t.users);
+            let taken: i64 = tx.query_row(&sql, params![user.id], |row| row.get(0))?;
+            if taken > 0 {
+                return Err(insert_failed(RestError::UserExists));
+            }
+            save_user(&tx, t, &user).map_err(insert_failed)?;
+            tx.commit()?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn find_user_by_email(&self, email: &str) -> Result<User, RestError> {
+        let email = email.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE email = ?1", t.users);
+            find_one(conn, &sql, params![email])
+        })
+        .await
+    }
+
+    async fn find_user_by_login(&self, email: &str, pwd: &str) -> Result<User, RestError> {
+        let (email, pwd) = (email.to_owned(), pwd.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE email = ?1 AND pwd = ?2", t.users);
+            find_one(conn, &sql, params![email, pwd])
+        })
+        .await
+    }
+
+    async fn find_user(&self, id: &str) -> Result<User, RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE id = ?1", t.users);
+            find_one(conn, &sql, params![id])
+        })
+        .await
+    }
+
+    async fn find_user_by_credentials(&self, id: &str, pwd: &str) -> Result<User, RestError> {
+        let (id, pwd) = (id.to_owned(), pwd.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {users} WHERE (id = ?1 AND pwd = ?2)
+                OR id = (SELECT user FROM {api_keys} WHERE key = ?1 AND secret = ?2)",
+                users = t.users,
+                api_keys = t.api_keys
+            );
+            find_one(conn, &sql, params![id, pwd])
+        })
+        .await
+    }
+
+    async fn push_api_key(&self, user_id: &str, api_key: &ApiKeyHashed) -> Result<(), RestError> {
+        let (user_id, api_key) = (user_id.to_owned(), api_key.clone());
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE id = ?1", t.users);
+            modify(
+                conn,
+                &sql,
+                params![user_id],
+                |user: &mut User| {
+                    user.api_keys.push(api_key);
+                    Ok(())
+                },
+                |conn, user| save_user(conn, t, user),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn pull_api_key(&self, user_id: &str, key: &str) -> Result<(), RestError> {
+        let (user_id, key) = (user_id.to_owned(), key.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE id = ?1", t.users);
+            modify(
+                conn,
+                &sql,
+                params![user_id],
+                |user: &mut User| {
+                    if !user.api_keys.iter().any(|k| k.key == key) {
+                        return Err(RestError::NotFound);
+                    }
+                    user.api_keys.retain(|k| k.key != key);
+                    Ok(())
+                },
+                |conn, user| save_user(conn, t, user),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    //
+    // Locks
+    //
+
+    async fn init_locks(&self, stale: DateTime<Utc>) -> Result<(), RestError> {
+        self.call(move |conn, t| {
+            // The reconcile lock is backdated so the first scheduled run isn't held up
+            let sql = format!(
+                "INSERT OR IGNORE INTO {} (name, active, modified) VALUES (?1, 0, ?2)",
+                t.admin
+            );
+            let backdated = (Utc::now() - Duration::days(1)).timestamp_millis();
+            conn.execute(&sql, params!["reconcile", backdated])?;
+            conn.execute(&sql, params!["cleanup", now()])?;
+
+            // Free a cleanup lock left behind by an instance that died while holding it
+            let sql = format!(
+                "UPDATE {} SET active = 0, modified = ?1 WHERE name = 'cleanup' AND active = 1 AND modified < ?2",
+                t.admin
+            );
+            if conn.execute(&sql, params![now(), stale.timestamp_millis()])? == 0 {
+                log::debug!("Cleanup doc already is correct");
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn lock_cleanup(&self, since: DateTime<Utc>) -> Result<(), RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "UPDATE {} SET active = 1, modified = ?1 WHERE name = 'cleanup' AND active = 0 AND modified < ?2",
+                t.admin
+            );
+            changed(conn.execute(&sql, params![now(), since.timestamp_millis()])?)
+        })
+        .await
+    }
+
+    async fn unlock_cleanup(&self) -> Result<(), RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "UPDATE {} SET active = 0 WHERE name = 'cleanup' AND active = 1",
+                t.admin
+            );
+            changed(conn.execute(&sql, [])?)
+        })
+        .await
+    }
+
+    async fn lock_reconcile(&self, since: DateTime<Utc>) -> Result<(), RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "UPDATE {} SET modified = ?1 WHERE name = 'reconcile' AND modified < ?2",
+                t.admin
+            );
+            changed(conn.execute(&sql, params![now(), since.timestamp_millis()])?)
+        })
+        .await
+    }
+
+    async fn lock_expiry(&self, _owner: &str, _since: DateTime<Utc>) -> Result<(), RestError> {
+        Err(RestError::BadRequest(TTL_UNSUPPORTED))
+    }
+
+    //
+    // Shared objects
+    //
+
+    async fn share_blob(&self, digest: &str, object: &str) -> Result<Blob, RestError> {
+        let (digest, object) = (digest.to_owned(), object.to_owned());
+        self.call(move |conn, t| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let sql = format!("SELECT doc FROM {} WHERE digest = ?1", t.blobs);
+            let blob = match find_one::<Blob, _>(&tx, &sql, params![digest]) {
+                // A blob whose last reference is being released can't take new ones
+                Ok(b) if b.refs <= 0 => return Err(RestError::BadInsert),
+                Ok(b) => Blob {
+                    refs: b.refs + 1,
+                    ..b
+                },
+                Err(RestError::NotFound) => Blob {
+                    digest,
+                    object,
+                    refs: 1,
+                    released: Vec::new(),
+                    created: Utc::now(),
+                },
+                Err(e) => return Err(e),
+            };
+            save_blob(&tx, t, &blob)?;
+            tx.commit()?;
+            Ok(blob)
+        })
+        .await
+    }
+
+    async fn release_blob(&self, digest: &str, upload: &str) -> Result<Blob, RestError> {
+        let (digest, upload) = (digest.to_owned(), upload.to_owned());
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE digest = ?1 AND refs > 0", t.blobs);
+            let mut blob: Blob = modify(
+                conn,
+                &sql,
+                params![digest],
+                |blob: &mut Blob| {
+                    if blob.released.contains(&upload) {
+                        return Err(RestError::NotFound);
+                    }
+                    blob.refs -= 1;
+                    blob.released.push(upload.clone());
+                    Ok(())
+                },
+                |conn, blob| save_blob(conn, t, blob),
+            )?;
+            blob.refs -= 1;
+            blob.released.push(upload);
+            Ok(blob)
+        })
+        .await
+    }
+
+    async fn delete_blob(&self, digest: &str) -> Result<(), RestError> {
+        let digest = digest.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("DELETE FROM {} WHERE digest = ?1 AND refs <= 0", t.blobs);
+            changed(conn.execute(&sql, params![digest])?)
+        })
+        .await
+    }
+
+    //
+    // Resumable upload sessions
+    //
+
+    async fn insert_session(&self, session: UploadSession) -> Result<UploadSession, RestError> {
+        self.call(move |conn, t| {
+            save_session(conn, t, &session).map_err(insert_failed)?;
+            Ok(session)
+        })
+        .await
+    }
+
+    async fn find_open_session(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<UploadSession, RestError> {
+        let (id, owner) = (id.to_owned(), owner.map(|o| o.to_owned()));
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner IS ?2 AND status = ?3 AND expires > ?4",
+                t.resumable
+            );
+            find_one(conn, &sql, params![id, owner, SESSION_OPEN, now()])
+        })
+        .await
+    }
+
+    async fn append_session_part(
+        &self,
+        id: &str,
+        offset: i64,
+        part: &UploadPart,
+    ) -> Result<(), RestError> {
+        let (id, part) = (id.to_owned(), part.clone());
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND status = ?2",
+                t.resumable
+            );
+            modify(
+                conn,
+                &sql,
+                params![id, SESSION_OPEN],
+                |session: &mut UploadSession| {
+                    if session.offset != offset {
+                        return Err(RestError::NotFound);
+                    }
+                    session.offset += part.bytes;
+                    session.parts.push(part);
+                    Ok(())
+                },
+                |conn, session| save_session(conn, t, session),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn claim_session(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<UploadSession, RestError> {
+        let (id, owner) = (id.to_owned(), owner.map(|o| o.to_owned()));
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND owner IS ?2 AND status = ?3 AND expires > ?4",
+                t.resumable
+            );
+            modify(
+                conn,
+                &sql,
+                params![id, owner, SESSION_OPEN, now()],
+                |session: &mut UploadSession| {
+                    session.status = SESSION_COMPLETING.to_owned();
+                    Ok(())
+                },
+                |conn, session| save_session(conn, t, session),
+            )
+        })
+        .await
+    }
+
+    async fn reopen_session(&self, id: &str) -> Result<(), RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE id = ?1 AND status = ?2",
+                t.resumable
+            );
+            modify(
+                conn,
+                &sql,
+                params![id, SESSION_COMPLETING],
+                |session: &mut UploadSession| {
+                    session.status = SESSION_OPEN.to_owned();
+                    Ok(())
+                },
+                |conn, session| save_session(conn, t, session),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_session(&self, id: &str) -> Result<(), RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("DELETE FROM {} WHERE id = ?1", t.resumable);
+            changed(conn.execute(&sql, params![id])?)
+        })
+        .await
+    }
+
+    async fn sessions(&self) -> Result<Vec<UploadSession>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {}", t.resumable);
+            find(conn, &sql, [])
+        })
+        .await
+    }
+
+    async fn expired_sessions(&self, limit: i64) -> Result<Vec<UploadSession>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE expires < ?1 LIMIT ?2",
+                t.resumable
+            );
+            find(conn, &sql, params![now(), limit])
+        })
+        .await
+    }
+
+    //
+    // Deletion queue
+    //
+
+    async fn queue_deletion(&self, object: &str, error: &str) -> Result<(), RestError> {
+        let (object, error) = (object.to_owned(), error.to_owned());
+        self.call(move |conn, t| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let sql = format!("SELECT doc FROM {} WHERE object = ?1", t.deletions);
+            let deletion = match find_one::<Deletion, _>(&tx, &sql, params![object]) {
+                Ok(d) => Deletion {
+                    last_error: error,
+                    ..d
+                },
+                Err(RestError::NotFound) => Deletion {
+                    object,
+                    status: DELETION_PENDING.to_owned(),
+                    attempts: 0,
+                    created: Utc::now().into(),
+                    next_attempt: (Utc::now() + Deletion::backoff(0)).into(),
+                    last_error: error,
+                },
+                Err(e) => return Err(e),
+            };
+            save_deletion(&tx, t, &deletion)?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn due_deletions(&self, limit: i64) -> Result<Vec<Deletion>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE next_attempt <= ?1 ORDER BY next_attempt LIMIT ?2",
+                t.deletions
+            );
+            find(conn, &sql, params![now(), limit])
+        })
+        .await
+    }
+
+    async fn reschedule_deletion(&self, deletion: &Deletion) -> Result<(), RestError> {
+        let deletion = deletion.clone();
+        self.call(move |conn, t| {
+            let sql = format!("SELECT doc FROM {} WHERE object = ?1", t.deletions);
+            modify(
+                conn,
+                &sql,
+                params![deletion.object],
+                |queued: &mut Deletion| {
+                    queued.status = deletion.status.clone();
+                    queued.attempts = deletion.attempts;
+                    queued.next_attempt = deletion.next_attempt;
+                    queued.last_error = deletion.last_error.clone();
+                    Ok(())
+                },
+                |conn, queued| save_deletion(conn, t, queued),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_deletion(&self, object: &str) -> Result<(), RestError> {
+        let object = object.to_owned();
+        self.call(move |conn, t| {
+            let sql = format!("DELETE FROM {} WHERE object = ?1", t.deletions);
+            changed(conn.execute(&sql, params![object])?)
+        })
+        .await
+    }
+
+    async fn deletion_counts(&self) -> Result<(usize, usize), RestError> {
+        self.call(move |conn, t| {
+            let sql = format!("SELECT COUNT(*) FROM {} WHERE status = ?1", t.deletions);
+            let count = |status: &str| -> Result<usize, RestError> {
+                let count: i64 = conn.query_row(&sql, params![status], |row| row.get(0))?;
+                Ok(count as usize)
+            };
+            Ok((count(DELETION_PENDING)?, count(DELETION_DEAD)?))
+        })
+        .await
+    }
+
+    //
+    // Purging
+    //
+
+    async fn purgeable_uploads(
+        &self,
+        cutoff: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError> {
+        self.call(move |conn, t| {
+            let sql = format!(
+                "SELECT doc FROM {} WHERE active = 0 AND purged IS NULL AND pending IS NULL
+                AND COALESCE(deactivated, expires) < ?1 LIMIT ?2",
+                t.uploads
+            );
+            find(conn, &sql, params![cutoff.timestamp_millis(), limit])
+        })
+        .await
+    }
+
+    async fn delete_upload(&self, id: &str) -> Result<(), RestError> {
+        let id = id.to_owned();
+        self.call(move |conn, t| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let sql = format!(
+                "DELETE FROM {} WHERE id = ?1 AND active = 0 AND purged IS NULL AND pending IS NULL",
+                t.uploads
+            );
+            changed(tx.execute(&sql, params![id])?)?;
+            tx.execute(
+                &format!("DELETE FROM {} WHERE upload = ?1", t.links),
+                params![id],
+            )?;
+            Ok(tx.commit()?)
+        })
+        .await
+    }
+
+    async fn tombstone_upload(&self, tombstone: &Tombstone) -> Result<(), RestError> {
+        let tombstone = tombstone.clone();
+        self.call(move |conn, t| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let sql = format!(
+                "UPDATE {} SET purged = ?2, doc = ?3
+                WHERE id = ?1 AND active = 0 AND purged IS NULL AND pending IS NULL",
+                t.uploads
+            );
+            changed(tx.execute(
+                &sql,
+                params![
+                    tombstone.id,
+                    tombstone.purged.timestamp_millis(),
+                    serde_json::to_string(&tombstone)?,
+                ],
+            )?)?;
+            tx.execute(
+                &format!("DELETE FROM {} WHERE upload = ?1", t.links),
+                params![tombstone.id],
+            )?;
+            Ok(tx.commit()?)
+        })
+        .await
+    }
+
+    //
+    // TTL expiry
+    //
+
+    async fn set_ttl_expiry(&self, enabled: bool) -> Result<(), RestError> {
+        match enabled {
+            true => Err(RestError::BadRequest(TTL_UNSUPPORTED)),
+            false => Ok(()),
+        }
+    }
+
+    async fn watch_expired(&self, _owner: &str) -> Result<ExpiredUploads, RestError> {
+        Err(RestError::BadRequest(TTL_UNSUPPORTED))
+    }
+
+    async fn save_expiry_position(&self, _owner: &str, _position: &str) -> Result<(), RestError> {
+        Err(RestError::BadRequest(TTL_UNSUPPORTED))
+    }
+
+    //
+    // Storage migrations
+    //
+
+    async fn migrated_objects(
+        &self,
+        collection: &str,
+        target: &str,
+    ) -> Result<Vec<Migration>, RestError> {
+        let (table, target) = (quote(collection), target.to_owned());
+        self.call(move |conn, _| {
+            conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    object TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    doc TEXT NOT NULL,
+                    PRIMARY KEY (object, target)
+                )",
+                table
+            ))?;
+            let sql = format!("SELECT doc FROM {} WHERE target = ?1", table);
+            find(conn, &sql, params![target])
+        })
+        .await
+    }
+
+    async fn record_migration(
+        &self,
+        collection: &str,
+        migration: &Migration,
+    ) -> Result<(), RestError> {
+        let (table, migration) = (quote(collection), migration.clone());
+        self.call(move |conn, _| {
+            let sql = format!(
+                "INSERT OR REPLACE INTO {} (object, target, doc) VALUES (?1, ?2, ?3)",
+                table
+            );
+            conn.execute(
+                &sql,
+                params![
+                    migration.object,
+                    migration.target,
+                    serde_json::to_string(&migration)?
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::links::Links;
+    use crate::database::metadata::{
+        Direct, Encryption, Facts, Lifecycle, LifecycleCurrent, LifecycleMax, Meta, Size, Tombstone,
+    };
+    use crate::database::resumable::SessionEncryption;
+    use crate::database::users::Access;
+
+    async fn store() -> SqliteStore {
+        let collections = Collections {
+            uploads: "uploads".to_owned(),
+            admin: "admin".to_owned(),
+            users: "users".to_owned(),
+            resumable: "resumable".to_owned(),
+            blobs: "blobs".to_owned(),
+            deletions: "deletions".to_owned(),
+        };
+        SqliteStore::open(":memory:", collections).await.unwrap()
+    }
+
+    fn upload(id: &str, owner: Option<&str>, seconds: i64) -> MetaData {
+        MetaData {
+            id: id.to_owned(),
+            active: true,
+            meta: Meta {
+                created: Utc::now(),
+                content_type: "text/plain".to_owned(),
+                expires: None,
+                user_agent: None,
+                x_forwarded_for: None,
+                bytes: Size {
+                    original: 10,
+                    stored: 10,
+                },
+                filename: None,
+                tags: Some(vec![format!("tag-{}", id)]),
+            },
+            lifecycle: Lifecycle {
+                max: LifecycleMax {
+                    reads: 5,
+                    seconds,
+                    expires: (Utc::now() + Duration::seconds(seconds)).into(),
+                },
+                current: LifecycleCurrent { reads: 0, bytes: 0 },
+                deactivated: None,
+            },
+            facts: Facts {
+                owner: owner.map(|o| o.to_owned()),
+                pwd: None,
+                encryption: Encryption {
+                    encrypted: false,
+                    managed: false,
+                    key: None,
+                    version: None,
+                    chunk_size: None,
+                },
+                compression: None,
+                ignore_link_key: false,
+                digest: None,
+                object: None,
+                object_key_ver: None,
+                direct: None,
+                segments: None,
+            },
+            links: Links(vec![Link {
+                id: format!("link-{}", id),
+                key: None,
+                created: Utc::now(),
+                reads: 0,
+                tags: None,
+            }]),
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_uploads_by_owner_and_link() {
+        let store = store().await;
+        store
+            .insert_upload(upload("a", Some("alice"), 60))
+            .await
+            .unwrap();
+
+        assert_eq!(store.find_owned_upload("alice", "a").await.unwrap().id, "a");
+        assert_eq!(store.find_upload_by_link("link-a").await.unwrap().id, "a");
+        assert!(matches!(
+            store.find_owned_upload("bob", "a").await,
+            Err(RestError::NotFound)
+        ));
+        assert!(matches!(
+            store.find_upload_by_link("link-b").await,
+            Err(RestError::NotFound)
+        ));
+
+        // Ids are unique
+        assert!(matches!(
+            store.insert_upload(upload("a", None, 60)).await,
+            Err(RestError::BadInsert)
+        ));
+    }
+
+    #[tokio::test]
+    async fn records_reads_and_read_bytes() {
+        let store = store().await;
+        store.insert_upload(upload("a", None, 60)).await.unwrap();
+
+        // Updates hand back the document as it was, like find_one_and_update in MongoDB
+        let read = store.record_read("a", "link-a").await.unwrap();
+        assert_eq!(read.lifecycle.current.reads, 0);
+        let read = store.find_upload_by_link("link-a").await.unwrap();
+        assert_eq!(read.lifecycle.current.reads, 1);
+        assert_eq!(read.links.0[0].reads, 1);
+        assert!(matches!(
+            store.record_read("a", "link-b").await,
+            Err(RestError::NotFound)
+        ));
+
+        store.reserve_read_bytes("a", 60, 100).await.unwrap();
+        assert!(matches!(
+            store.reserve_read_bytes("a", 50, 100).await,
+            Err(RestError::NotFound)
+        ));
+        store.refund_read_bytes("a", 20).await.unwrap();
+        store.reserve_read_bytes("a", 50, 100).await.unwrap();
+        let upload = store.find_upload_by_link("link-a").await.unwrap();
+        assert_eq!(upload.lifecycle.current.bytes, 90);
+    }
+
+    #[tokio::test]
+    async fn deactivated_and_expired_uploads_drop_out() {
+        let store = store().await;
+        store.insert_upload(upload("a", None, 60)).await.unwrap();
+        store.insert_upload(upload("b", None, 60)).await.unwrap();
+        store.insert_upload(upload("c", None, -60)).await.unwrap();
+        assert_eq!(store.stored_bytes().await.unwrap(), 30);
+
+        let expired = store.expired_uploads(10).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, "c");
+
+        assert_eq!(store.deactivate_upload("b").await.unwrap().id, "b");
+        assert!(matches!(
+            store.deactivate_upload("b").await,
+            Err(RestError::NotFound)
+        ));
+        assert!(matches!(
+            store.find_upload_by_link("link-b").await,
+            Err(RestError::NotFound)
+        ));
+
+        let mut active: Vec<String> = store
+            .active_uploads()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.id)
+            .collect();
+        active.sort();
+        assert_eq!(active, ["a", "c"]);
+        assert_eq!(store.stored_bytes().await.unwrap(), 20);
+    }
+
+    #[tokio::test]
+    async fn budget_reservations_stay_within_the_limit() {
+        let store = store().await;
+        store.init_budget(40).await.unwrap();
+        // An existing total is kept
+        store.init_budget(0).await.unwrap();
+        assert_eq!(store.budget_used().await.unwrap(), 40);
+
+        store.reserve_storage(60, 100).await.unwrap();
+        assert!(matches!(
+            store.reserve_storage(1, 100).await,
+            Err(RestError::NotFound)
+        ));
+        store.release_storage(30).await.unwrap();
+        store.reserve_storage(30, 100).await.unwrap();
+        assert_eq!(store.budget_used().await.unwrap(), 100);
+
+        // Without a limit the total is only counted
+        store.reserve_storage(50, 0).await.unwrap();
+        assert_eq!(store.budget_used().await.unwrap(), 150);
+
+        // Concurrent reservations can't overshoot together
+        store.release_storage(150).await.unwrap();
+        let reservations = (0..10).map(|_| store.reserve_storage(30, 100));
+        let reserved = futures::future::join_all(reservations)
+            .await
+            .into_iter()
+            .filter(|r| r.is_ok())
+            .count();
+        assert_eq!(reserved, 3);
+        assert_eq!(store.budget_used().await.unwrap(), 90);
+    }
+
+    #[tokio::test]
+    async fn pages_through_owned_uploads() {
+        let store = store().await;
+        for id in ["a", "b", "c", "d", "e"] {
+            store
+                .insert_upload(upload(id, Some("alice"), 60))
+                .await
+                .unwrap();
+        }
+        store
+            .insert_upload(upload("f", Some("bob"), 60))
+            .await
+            .unwrap();
+        store
+            .insert_upload(upload("g", Some("alice"), -60))
+            .await
+            .unwrap();
+
+        // Newest first, with the total only on the first page
+        let mut ids = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store
+                .owned_uploads("alice", None, after.as_deref(), 2)
+                .await
+                .unwrap();
+            assert_eq!(page.total, after.is_none().then_some(5));
+            ids.extend(page.items.into_iter().map(|u| u.id));
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(ids, ["e", "d", "c", "b", "a"]);
+
+        let page = store
+            .owned_uploads("alice", Some(vec!["tag-c".to_owned()]), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(page.total, Some(1));
+        assert_eq!(page.items[0].id, "c");
+
+        assert!(matches!(
+            store.owned_uploads("alice", None, Some("x"), 2).await,
+            Err(RestError::BadRequest(_))
+        ));
+    }
+
+    fn pending(id: &str, seconds: i64) -> MetaData {
+        let mut upload = upload(id, Some("alice"), 60);
+        upload.active = false;
+        upload.facts.direct = Some(Direct {
+            complete_by: (Utc::now() + Duration::seconds(seconds)).into(),
+            staging: format!("{}.staging", id),
+            completing: None,
+        });
+        upload
+    }
+
+    #[tokio::test]
+    async fn completes_direct_uploads_once() {
+        let store = store().await;
+        store.insert_upload(pending("a", 60)).await.unwrap();
+        store.insert_upload(pending("b", -60)).await.unwrap();
+        assert_eq!(store.pending_uploads().await.unwrap().len(), 2);
+
+        // Only the owner can claim it, and only one claim is held at a time
+        assert!(matches!(
+            store.claim_pending_upload(Some("bob"), "a").await,
+            Err(RestError::NotFound)
+        ));
+        let claimed = store
+            .claim_pending_upload(Some("alice"), "a")
+            .await
+            .unwrap();
+        assert_eq!(claimed.staging_id(), Some("a.staging"));
+        assert!(store
+            .claim_pending_upload(Some("alice"), "a")
+            .await
+            .is_err());
+        store.release_pending_upload("a").await.unwrap();
+        store
+            .claim_pending_upload(Some("alice"), "a")
+            .await
+            .unwrap();
+
+        // Past its deadline it can't be claimed, only abandoned
+        assert!(store
+            .claim_pending_upload(Some("alice"), "b")
+            .await
+            .is_err());
+        let expired = store.expired_pending_uploads(10).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].staging_id(), Some("b.staging"));
+
+        // The staging record goes once the upload is active or dropped
+        let activated = store.activate_pending_upload("a").await.unwrap();
+        assert_eq!(activated.staging_id(), Some("a.staging"));
+        let active = store.find_upload_by_link("link-a").await.unwrap();
+        assert!(active.facts.direct.is_none());
+        store.drop_pending_upload("b").await.unwrap();
+        assert!(store.pending_uploads().await.unwrap().is_empty());
+        assert!(store.activate_pending_upload("b").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn finds_referenced_objects_by_page() {
+        let store = store().await;
+        store.insert_upload(upload("a", None, 60)).await.unwrap();
+        store.insert_upload(upload("b", None, 60)).await.unwrap();
+        store.insert_upload(upload("c", None, 60)).await.unwrap();
+        store.insert_upload(pending("d", 60)).await.unwrap();
+        store.share_blob("digest", "shared").await.unwrap();
+        store.deactivate_upload("b").await.unwrap();
+
+        let page: Vec<String> = ["a", "b", "shared", "d.staging", "unknown"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        let mut referenced: Vec<String> = store
+            .referenced_objects(&page)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        referenced.sort();
+        assert_eq!(referenced, ["a", "d.staging", "shared"]);
+
+        // Active uploads are listed in id order, after the last one of the previous page
+        let first = store.active_uploads_after(None, 1).await.unwrap();
+        assert_eq!(first[0].id, "a");
+        let rest = store.active_uploads_after(Some("a"), 10).await.unwrap();
+        let rest: Vec<&str> = rest.iter().map(|u| u.id.as_str()).collect();
+        assert_eq!(rest, ["c"]);
+    }
+
+    fn deactivated(id: &str, seconds_ago: Option<i64>) -> MetaData {
+        let mut upload = upload(id, Some("alice"), 60);
+        upload.active = false;
+        upload.lifecycle.deactivated =
+            seconds_ago.map(|s| (Utc::now() - Duration::seconds(s)).into());
+        upload
+    }
+
+    #[tokio::test]
+    async fn purges_inactive_uploads() {
+        let store = store().await;
+        store
+            .insert_upload(upload("active", None, 60))
+            .await
+            .unwrap();
+        store
+            .insert_upload(deactivated("old", Some(120)))
+            .await
+            .unwrap();
+        store
+            .insert_upload(deactivated("recent", Some(10)))
+            .await
+            .unwrap();
+        // Uploads deactivated before it was recorded are purged by their expiry instead
+        let mut expired = upload("expired", None, -120);
+        expired.active = false;
+        store.insert_upload(expired).await.unwrap();
+        store.insert_upload(pending("pending", -120)).await.unwrap();
+
+        let cutoff = Utc::now() - Duration::seconds(60);
+        let mut purgeable: Vec<String> = store
+            .purgeable_uploads(cutoff, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.id)
+            .collect();
+        purgeable.sort();
+        assert_eq!(purgeable, ["expired", "old"]);
+
+        // A tombstone keeps only who uploaded it and when, and its links are gone
+        store
+            .tombstone_upload(&Tombstone::from(&deactivated("old", Some(120))))
+            .await
+            .unwrap();
+        let doc: serde_json::Value = store
+            .call(|conn, t| {
+                let sql = format!("SELECT doc FROM {} WHERE id = 'old'", t.uploads);
+                let doc: String = conn.query_row(&sql, [], |row| row.get(0))?;
+                Ok(serde_json::from_str(&doc)?)
+            })
+            .await
+            .unwrap();
+        let mut fields: Vec<&String> = doc.as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(
+            fields,
+            ["active", "created", "deactivated", "id", "owner", "purged"]
+        );
+        assert!(matches!(
+            store.find_upload_by_link("link-old").await,
+            Err(RestError::NotFound)
+        ));
+
+        // Each upload is purged once, and active or pending ones never are
+        store.delete_upload("expired").await.unwrap();
+        for id in ["old", "expired", "active", "pending"] {
+            assert!(store.delete_upload(id).await.is_err(), "{}", id);
+        }
+        assert!(store
+            .purgeable_uploads(cutoff, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn counts_references_to_shared_objects() {
+        let store = store().await;
+
+        // The first upload's object is the one shared
+        assert_eq!(store.share_blob("digest", "a").await.unwrap().refs, 1);
+        let blob = store.share_blob("digest", "b").await.unwrap();
+        assert_eq!((blob.object.as_str(), blob.refs), ("a", 2));
+
+        // Each upload drops its reference once, and the blob stays while one is left
+        assert_eq!(store.release_blob("digest", "a").await.unwrap().refs, 1);
+        assert!(matches!(
+            store.release_blob("digest", "a").await,
+            Err(RestError::NotFound)
+        ));
+        assert!(store.delete_blob("digest").await.is_err());
+        assert_eq!(store.release_blob("digest", "b").await.unwrap().refs, 0);
+
+        // Once released it takes no new references, and can be deleted and registered again
+        assert!(matches!(
+            store.share_blob("digest", "c").await,
+            Err(RestError::BadInsert)
+        ));
+        store.delete_blob("digest").await.unwrap();
+        assert_eq!(store.share_blob("digest", "c").await.unwrap().object, "c");
+    }
+
+    fn session(id: &str, seconds: i64) -> UploadSession {
+        UploadSession {
+            id: id.to_owned(),
+            owner: Some("alice".to_owned()),
+            status: SESSION_OPEN.to_owned(),
+            created: Utc::now(),
+            expires: (Utc::now() + Duration::seconds(seconds)).into(),
+            length: 100,
+            offset: 0,
+            parts: Vec::new(),
+            encryption: SessionEncryption {
+                key: None,
+                version: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn appends_session_parts_in_order() {
+        let store = store().await;
+        store.insert_session(session("s", 60)).await.unwrap();
+        store.insert_session(session("old", -60)).await.unwrap();
+        let part = |id: &str, bytes: i64| UploadPart {
+            id: id.to_owned(),
+            bytes,
+        };
+
+        // A part only lands at the offset the session is at
+        store
+            .append_session_part("s", 0, &part("p1", 40))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.append_session_part("s", 0, &part("p2", 40)).await,
+            Err(RestError::NotFound)
+        ));
+        store
+            .append_session_part("s", 40, &part("p2", 60))
+            .await
+            .unwrap();
+        let open = store.find_open_session("s", Some("alice")).await.unwrap();
+        assert_eq!(open.offset, 100);
+        let parts: Vec<&str> = open.parts.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(parts, ["p1", "p2"]);
+        assert!(store.find_open_session("s", Some("bob")).await.is_err());
+        assert!(store.find_open_session("s", None).await.is_err());
+
+        // Claimed sessions take no more parts and can't be claimed again until reopened
+        store.claim_session("s", Some("alice")).await.unwrap();
+        assert!(store
+            .append_session_part("s", 100, &part("p3", 1))
+            .await
+            .is_err());
+        assert!(store.claim_session("s", Some("alice")).await.is_err());
+        assert!(store.find_open_session("s", Some("alice")).await.is_err());
+        store.reopen_session("s").await.unwrap();
+        store.claim_session("s", Some("alice")).await.unwrap();
+
+        // Expired sessions can't be claimed, only cleaned up
+        assert!(store.claim_session("old", Some("alice")).await.is_err());
+        let expired = store.expired_sessions(10).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, "old");
+        store.delete_session("old").await.unwrap();
+        assert_eq!(store.sessions().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn finds_users_by_email_and_api_key() {
+        let store = store().await;
+        let user = store.insert_user(User::new("a@b.c", "pwd")).await.unwrap();
+        let email = User::hash("a@b.c");
+
+        assert_eq!(store.find_user_by_email(&email).await.unwrap().id, user.id);
+        assert_eq!(
+            store
+                .find_user_by_credentials(&user.id, &User::hash("pwd"))
+                .await
+                .unwrap()
+                .id,
+            user.id
+        );
+        assert!(matches!(
+            store.insert_user(User::new("a@b.c", "other")).await,
+            Err(RestError::BadInsert)
+        ));
+
+        let api_key = ApiKeyHashed {
+            key: "key".to_owned(),
+            secret: "secret".to_owned(),
+            created: Utc::now(),
+            access: Access {
+                role: "admin".to_owned(),
+            },
+            tags: None,
+        };
+        store.push_api_key(&user.id, &api_key).await.unwrap();
+        assert_eq!(
+            store
+                .find_user_by_credentials("key", "secret")
+                .await
+                .unwrap()
+                .id,
+            user.id
+        );
+
+        store.pull_api_key(&user.id, "key").await.unwrap();
+        assert!(matches!(
+            store.find_user_by_credentials("key", "secret").await,
+            Err(RestError::NotFound)
+        ));
+        assert!(matches!(
+            store.pull_api_key(&user.id, "key").await,
+            Err(RestError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn cleanup_lock_is_held_until_released() {
+        let store = store().await;
+        store.init_locks(Utc::now()).await.unwrap();
+
+        let since = Utc::now() + Duration::seconds(1);
+        store.lock_cleanup(since).await.unwrap();
+        assert!(store.lock_cleanup(since).await.is_err());
+        store.unlock_cleanup().await.unwrap();
+        assert!(store.unlock_cleanup().await.is_err());
+        store.lock_cleanup(since).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queues_and_reschedules_deletions() {
+        let store = store().await;
+        store.queue_deletion("obj", "first").await.unwrap();
+        store.queue_deletion("obj", "second").await.unwrap();
+        assert_eq!(store.deletion_counts().await.unwrap(), (1, 0));
+
+        // The first attempt waits out the initial backoff
+        assert!(store.due_deletions(10).await.unwrap().is_empty());
+
+        let deletion = Deletion {
+            object: "obj".to_owned(),
+            status: DELETION_DEAD.to_owned(),
+            attempts: 3,
+            created: Utc::now().into(),
+            next_attempt: (Utc::now() - Duration::seconds(1)).into(),
+            last_error: "third".to_owned(),
+        };
+        store.reschedule_deletion(&deletion).await.unwrap();
+        assert_eq!(store.deletion_counts().await.unwrap(), (0, 1));
+        let due = store.due_deletions(10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 3);
+        assert_eq!(due[0].last_error, "third");
+
+        store.remove_deletion("obj").await.unwrap();
+        assert!(matches!(
+            store.remove_deletion("obj").await,
+            Err(RestError::NotFound)
+        ));
+        assert_eq!(store.deletion_counts().await.unwrap(), (0, 0));
+    }
+}
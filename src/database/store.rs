@@ -0,0 +1,434 @@
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ArgMatches;
+use enum_dispatch::enum_dispatch;
+use futures::stream::BoxStream;
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::database::blobs::Blob;
+use crate::database::deletions::Deletion;
+use crate::database::links::Link;
+use crate::database::metadata::{MetaData, Tombstone};
+use crate::database::migrations::Migration;
+use crate::database::mongo_store::MongoStore;
+use crate::database::resumable::{UploadPart, UploadSession};
+use crate::database::sqlite::SqliteStore;
+use crate::database::users::{ApiKeyHashed, User};
+use crate::error::Error as RestError;
+
+// Database file used when metadata is kept in --data_dir
+const DATA_DIR_SQLITE: &str = "tackd.db";
+
+// An upload removed by the store as it expired, as it was before removal, and the position in the
+// removals to save once it has been handled
+#[derive(Clone, Debug)]
+pub struct ExpiredUpload {
+    pub upload: MetaData,
+    pub position: String,
+}
+
+pub type ExpiredUploads = BoxStream<'static, Result<ExpiredUpload, RestError>>;
+
+// Listings return at most this many items a page, and this many unless asked for fewer. Listings
+// were capped at 1000 before they could be paged through, so clients that don't page see the same.
+pub const PAGE_LIMIT_MAX: i64 = 1000;
+pub const PAGE_LIMIT_DEFAULT: i64 = 1000;
+pub const PAGE_INVALID_CURSOR: &str = "Invalid next cursor";
+
+// A page of a listing. next is the position of its last item when more may follow, and total
+// counts every item listed, where that is cheap to find.
+#[derive(Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+    pub total: Option<u64>,
+}
+
+impl<T> Page<T> {
+    // Page through items already in memory, ordered by creation and then key, continuing after
+    // the position of an earlier page
+    pub fn slice(
+        mut items: Vec<T>,
+        position: impl Fn(&T) -> (DateTime<Utc>, String),
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<T>, RestError> {
+        let total = items.len() as u64;
+        items.sort_by_key(|i| position(i));
+        if let Some(after) = after {
+            let (created, key) = after
+                .split_once(' ')
+                .ok_or(RestError::BadRequest(PAGE_INVALID_CURSOR))?;
+            let after = (
+                DateTime::parse_from_rfc3339(created)
+                    .map_err(|_| RestError::BadRequest(PAGE_INVALID_CURSOR))?
+                    .with_timezone(&Utc),
+                key.to_owned(),
+            );
+            items.retain(|i| position(i) > after);
+        }
+
+        let more = items.len() as i64 > limit;
+        items.truncate(limit as usize);
+        let next = match (more, items.last()) {
+            (true, Some(last)) => {
+                let (created, key) = position(last);
+                Some(format!(
+                    "{} {}",
+                    created.to_rfc3339_opts(SecondsFormat::Nanos, true),
+                    key
+                ))
+            }
+            _ => None,
+        };
+        Ok(Page {
+            items,
+            next,
+            total: Some(total),
+        })
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next: self.next,
+            total: self.total,
+        }
+    }
+}
+
+// Names of the collections, or tables, that metadata is kept in
+#[derive(Clone, Debug)]
+pub struct Collections {
+    pub uploads: String,
+    pub admin: String,
+    pub users: String,
+    pub resumable: String,
+    pub blobs: String,
+    pub deletions: String,
+}
+
+// Order uploads are evicted in when the storage budget runs out
+#[derive(Clone, Copy, Debug)]
+pub enum Eviction {
+    Expiring,
+    Oldest,
+}
+
+// Lookups that find nothing, and conditional updates whose condition doesn't hold, return NotFound.
+// Listings of active uploads are newest first, unless an order is given.
+#[async_trait]
+#[enum_dispatch(MetadataClient)]
+pub trait MetadataStore {
+    async fn create_indexes(&self) -> Result<(), RestError>;
+
+    //
+    // Uploads
+    //
+
+    async fn insert_upload(&self, upload: MetaData) -> Result<MetaData, RestError>;
+    async fn find_upload_by_link(&self, link_id: &str) -> Result<MetaData, RestError>;
+    async fn find_owned_upload(&self, owner: &str, id: &str) -> Result<MetaData, RestError>;
+    // Unexpired uploads of an owner, carrying every one of the tags, continuing after the position
+    // of an earlier page. Only the first page is counted.
+    async fn owned_uploads(
+        &self,
+        owner: &str,
+        tags: Option<Vec<String>>,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<MetaData>, RestError>;
+    async fn active_uploads(&self) -> Result<Vec<MetaData>, RestError>;
+    // Active uploads in id order, starting after the given id, to walk them a page at a time
+    async fn active_uploads_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError>;
+    // Those of the given storage ids that an active or pending upload, a shared object or an
+    // upload session refers to
+    async fn referenced_objects(&self, ids: &[String]) -> Result<HashSet<String>, RestError>;
+    async fn expired_uploads(&self, limit: i64) -> Result<Vec<MetaData>, RestError>;
+    async fn eviction_candidates(
+        &self,
+        order: Eviction,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError>;
+    // Returns the upload as it was before being deactivated, which records when it happened
+    async fn deactivate_upload(&self, id: &str) -> Result<MetaData, RestError>;
+    // Count a completed read of the upload through one of its links, returning the upload as it
+    // was before the read
+    async fn record_read(&self, id: &str, link_id: &str) -> Result<MetaData, RestError>;
+    // Reserve bytes for a download, unless the bytes already sent or reserved would exceed limit
+    async fn reserve_read_bytes(&self, id: &str, bytes: i64, limit: i64) -> Result<(), RestError>;
+    async fn refund_read_bytes(&self, id: &str, bytes: i64) -> Result<(), RestError>;
+    // Bytes held in storage by active uploads, counted from the uploads themselves
+    async fn stored_bytes(&self) -> Result<usize, RestError>;
+    // Create the running total of bytes counted against the storage budget, unless it exists
+    async fn init_budget(&self, used: usize) -> Result<(), RestError>;
+    async fn budget_used(&self) -> Result<usize, RestError>;
+    // Add bytes to the running total in one conditional update, unless it would exceed a non-zero
+    // limit
+    async fn reserve_storage(&self, bytes: i64, limit: i64) -> Result<(), RestError>;
+    async fn release_storage(&self, bytes: i64) -> Result<(), RestError>;
+    async fn add_tags(&self, owner: &str, id: &str, tags: &[String]) -> Result<(), RestError>;
+    async fn remove_tags(&self, owner: &str, id: &str, tags: &[String]) -> Result<(), RestError>;
+
+    //
+    // Links
+    //
+
+    // Returns the upload as it was before the link was added
+    async fn push_link(&self, owner: &str, id: &str, link: &Link) -> Result<MetaData, RestError>;
+    async fn pull_link(&self, owner: &str, id: &str, link_id: &str) -> Result<(), RestError>;
+
+    //
+    // Direct uploads, inactive uploads waiting for their object to be written
+    //
+
+    // Mark an unexpired direct upload as completing, unless it already is
+    async fn claim_pending_upload(
+        &self,
+        owner: Option<&str>,
+        id: &str,
+    ) -> Result<MetaData, RestError>;
+    async fn release_pending_upload(&self, id: &str) -> Result<(), RestError>;
+    async fn activate_pending_upload(&self, id: &str) -> Result<MetaData, RestError>;
+    async fn drop_pending_upload(&self, id: &str) -> Result<(), RestError>;
+    async fn pending_uploads(&self) -> Result<Vec<MetaData>, RestError>;
+    async fn expired_pending_uploads(&self, limit: i64) -> Result<Vec<MetaData>, RestError>;
+
+    //
+    // Users and API keys, emails and passwords are passed in hashed
+    //
+
+    async fn insert_user(&self, user: User) -> Result<User, RestError>;
+    async fn find_user_by_email(&self, email: &str) -> Result<User, RestError>;
+    async fn find_user_by_login(&self, email: &str, pwd: &str) -> Result<User, RestError>;
+    async fn find_user(&self, id: &str) -> Result<User, RestError>;
+    // Match either a user id and password, or an API key and secret
+    async fn find_user_by_credentials(&self, id: &str, pwd: &str) -> Result<User, RestError>;
+    async fn push_api_key(&self, user_id: &str, api_key: &ApiKeyHashed) -> Result<(), RestError>;
+    async fn pull_api_key(&self, user_id: &str, key: &str) -> Result<(), RestError>;
+
+    //
+    // Locks, which keep cleanup and reconciliation to one instance at a time
+    //
+
+    // Create missing locks, and free a cleanup lock taken before stale by an instance that died
+    async fn init_locks(&self, stale: DateTime<Utc>) -> Result<(), RestError>;
+    // Take the cleanup lock, if it is free and was last taken before since
+    async fn lock_cleanup(&self, since: DateTime<Utc>) -> Result<(), RestError>;
+    async fn unlock_cleanup(&self) -> Result<(), RestError>;
+    // Take the reconcile lock, if it was last taken before since
+    async fn lock_reconcile(&self, since: DateTime<Utc>) -> Result<(), RestError>;
+    // Take or renew the expiry lock for owner, if owner holds it or it was last renewed before since
+    async fn lock_expiry(&self, owner: &str, since: DateTime<Utc>) -> Result<(), RestError>;
+
+    //
+    // Shared objects
+    //
+
+    // Add a reference to the blob with this digest, registering object under it if there is none
+    async fn share_blob(&self, digest: &str, object: &str) -> Result<Blob, RestError>;
+    // Drop the reference held by upload, returning the blob with the references left. Fails with
+    // NotFound once the upload's reference has been dropped.
+    async fn release_blob(&self, digest: &str, upload: &str) -> Result<Blob, RestError>;
+    async fn delete_blob(&self, digest: &str) -> Result<(), RestError>;
+
+    //
+    // Resumable upload sessions
+    //
+
+    async fn insert_session(&self, session: UploadSession) -> Result<UploadSession, RestError>;
+    async fn find_open_session(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<UploadSession, RestError>;
+    // Add a part, unless something else wrote to the session since it was at offset
+    async fn append_session_part(
+        &self,
+        id: &str,
+        offset: i64,
+        part: &UploadPart,
+    ) -> Result<(), RestError>;
+    // Mark an open session as completing, so it can't be patched or completed twice
+    async fn claim_session(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<UploadSession, RestError>;
+    async fn reopen_session(&self, id: &str) -> Result<(), RestError>;
+    async fn delete_session(&self, id: &str) -> Result<(), RestError>;
+    async fn sessions(&self) -> Result<Vec<UploadSession>, RestError>;
+    async fn expired_sessions(&self, limit: i64) -> Result<Vec<UploadSession>, RestError>;
+
+    //
+    // Deletion queue
+    //
+
+    // Queue an object, or record the latest error of one already queued
+    async fn queue_deletion(&self, object: &str, error: &str) -> Result<(), RestError>;
+    async fn due_deletions(&self, limit: i64) -> Result<Vec<Deletion>, RestError>;
+    async fn reschedule_deletion(&self, deletion: &Deletion) -> Result<(), RestError>;
+    async fn remove_deletion(&self, object: &str) -> Result<(), RestError>;
+    // Pending and dead entries
+    async fn deletion_counts(&self) -> Result<(usize, usize), RestError>;
+
+    //
+    // Purging, which removes inactive uploads for good
+    //
+
+    // Inactive uploads deactivated before cutoff, or expired before it if they don't record when
+    // they were deactivated. Pending direct uploads and tombstones are left out.
+    async fn purgeable_uploads(
+        &self,
+        cutoff: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<MetaData>, RestError>;
+    async fn delete_upload(&self, id: &str) -> Result<(), RestError>;
+    // Replace an inactive upload with its tombstone
+    async fn tombstone_upload(&self, tombstone: &Tombstone) -> Result<(), RestError>;
+
+    //
+    // TTL expiry, where the store removes uploads itself once they expire
+    //
+
+    // Start removing expired uploads, or stop and leave them to the cleanup
+    async fn set_ttl_expiry(&self, enabled: bool) -> Result<(), RestError>;
+    // Uploads removed since the last position saved, for the holder of the expiry lock
+    async fn watch_expired(&self, owner: &str) -> Result<ExpiredUploads, RestError>;
+    // Record that removals up to position have been handled, so a new watch resumes after them
+    async fn save_expiry_position(&self, owner: &str, position: &str) -> Result<(), RestError>;
+
+    //
+    // Storage migrations, progress is kept in a collection named by the migration
+    //
+
+    async fn migrated_objects(
+        &self,
+        collection: &str,
+        target: &str,
+    ) -> Result<Vec<Migration>, RestError>;
+    async fn record_migration(
+        &self,
+        collection: &str,
+        migration: &Migration,
+    ) -> Result<(), RestError>;
+}
+
+#[derive(Clone, Debug)]
+#[enum_dispatch]
+pub enum MetadataClient {
+    MongoStore(MongoStore),
+    SqliteStore(SqliteStore),
+}
+
+impl MetadataClient {
+    // The store from_opts connects to, SQLite when a database file or data directory is given
+    pub fn kind(opts: &ArgMatches) -> &str {
+        match opts.value_of("metadata") {
+            Some(k) => k,
+            None if opts.is_present("sqlite") || opts.is_present("data_dir") => "sqlite",
+            None => "mongo",
+        }
+    }
+
+    // Connect to the requested store, SQLite when a database file or data directory is given and
+    // MongoDB otherwise
+    pub async fn from_opts(opts: &ArgMatches) -> Result<MetadataClient, RestError> {
+        let collections = Collections {
+            uploads: opts.value_of("collection").unwrap().to_string(),
+            admin: opts.value_of("admin").unwrap().to_string(),
+            users: opts.value_of("users").unwrap().to_string(),
+            resumable: opts.value_of("resumable").unwrap().to_string(),
+            blobs: opts.value_of("blobs").unwrap().to_string(),
+            deletions: opts.value_of("deletions").unwrap().to_string(),
+        };
+        let sqlite = match (opts.value_of("sqlite"), opts.value_of("data_dir")) {
+            (Some(path), _) => Some(path.to_owned()),
+            (None, Some(dir)) => Some(Path::new(dir).join(DATA_DIR_SQLITE).display().to_string()),
+            (None, None) => None,
+        };
+        let kind = MetadataClient::kind(opts);
+        log::info!("\"Using {} metadata store\"", kind);
+
+        let client = match kind {
+            "sqlite" => {
+                let path =
+                    sqlite.expect("Set --sqlite or --data_dir for the sqlite metadata store");
+                MetadataClient::SqliteStore(SqliteStore::open(&path, collections).await?)
+            }
+            "mongo" => {
+                let url = opts
+                    .value_of("mongo")
+                    .expect("Set --mongo for the mongo metadata store");
+                MetadataClient::MongoStore(
+                    MongoStore::connect(url, opts.value_of("database").unwrap(), collections)
+                        .await?,
+                )
+            }
+            _ => panic!("Unknown metadata store: {}", kind),
+        };
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn items() -> Vec<(DateTime<Utc>, String)> {
+        let at = |s: i64| Utc.timestamp_opt(1_700_000_000 + s, 0).unwrap();
+        // Out of order, with two items created at the same time
+        vec![
+            (at(3), "d".to_owned()),
+            (at(1), "b".to_owned()),
+            (at(1), "a".to_owned()),
+            (at(2), "c".to_owned()),
+            (at(4), "e".to_owned()),
+        ]
+    }
+
+    fn keys(page: &Page<(DateTime<Utc>, String)>) -> Vec<&str> {
+        page.items.iter().map(|(_, k)| k.as_str()).collect()
+    }
+
+    #[test]
+    fn slice_pages_through_every_item_once() {
+        let first = Page::slice(items(), |i| i.clone(), None, 2).unwrap();
+        assert_eq!(keys(&first), ["a", "b"]);
+        assert_eq!(first.total, Some(5));
+
+        let second = Page::slice(items(), |i| i.clone(), first.next.as_deref(), 2).unwrap();
+        assert_eq!(keys(&second), ["c", "d"]);
+
+        let third = Page::slice(items(), |i| i.clone(), second.next.as_deref(), 2).unwrap();
+        assert_eq!(keys(&third), ["e"]);
+        assert_eq!(third.next, None);
+        assert_eq!(third.total, Some(5));
+    }
+
+    #[test]
+    fn slice_ends_without_a_cursor_on_an_exact_fit() {
+        let page = Page::slice(items(), |i| i.clone(), None, 5).unwrap();
+        assert_eq!(page.items.len(), 5);
+        assert_eq!(page.next, None);
+
+        let first = Page::slice(items(), |i| i.clone(), None, 3).unwrap();
+        let second = Page::slice(items(), |i| i.clone(), first.next.as_deref(), 2).unwrap();
+        assert_eq!(keys(&second), ["d", "e"]);
+        assert_eq!(second.next, None);
+    }
+
+    #[test]
+    fn slice_rejects_invalid_positions() {
+        for after in ["", "not-a-date a", "2023-01-01T00:00:00Z"] {
+            let result = Page::slice(items(), |i| i.clone(), Some(after), 2);
+            assert!(matches!(result, Err(RestError::BadRequest(_))), "{}", after);
+        }
+    }
+}
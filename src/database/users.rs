@@ -1,21 +1,16 @@
 use blake2::{digest::consts::U10, Blake2b, Blake2s256, Digest};
-use bson::{doc, to_document};
 use chrono::{DateTime, Utc};
 use hex::encode;
-use mongodb::options::IndexOptions;
-use mongodb::IndexModel;
 use rand::distributions::{Alphanumeric, DistString};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::database::mongo::MongoClient;
+use crate::database::store::{MetadataClient, MetadataStore, Page};
 use crate::error::Error as RestError;
 
 #[derive(Clone, Debug)]
 pub struct UsersAdmin {
-    pub database: String,
-    pub collection: String,
-    pub db: MongoClient,
+    pub store: MetadataClient,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -177,32 +172,18 @@ impl CurrentUser {
 }
 
 impl UsersAdmin {
-    pub async fn new(
-        db: &str,
-        coll: &str,
-        mongo_client: mongodb::Client,
-    ) -> Result<UsersAdmin, RestError> {
-        let mut users_admin = UsersAdmin {
-            database: db.to_owned(),
-            collection: coll.to_owned(),
-            db: MongoClient::new(mongo_client.clone(), db),
-        };
-        users_admin.create_indexes().await?;
-        Ok(users_admin)
+    pub fn new(store: MetadataClient) -> UsersAdmin {
+        UsersAdmin { store }
     }
 
     pub async fn get_user(&self, email: &str) -> Result<User, RestError> {
-        let filter = doc! {"email": &User::hash(email) };
-        self.db
-            .find_one::<User>(&self.collection, filter, None)
-            .await
+        self.store.find_user_by_email(&User::hash(email)).await
     }
 
     pub async fn validate_email(&self, email: &str, pwd: &str) -> Result<User, RestError> {
-        let filter = doc! {"email": User::hash(email), "pwd": User::hash(pwd) };
         match self
-            .db
-            .find_one::<User>(&self.collection, filter, None)
+            .store
+            .find_user_by_login(&User::hash(email), &User::hash(pwd))
             .await
         {
             Ok(v) => Ok(v),
@@ -214,11 +195,7 @@ impl UsersAdmin {
         if self.get_user(email).await.is_ok() {
             return Err(RestError::UserExists);
         }
-        Ok(self
-            .db
-            .insert_one::<User>(&self.collection, User::new(email, password), None)
-            .await?
-            .id)
+        Ok(self.store.insert_user(User::new(email, password)).await?.id)
     }
 
     pub async fn get_user_id(&self, email: &str, password: &str) -> Result<String, RestError> {
@@ -235,36 +212,25 @@ impl UsersAdmin {
         role: Option<String>,
     ) -> Result<ApiKey, RestError> {
         let api_key = ApiKey::new(tags, role);
-        let filter = doc! {"id": &id };
-        let update = doc! {"$push": {"api_keys": to_document(&api_key.hashed())? }};
-
-        self.db
-            .find_one_and_update::<User>(&self.collection, filter, update, None)
-            .await?;
+        self.store.push_api_key(id, &api_key.hashed()).await?;
         Ok(api_key)
     }
 
     pub async fn delete_api_key(&self, id: &str, key: &str) -> Result<bool, RestError> {
         log::debug!("\"Trying to delete {} from {}", key, id);
-        let filter = doc! {"id": &id, "api_keys.key": key };
-        let update = doc! {"$pull": {"api_keys": { "key": key } }};
-
-        match self
-            .db
-            .find_one_and_update::<User>(&self.collection, filter, update, None)
-            .await
-        {
+        match self.store.pull_api_key(id, key).await {
             Ok(_) => Ok(true),
             Err(_) => Ok(false),
         }
     }
 
-    pub async fn list_api_keys(&self, id: &str) -> Result<Vec<ApiKeyBrief>, RestError> {
-        let filter = doc! {"id": &id };
-        let user = self
-            .db
-            .find_one::<User>(&self.collection, filter, None)
-            .await?;
+    pub async fn list_api_keys(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<ApiKeyBrief>, RestError> {
+        let user = self.store.find_user(id).await?;
         let result: Vec<ApiKeyBrief> = user
             .api_keys
             .iter()
@@ -275,7 +241,7 @@ impl UsersAdmin {
                 tags: s.tags.clone(),
             })
             .collect();
-        Ok(result)
+        Page::slice(result, |k| (k.created, k.key.clone()), after, limit)
     }
 
     //    pub async fn validate_api_key(&self, key: &str, secret: &str) -> Result<String, RestError> {
@@ -298,10 +264,9 @@ impl UsersAdmin {
         id: &str,
         pwd: &str,
     ) -> Result<CurrentUser, RestError> {
-        let filter = doc! {"$or": [ {"id": id, "pwd": User::hash(pwd) }, { "api_keys.key": id, "api_keys.secret": User::hash(pwd) } ] };
         let doc = self
-            .db
-            .find_one::<User>(&self.collection, filter, None)
+            .store
+            .find_user_by_credentials(id, &User::hash(pwd))
             .await?;
         let api_key = doc.api_keys.iter().find(|k| k.key == id);
 
@@ -320,33 +285,4 @@ impl UsersAdmin {
             })
         }
     }
-
-    pub async fn create_indexes(&mut self) -> Result<(), RestError> {
-        log::debug!("Creating users collection indexes");
-        let mut indexes = Vec::new();
-        indexes.push(
-            IndexModel::builder()
-                .keys(doc! {"api_keys.key":1, "api_keys.secret": 1})
-                .options(IndexOptions::builder().unique(true).build())
-                .build(),
-        );
-
-        indexes.push(
-            IndexModel::builder()
-                .keys(doc! {"id":1, "pwd": 1})
-                .options(IndexOptions::builder().unique(true).build())
-                .build(),
-        );
-
-        indexes.push(
-            IndexModel::builder()
-                .keys(doc! {"email":1})
-                .options(IndexOptions::builder().unique(true).build())
-                .build(),
-        );
-
-        self.db
-            .create_indexes(&self.collection, indexes, None)
-            .await
-    }
 }
@@ -14,16 +14,34 @@ pub enum Error {
     UserExists,
     BadLogin,
     Unauthorized,
+    PayloadTooLarge,
+    InsufficientStorage,
+    BadRequest(&'static str),
+    Conflict,
+    PreconditionFailed,
+    UnsupportedMediaType,
+    RangeNotSatisfiable(u64),
     CryptoError(orion::errors::UnknownCryptoError),
     DeError(bson::de::Error),
     SerError(bson::ser::Error),
     Mongo(mongodb::error::Error),
+    Sqlite(rusqlite::Error),
     Storage(cloud_storage::Error),
     Bson(bson::document::ValueAccessError),
     Utf(std::str::Utf8Error),
     Azure(azure_core::error::Error),
     Ms(ms_converter::Error),
     Json(serde_json::Error),
+    Io(std::io::Error),
+    Join(tokio::task::JoinError),
+    S3(Box<aws_sdk_s3::Error>),
+    S3Stream(aws_sdk_s3::primitives::ByteStreamError),
+    S3Presign(aws_sdk_s3::presigning::PresigningConfigError),
+    Body(axum::Error),
+    Http(reqwest::Error),
+    Header(hyper::header::InvalidHeaderValue),
+    StorageCredentials(String),
+    StorageConfig(String),
 }
 
 impl std::error::Error for Error {}
@@ -39,16 +57,38 @@ impl fmt::Display for Error {
             Error::UserExists => f.write_str("{\"error\": \"User already exists\"}"),
             Error::BadLogin => f.write_str("{\"error\": \"Incorrect login credentials\"}"),
             Error::Unauthorized => f.write_str("{\"error\": \"Unauthorized\"}"),
+            Error::PayloadTooLarge => f.write_str("{\"error\": \"Upload exceeds the size limit\"}"),
+            Error::InsufficientStorage => f.write_str("{\"error\": \"Storage budget exceeded\"}"),
+            Error::BadRequest(msg) => write!(f, "{{\"error\": \"{}\"}}", msg),
+            Error::Conflict => f.write_str("{\"error\": \"Upload offset does not match\"}"),
+            Error::PreconditionFailed => {
+                f.write_str("{\"error\": \"Unsupported protocol version\"}")
+            }
+            Error::UnsupportedMediaType => f.write_str("{\"error\": \"Unsupported content type\"}"),
+            Error::RangeNotSatisfiable(_) => {
+                f.write_str("{\"error\": \"Requested range not satisfiable\"}")
+            }
             Error::CryptoError(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::DeError(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::SerError(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Mongo(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::Sqlite(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Storage(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Bson(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Utf(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Azure(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Ms(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Json(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::Io(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::Join(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::S3(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::S3Stream(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::S3Presign(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::Body(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::Http(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::Header(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::StorageCredentials(ref msg) => write!(f, "{{\"error\": \"{}\"}}", msg),
+            Error::StorageConfig(ref msg) => write!(f, "{{\"error\": \"{}\"}}", msg),
         }
     }
 }
@@ -63,10 +103,23 @@ impl IntoResponse for Error {
             Error::UserExists => StatusCode::CONFLICT,
             Error::BadLogin | Error::Unauthorized => StatusCode::UNAUTHORIZED,
             Error::DeError(_) => StatusCode::NOT_FOUND,
+            Error::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Conflict => StatusCode::CONFLICT,
+            Error::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Error::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            Error::Body(_) => StatusCode::BAD_REQUEST,
+            Error::StorageCredentials(_) => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        Response::builder().status(status_code).body(body).unwrap()
+        let mut response = Response::builder().status(status_code);
+        if let Error::RangeNotSatisfiable(size) = self {
+            response = response.header("content-range", format!("bytes */{}", size));
+        }
+        response.body(body).unwrap()
     }
 }
 
@@ -94,6 +147,16 @@ impl From<mongodb::error::Error> for Error {
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        // Queries expecting a row find nothing, like a MongoDB filter that matches no document
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => Error::NotFound,
+            err => Error::Sqlite(err),
+        }
+    }
+}
+
 impl From<cloud_storage::Error> for Error {
     fn from(err: cloud_storage::Error) -> Error {
         Error::Storage(err)
@@ -129,3 +192,59 @@ impl From<serde_json::Error> for Error {
         Error::Json(err)
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<aws_sdk_s3::Error> for Error {
+    fn from(err: aws_sdk_s3::Error) -> Error {
+        Error::S3(Box::new(err))
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(err: tokio::task::JoinError) -> Error {
+        Error::Join(err)
+    }
+}
+
+impl From<aws_sdk_s3::primitives::ByteStreamError> for Error {
+    fn from(err: aws_sdk_s3::primitives::ByteStreamError) -> Error {
+        Error::S3Stream(err)
+    }
+}
+
+impl From<aws_sdk_s3::presigning::PresigningConfigError> for Error {
+    fn from(err: aws_sdk_s3::presigning::PresigningConfigError) -> Error {
+        Error::S3Presign(err)
+    }
+}
+
+impl From<axum::Error> for Error {
+    fn from(err: axum::Error) -> Error {
+        // Streamed bodies only hit the upload limit part way through the request
+        let mut source = std::error::Error::source(&err);
+        while let Some(e) = source {
+            if e.is::<http_body::LengthLimitError>() {
+                return Error::PayloadTooLarge;
+            }
+            source = e.source();
+        }
+        Error::Body(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::Http(err)
+    }
+}
+
+impl From<hyper::header::InvalidHeaderValue> for Error {
+    fn from(err: hyper::header::InvalidHeaderValue) -> Error {
+        Error::Header(err)
+    }
+}
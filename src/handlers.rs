@@ -1,21 +1,25 @@
 use axum::{
-    body::Bytes,
-    extract::{OriginalUri, Path, Query},
+    body::StreamBody,
+    extract::{BodyStream, OriginalUri, Path, Query},
     http::StatusCode,
     response::{IntoResponse, Response},
     Extension, Json,
 };
 use clap::{crate_description, crate_name, crate_version};
+use futures::TryStreamExt;
+use hyper::header::{HeaderValue, AUTHORIZATION, LOCATION};
 use hyper::HeaderMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use utoipa::{IntoParams, ToSchema};
 
 //use crate::database::secret::SecretScrubbed;
 use crate::database::metadata::MetaDataPublic;
+use crate::database::store::{Page, PAGE_LIMIT_DEFAULT, PAGE_LIMIT_MAX};
 use crate::database::users::CurrentUser;
 use crate::error::Error as RestError;
-use crate::helpers::tags_deserialize;
+use crate::helpers::{decode_cursor, encode_cursor, http_date, tags_deserialize, RangeRequest};
+use crate::state::{hash, Keys, ReconcileReport};
 use crate::State;
 
 // This is required in order to get the method from the request
@@ -47,6 +51,47 @@ pub struct Tags {
     tags: Option<Vec<String>>,
 }
 
+#[derive(Deserialize, IntoParams)]
+pub struct Paging {
+    // Items a page, up to 1000
+    limit: Option<i64>,
+    // Cursor returned in X-Next-Cursor with the previous page
+    next: Option<String>,
+}
+
+impl Paging {
+    fn limit(&self) -> Result<i64, RestError> {
+        match self.limit {
+            Some(l) if l < 1 => Err(RestError::BadRequest("Limit must be at least 1")),
+            Some(l) => Ok(std::cmp::min(l, PAGE_LIMIT_MAX)),
+            None => Ok(PAGE_LIMIT_DEFAULT),
+        }
+    }
+
+    fn after(&self, keys: &Keys) -> Result<Option<String>, RestError> {
+        self.next
+            .as_deref()
+            .map(|c| decode_cursor(keys, c))
+            .transpose()
+    }
+}
+
+// Listings are still returned as a plain array, with the cursor for the next page, if there is
+// one, and the total count in headers
+fn page_response<T: Serialize>(keys: &Keys, page: Page<T>) -> Result<Response, RestError> {
+    let mut headers = HeaderMap::new();
+    if let Some(next) = page.next.as_deref() {
+        headers.insert(
+            "x-next-cursor",
+            HeaderValue::from_str(&encode_cursor(keys, next))?,
+        );
+    }
+    if let Some(total) = page.total {
+        headers.insert("x-total-count", total.into());
+    }
+    Ok((headers, Json(page.items)).into_response())
+}
+
 #[derive(Deserialize, ToSchema)]
 pub struct CreateUser {
     email: String,
@@ -267,6 +312,9 @@ pub async fn delete_link(
 #[utoipa::path(
     get,
     path = "/api/v1/uploads/{doc_id}/links",
+    params(
+       Paging
+    ),
     security(("basic" = [])),
     responses(
         (status = 200, description = "List upload links"),
@@ -276,18 +324,24 @@ pub async fn get_links(
     Extension(state): Extension<State>,
     Extension(current_user): Extension<CurrentUser>,
     Path(doc_id): Path<String>,
-) -> Result<Json<Value>, RestError> {
-    if current_user.id.is_some() && current_user.list() {
+    paging: Query<Paging>,
+) -> Result<Response, RestError> {
+    if let Some(id) = current_user.id.as_ref().filter(|_| current_user.list()) {
         match state
-            .get_links(current_user.id.as_ref().unwrap(), &doc_id)
+            .get_links(
+                id,
+                &doc_id,
+                paging.after(&state.configs.keys)?.as_deref(),
+                paging.limit()?,
+            )
             .await
         {
-            Ok(secret) => {
+            Ok(links) => {
                 log::info!(
                     "{{\"method\": \"GET\", \"path\": \"/api/v1/uploads/{}/links\", \"status\": 200}}",
                     doc_id
                 );
-                Ok(Json(json! {secret}))
+                page_response(&state.configs.keys, links)
             }
             Err(e) => Err(e),
         }
@@ -372,6 +426,9 @@ pub async fn delete_api_key(
 #[utoipa::path(
     get,
     path = "/api/v1/user/apiKey",
+    params(
+       Paging
+    ),
     security(("basic" = [])),
     responses(
         (status = 200, description = "List API keys"),
@@ -380,14 +437,22 @@ pub async fn delete_api_key(
 pub async fn list_api_keys(
     Extension(state): Extension<State>,
     Extension(current_user): Extension<CurrentUser>,
-) -> Result<Json<Value>, RestError> {
-    if current_user.id.is_some() && current_user.list() {
-        match state.list_api_keys(current_user.id.as_ref().unwrap()).await {
+    paging: Query<Paging>,
+) -> Result<Response, RestError> {
+    if let Some(id) = current_user.id.as_ref().filter(|_| current_user.list()) {
+        match state
+            .list_api_keys(
+                id,
+                paging.after(&state.configs.keys)?.as_deref(),
+                paging.limit()?,
+            )
+            .await
+        {
             Ok(api_keys) => {
                 log::info!(
                     "{{\"method\": \"GET\", \"path\": \"/api/v1/user/apiKey\", \"status\": 200}}",
                 );
-                Ok(Json(json!(api_keys)))
+                page_response(&state.configs.keys, api_keys)
             }
             Err(e) => Err(e),
         }
@@ -400,7 +465,7 @@ pub async fn list_api_keys(
     get,
     path = "/api/v1/uploads",
     params(
-       Tags
+       Tags, Paging
     ),
     security(("basic" = [])),
     responses(
@@ -411,17 +476,23 @@ pub async fn list_uploads(
     Extension(state): Extension<State>,
     Extension(current_user): Extension<CurrentUser>,
     queries: Query<Tags>,
-) -> Result<Json<Vec<MetaDataPublic>>, RestError> {
-    if current_user.id.is_some() && current_user.list() {
+    paging: Query<Paging>,
+) -> Result<Response, RestError> {
+    if let Some(id) = current_user.id.as_ref().filter(|_| current_user.list()) {
         match state
-            .uploads_owned(current_user.id.as_ref().unwrap(), queries.tags.clone())
+            .uploads_owned(
+                id,
+                queries.tags.clone(),
+                paging.after(&state.configs.keys)?.as_deref(),
+                paging.limit()?,
+            )
             .await
         {
             Ok(uploads) => {
                 log::info!(
                     "{{\"method\": \"GET\", \"path\": \"/api/v1/user/uploads\", \"status\": 200}}",
                 );
-                Ok(Json(uploads))
+                page_response(&state.configs.keys, uploads)
             }
             Err(e) => Err(e),
         }
@@ -550,12 +621,15 @@ pub async fn get_doc_tags(
     ),
     responses(
         (status = 200, description = "Download item", content_type = "application/octet"),
+        (status = 206, description = "Download part of item", content_type = "application/octet"),
+        (status = 416, description = "Requested range not satisfiable"),
     )
 )]
 pub async fn download(
     Extension(mut state): Extension<State>,
     queries: Query<QueriesGet>,
     Path(id): Path<String>,
+    request_headers: HeaderMap,
 ) -> Result<Response, RestError> {
     let id_override = match &queries.id {
         Some(i) => i.clone(),
@@ -563,17 +637,57 @@ pub async fn download(
     };
 
     match state
-        .get(&id_override, queries.key.as_ref(), queries.pwd.as_ref())
+        .get(
+            &id_override,
+            queries.key.as_ref(),
+            queries.pwd.as_ref(),
+            RangeRequest::from_headers(&request_headers),
+        )
         .await
     {
-        Ok((s, c)) => {
+        Ok(d) if d.redirect.is_some() => {
             log::info!(
-                "{{\"method\": \"GET\", \"path\": \"/download/{}\", \"status\": 200}}",
+                "{{\"method\": \"GET\", \"path\": \"/download/{}\", \"status\": 302}}",
                 &id_override
             );
             let mut headers = HeaderMap::new();
-            headers.insert("content-type", c.parse().unwrap());
-            Ok((StatusCode::OK, headers, s).into_response())
+            headers.insert(LOCATION, d.redirect.unwrap_or_default().parse().unwrap());
+            headers.insert("cache-control", "no-store".parse().unwrap());
+            Ok((StatusCode::FOUND, headers).into_response())
+        }
+        Ok(d) => {
+            let status = match d.range {
+                Some(_) => StatusCode::PARTIAL_CONTENT,
+                None => StatusCode::OK,
+            };
+            log::info!(
+                "{{\"method\": \"GET\", \"path\": \"/download/{}\", \"status\": {}}}",
+                &id_override,
+                status.as_u16()
+            );
+            let mut headers = HeaderMap::new();
+            headers.insert("content-type", d.content_type.parse().unwrap());
+            headers.insert("accept-ranges", "bytes".parse().unwrap());
+            headers.insert("etag", d.etag.parse().unwrap());
+            headers.insert(
+                "last-modified",
+                http_date(&d.last_modified).parse().unwrap(),
+            );
+            match d.range {
+                Some((start, end)) => {
+                    headers.insert(
+                        "content-range",
+                        format!("bytes {}-{}/{}", start, end, d.size)
+                            .parse()
+                            .unwrap(),
+                    );
+                    headers.insert("content-length", (end - start + 1).into());
+                }
+                None => {
+                    headers.insert("content-length", d.size.into());
+                }
+            }
+            Ok((status, headers, StreamBody::new(d.data)).into_response())
         }
         Err(e) => {
             log::info!(
@@ -602,10 +716,20 @@ pub async fn upload(
     Extension(current_user): Extension<CurrentUser>,
     queries: Query<QueriesSet>,
     headers: HeaderMap,
-    body: Bytes,
+    body: BodyStream,
 ) -> Result<Response, RestError> {
+    let declared = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
     let results = state
-        .set(body, &queries, headers, current_user.clone())
+        .set(
+            Box::pin(body.map_err(RestError::from)),
+            declared,
+            &queries,
+            headers,
+            current_user.clone(),
+        )
         .await?;
     log::info!(
         "{{\"method\": \"POST\", \"path\": \"/upload\", \"id\": \"{}\", \"status\": 201}}",
@@ -625,3 +749,318 @@ pub async fn handler_404(OriginalUri(original_uri): OriginalUri) -> impl IntoRes
     );
     RestError::NotFound
 }
+
+// Resumable uploads follow the tus protocol, https://tus.io/protocols/resumable-upload
+const TUS_VERSION: &str = "1.0.0";
+
+fn tus_headers(expires: Option<&bson::DateTime>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("tus-resumable", TUS_VERSION.parse().unwrap());
+    headers.insert("cache-control", "no-store".parse().unwrap());
+    if let Some(e) = expires {
+        headers.insert("upload-expires", http_date(&e.to_chrono()).parse().unwrap());
+    }
+    headers
+}
+
+fn tus_check(headers: &HeaderMap) -> Result<(), RestError> {
+    match headers.get("tus-resumable").and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(RestError::PreconditionFailed),
+    }
+}
+
+fn header_i64(headers: &HeaderMap, name: &'static str) -> Result<i64, RestError> {
+    match headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+    {
+        Some(v) if v >= 0 => Ok(v),
+        _ => Err(RestError::BadRequest(name)),
+    }
+}
+
+#[utoipa::path(
+    options,
+    path = "/api/v1/resumable",
+    responses(
+        (status = 204, description = "Describe resumable upload support"),
+    )
+)]
+pub async fn resumable_options() -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert("tus-resumable", TUS_VERSION.parse().unwrap());
+    headers.insert("tus-version", TUS_VERSION.parse().unwrap());
+    headers.insert(
+        "tus-extension",
+        "creation,expiration,termination".parse().unwrap(),
+    );
+    (StatusCode::NO_CONTENT, headers).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/resumable",
+    responses(
+        (status = 201, description = "Create resumable upload session"),
+    ),
+    security(("basic" = [])),
+)]
+pub async fn resumable_create(
+    Extension(state): Extension<State>,
+    Extension(current_user): Extension<CurrentUser>,
+    headers: HeaderMap,
+) -> Result<Response, RestError> {
+    tus_check(&headers)?;
+    let length = header_i64(&headers, "upload-length")?;
+    let session = state.create_session(length, &current_user).await?;
+    log::info!(
+        "{{\"method\": \"POST\", \"path\": \"/api/v1/resumable\", \"id\": \"{}\", \"status\": 201}}",
+        &session.id
+    );
+
+    let mut response_headers = tus_headers(Some(&session.expires));
+    response_headers.insert(
+        "location",
+        format!("{}/api/v1/resumable/{}", state.configs.url, session.id)
+            .parse()
+            .unwrap(),
+    );
+    Ok((StatusCode::CREATED, response_headers).into_response())
+}
+
+#[utoipa::path(
+    head,
+    path = "/api/v1/resumable/{id}",
+    responses(
+        (status = 200, description = "Get resumable upload offset"),
+    ),
+    security(("basic" = [])),
+)]
+pub async fn resumable_head(
+    Extension(state): Extension<State>,
+    Extension(current_user): Extension<CurrentUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, RestError> {
+    tus_check(&headers)?;
+    let session = state.get_session(&id, &current_user).await?;
+
+    let mut response_headers = tus_headers(Some(&session.expires));
+    response_headers.insert("upload-offset", session.offset.into());
+    response_headers.insert("upload-length", session.length.into());
+    Ok((StatusCode::OK, response_headers).into_response())
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/resumable/{id}",
+    request_body(content = Bytes, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 204, description = "Append to resumable upload"),
+        (status = 409, description = "Upload offset does not match"),
+    ),
+    security(("basic" = [])),
+)]
+pub async fn resumable_patch(
+    Extension(mut state): Extension<State>,
+    Extension(current_user): Extension<CurrentUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<Response, RestError> {
+    tus_check(&headers)?;
+    if headers.get("content-type").and_then(|v| v.to_str().ok())
+        != Some("application/offset+octet-stream")
+    {
+        return Err(RestError::UnsupportedMediaType);
+    }
+    let offset = header_i64(&headers, "upload-offset")?;
+
+    let offset = state
+        .patch_session(
+            &id,
+            offset,
+            Box::pin(body.map_err(RestError::from)),
+            &current_user,
+        )
+        .await?;
+    log::info!(
+        "{{\"method\": \"PATCH\", \"path\": \"/api/v1/resumable/{}\", \"offset\": {}, \"status\": 204}}",
+        &id,
+        offset
+    );
+
+    let mut response_headers = tus_headers(None);
+    response_headers.insert("upload-offset", offset.into());
+    Ok((StatusCode::NO_CONTENT, response_headers).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/resumable/{id}",
+    responses(
+        (status = 204, description = "Terminate resumable upload"),
+    ),
+    security(("basic" = [])),
+)]
+pub async fn resumable_delete(
+    Extension(state): Extension<State>,
+    Extension(current_user): Extension<CurrentUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, RestError> {
+    tus_check(&headers)?;
+    state.delete_session(&id, &current_user).await?;
+    log::info!(
+        "{{\"method\": \"DELETE\", \"path\": \"/api/v1/resumable/{}\", \"status\": 204}}",
+        &id
+    );
+    Ok((StatusCode::NO_CONTENT, tus_headers(None)).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/resumable/{id}/complete",
+    params(
+       QueriesSet
+    ),
+    responses(
+        (status = 201, description = "Finish resumable upload"),
+        (status = 409, description = "Upload is not complete"),
+    ),
+    security(("basic" = [])),
+)]
+pub async fn resumable_complete(
+    Extension(mut state): Extension<State>,
+    Extension(current_user): Extension<CurrentUser>,
+    Path(id): Path<String>,
+    queries: Query<QueriesSet>,
+    headers: HeaderMap,
+) -> Result<Response, RestError> {
+    let results = state
+        .complete_session(&id, &queries, headers, current_user)
+        .await?;
+    log::info!(
+        "{{\"method\": \"POST\", \"path\": \"/api/v1/resumable/{}/complete\", \"id\": \"{}\", \"status\": 201}}",
+        &id,
+        &results.data.id
+    );
+
+    let json = json!({"message": results });
+    Ok((StatusCode::CREATED, json.to_string()).into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct QueriesDirect {
+    // Size in bytes of the object the client is going to write
+    size: u64,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/direct",
+    params(
+       QueriesDirect,
+       QueriesSet
+    ),
+    responses(
+        (status = 201, description = "Start direct upload, returning a signed url to PUT the object to"),
+        (status = 400, description = "Storage backend can't sign upload urls"),
+    ),
+    security(("basic" = [])),
+)]
+pub async fn direct_create(
+    Extension(state): Extension<State>,
+    Extension(current_user): Extension<CurrentUser>,
+    direct: Query<QueriesDirect>,
+    queries: Query<QueriesSet>,
+    headers: HeaderMap,
+) -> Result<Response, RestError> {
+    let results = state
+        .create_direct(direct.size, &queries, headers, current_user)
+        .await?;
+    log::info!(
+        "{{\"method\": \"POST\", \"path\": \"/api/v1/direct\", \"id\": \"{}\", \"status\": 201}}",
+        &results.id
+    );
+
+    let json = json!({"message": results });
+    Ok((
+        StatusCode::CREATED,
+        [("cache-control", "no-store")],
+        json.to_string(),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/direct/{id}/complete",
+    responses(
+        (status = 200, description = "Finish direct upload"),
+        (status = 400, description = "Object is missing or does not match the declared size"),
+        (status = 507, description = "Storage budget exceeded"),
+    ),
+    security(("basic" = [])),
+)]
+pub async fn direct_complete(
+    Extension(state): Extension<State>,
+    Extension(current_user): Extension<CurrentUser>,
+    Path(id): Path<String>,
+) -> Result<Response, RestError> {
+    let results = state.complete_direct(&id, &current_user).await?;
+    log::info!(
+        "{{\"method\": \"POST\", \"path\": \"/api/v1/direct/{}/complete\", \"status\": 200}}",
+        &id
+    );
+
+    let json = json!({"message": results });
+    Ok((StatusCode::OK, json.to_string()).into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct QueriesReconcile {
+    #[serde(default)]
+    repair: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/reconcile",
+    params(
+       QueriesReconcile
+    ),
+    responses(
+        (status = 200, description = "Compare object storage with the uploads collection"),
+        (status = 401, description = "Missing or incorrect admin token"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn reconcile(
+    Extension(state): Extension<State>,
+    queries: Query<QueriesReconcile>,
+    headers: HeaderMap,
+) -> Result<Json<ReconcileReport>, RestError> {
+    // Only available when an admin token has been configured
+    let token = match state.configs.admin_token.as_ref() {
+        Some(t) => t,
+        None => return Err(RestError::NotFound),
+    };
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if provided.map(hash) != Some(hash(token)) {
+        log::warn!("\"Reconcile requested without a valid admin token\"");
+        return Err(RestError::Unauthorized);
+    }
+
+    let report = state.reconcile(queries.repair).await?;
+    log::info!(
+        "{{\"method\": \"POST\", \"path\": \"/api/v1/admin/reconcile\", \"repair\": {}, \"status\": 200}}",
+        queries.repair
+    );
+    Ok(Json(report))
+}
@@ -1,6 +1,15 @@
+use blake2::digest::Mac;
+use blake2::{Blake2s256, Blake2sMac256, Digest};
+use chrono::{DateTime, Utc};
+use hyper::header::{IF_RANGE, RANGE};
+use hyper::HeaderMap;
 use serde::Deserialize;
 use serde::Deserializer;
 
+use crate::database::store::PAGE_INVALID_CURSOR;
+use crate::error::Error as RestError;
+use crate::state::Keys;
+
 pub fn tags_deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
 where
     D: Deserializer<'de>,
@@ -12,3 +21,254 @@ where
         Ok(None)
     }
 }
+
+// A single byte range from a Range header, requests for multiple ranges are served in full
+#[derive(Clone, Copy, Debug)]
+pub enum RangeSpec {
+    From(u64, Option<u64>),
+    Suffix(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct RangeRequest {
+    pub spec: RangeSpec,
+    pub if_range: Option<String>,
+}
+
+impl RangeSpec {
+    pub fn parse(header: &str) -> Option<RangeSpec> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            return Some(RangeSpec::Suffix(end.parse().ok()?));
+        }
+
+        let start: u64 = start.parse().ok()?;
+        if end.is_empty() {
+            return Some(RangeSpec::From(start, None));
+        }
+        let end: u64 = end.parse().ok()?;
+        if end < start {
+            return None;
+        }
+        Some(RangeSpec::From(start, Some(end)))
+    }
+
+    // Inclusive start and end within an object of the given size, or None if it can't be satisfied
+    pub fn resolve(&self, size: u64) -> Option<(u64, u64)> {
+        if size == 0 {
+            return None;
+        }
+        match *self {
+            RangeSpec::From(start, _) if start >= size => None,
+            RangeSpec::From(start, end) => Some((start, end.unwrap_or(size - 1).min(size - 1))),
+            RangeSpec::Suffix(0) => None,
+            RangeSpec::Suffix(n) => Some((size.saturating_sub(n), size - 1)),
+        }
+    }
+}
+
+impl RangeRequest {
+    pub fn from_headers(headers: &HeaderMap) -> Option<RangeRequest> {
+        let spec = RangeSpec::parse(headers.get(RANGE)?.to_str().ok()?)?;
+        let if_range = headers
+            .get(IF_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned());
+        Some(RangeRequest { spec, if_range })
+    }
+
+    // A range only applies if the client's copy is still current, otherwise the whole object is sent
+    pub fn applies(&self, etag: &str, last_modified: &DateTime<Utc>) -> bool {
+        match self.if_range.as_deref() {
+            None => true,
+            Some(v) if v.starts_with('"') => v == etag,
+            Some(v) if v.starts_with("W/") => false,
+            Some(v) => match DateTime::parse_from_rfc2822(v) {
+                Ok(d) => d.timestamp() == last_modified.timestamp(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+// Listing positions are handed out signed with the latest key, along with its version, so clients
+// can pass them back but can't make up positions of their own
+pub fn encode_cursor(keys: &Keys, position: &str) -> String {
+    let key = keys.latest_key();
+    let mac = cursor_mac(&key.key, position).finalize().into_bytes();
+    format!("{}.{}.{}", key.ver, hex::encode(position), hex::encode(mac))
+}
+
+pub fn decode_cursor(keys: &Keys, cursor: &str) -> Result<String, RestError> {
+    let mut parts = cursor.splitn(3, '.');
+    let key = parts
+        .next()
+        .and_then(|v| v.parse::<u8>().ok())
+        .and_then(|v| keys.get_ver(v));
+    let position = parts
+        .next()
+        .and_then(|p| hex::decode(p).ok())
+        .and_then(|p| String::from_utf8(p).ok());
+    let mac = parts.next().and_then(|m| hex::decode(m).ok());
+    match (key, position, mac) {
+        (Some(key), Some(position), Some(mac))
+            if cursor_mac(&key.key, &position).verify_slice(&mac).is_ok() =>
+        {
+            Ok(position)
+        }
+        _ => Err(RestError::BadRequest(PAGE_INVALID_CURSOR)),
+    }
+}
+
+fn cursor_mac(key: &str, position: &str) -> Blake2sMac256 {
+    let secret = Blake2s256::digest(key.as_bytes());
+    let mut mac = Blake2sMac256::new_from_slice(&secret).expect("Blake2s accepts 32 byte keys");
+    mac.update(b"cursor ");
+    mac.update(position.as_bytes());
+    mac
+}
+
+pub fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Key;
+
+    fn keys(vers: &[u8]) -> Keys {
+        Keys {
+            keys: vers
+                .iter()
+                .map(|&ver| Key {
+                    ver,
+                    key: format!("{:0>32}", ver),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let position = "2023-01-01T00:00:00.000000000Z 436bdf7f";
+        let cursor = encode_cursor(&keys(&[1]), position);
+        assert!(!cursor.contains(' '));
+        assert_eq!(decode_cursor(&keys(&[1]), &cursor).unwrap(), position);
+
+        // Cursors stay valid after a new key is added
+        assert_eq!(decode_cursor(&keys(&[1, 2]), &cursor).unwrap(), position);
+    }
+
+    #[test]
+    fn cursor_rejects_tampering() {
+        let cursor = encode_cursor(&keys(&[1]), "2023-01-01T00:00:00Z a");
+        let (ver, rest) = cursor.split_once('.').unwrap();
+        let (_, mac) = rest.split_once('.').unwrap();
+        let forged = format!("{}.{}.{}", ver, hex::encode("2023-01-01T00:00:00Z b"), mac);
+
+        for cursor in [
+            forged.as_str(),
+            &cursor[..cursor.len() - 2],
+            &format!("9.{}", rest),
+            "",
+            "1.zz.zz",
+            "garbage",
+        ] {
+            assert!(
+                matches!(
+                    decode_cursor(&keys(&[1]), cursor),
+                    Err(RestError::BadRequest(_))
+                ),
+                "{}",
+                cursor
+            );
+        }
+        // Nor is a cursor valid once its key is gone
+        assert!(decode_cursor(&keys(&[2]), &cursor).is_err());
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert!(matches!(
+            RangeSpec::parse("bytes=0-99"),
+            Some(RangeSpec::From(0, Some(99)))
+        ));
+        assert!(matches!(
+            RangeSpec::parse("bytes=100-"),
+            Some(RangeSpec::From(100, None))
+        ));
+        assert!(matches!(
+            RangeSpec::parse("bytes=-500"),
+            Some(RangeSpec::Suffix(500))
+        ));
+        assert!(matches!(
+            RangeSpec::parse(" bytes= 5 - 5 "),
+            Some(RangeSpec::From(5, Some(5)))
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_ranges() {
+        for header in [
+            "bytes=5-1",
+            "bytes=0-1,3-4",
+            "items=0-1",
+            "bytes=a-b",
+            "bytes=-",
+            "bytes=10",
+        ] {
+            assert!(RangeSpec::parse(header).is_none(), "{}", header);
+        }
+    }
+
+    #[test]
+    fn resolves_against_object_size() {
+        assert_eq!(RangeSpec::From(0, Some(99)).resolve(50), Some((0, 49)));
+        assert_eq!(RangeSpec::From(10, Some(19)).resolve(50), Some((10, 19)));
+        assert_eq!(RangeSpec::From(10, None).resolve(50), Some((10, 49)));
+        assert_eq!(RangeSpec::From(49, None).resolve(50), Some((49, 49)));
+        assert_eq!(RangeSpec::From(50, None).resolve(50), None);
+        assert_eq!(RangeSpec::Suffix(10).resolve(50), Some((40, 49)));
+        assert_eq!(RangeSpec::Suffix(100).resolve(50), Some((0, 49)));
+        assert_eq!(RangeSpec::Suffix(0).resolve(50), None);
+        assert_eq!(RangeSpec::From(0, None).resolve(0), None);
+        assert_eq!(RangeSpec::Suffix(1).resolve(0), None);
+    }
+
+    #[test]
+    fn if_range_only_applies_to_the_current_version() {
+        let request = |if_range: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RANGE, "bytes=0-9".parse().unwrap());
+            if let Some(v) = if_range {
+                headers.insert(IF_RANGE, v.parse().unwrap());
+            }
+            RangeRequest::from_headers(&headers).unwrap()
+        };
+        let modified = DateTime::parse_from_rfc2822("Tue, 03 Jan 2023 10:00:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        let etag = "\"abc\"";
+
+        assert!(request(None).applies(etag, &modified));
+        assert!(request(Some("\"abc\"")).applies(etag, &modified));
+        assert!(!request(Some("\"def\"")).applies(etag, &modified));
+        // Weak validators never match, as ranges need a byte for byte identical object
+        assert!(!request(Some("W/\"abc\"")).applies(etag, &modified));
+        assert!(request(Some(&http_date(&modified))).applies(etag, &modified));
+        assert!(!request(Some("Wed, 04 Jan 2023 10:00:00 GMT")).applies(etag, &modified));
+        assert!(!request(Some("yesterday")).applies(etag, &modified));
+
+        // If-Range on its own asks for nothing
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_RANGE, etag.parse().unwrap());
+        assert!(RangeRequest::from_headers(&headers).is_none());
+    }
+}
@@ -2,17 +2,13 @@ use axum::extract::DefaultBodyLimit;
 use axum::{
     extract::Extension,
     middleware,
-    routing::{delete, get, post, put},
+    routing::{delete, get, head, post, put},
     Router,
 };
-use azure_storage::StorageCredentials;
-use azure_storage_blobs::prelude::*;
 use chrono::Local;
-use clap::{crate_name, crate_version, Arg, Command};
+use clap::{crate_name, crate_version, Arg, ArgMatches, Command, ErrorKind};
 use env_logger::{Builder, Target};
 use log::LevelFilter;
-use mongodb::options::ClientOptions;
-use mongodb::Client;
 use std::future::ready;
 use std::io::Write;
 use std::net::SocketAddr;
@@ -37,14 +33,17 @@ mod state;
 mod storage;
 
 use crate::metrics::{setup_metrics_recorder, track_metrics};
+use crate::database::store::MetadataClient;
 use crate::handlers::{CreateUser};
-use crate::storage::azure_blob::AzureBlobClient;
-use crate::storage::gcs::GcsClient;
-use crate::storage::trait_storage::StorageClient;
+use crate::storage::trait_storage::{
+    StorageClient, StorageOpts, STORAGE_SIDE_ARGS, STORAGE_SIDE_OPTS,
+};
 use handlers::{
     add_doc_tags, add_link, download, upload, create_api_key, create_user, delete_api_key,
-    delete_doc, delete_doc_tags, delete_link, get_doc, get_doc_tags, get_links, get_user_id,
-    handler_404, health, list_api_keys, list_uploads, root,
+    delete_doc, delete_doc_tags, delete_link, direct_complete, direct_create, get_doc,
+    get_doc_tags, get_links, get_user_id, handler_404, health, list_api_keys, list_uploads,
+    reconcile, resumable_complete, resumable_create, resumable_delete, resumable_head,
+    resumable_options, resumable_patch, root,
 };
 use state::State;
 
@@ -73,6 +72,15 @@ static GLOBAL: Jemalloc = Jemalloc;
         handlers::get_doc_tags,
         handlers::download,
         handlers::upload,
+        handlers::resumable_options,
+        handlers::resumable_create,
+        handlers::resumable_head,
+        handlers::resumable_patch,
+        handlers::resumable_delete,
+        handlers::resumable_complete,
+        handlers::direct_create,
+        handlers::direct_complete,
+        handlers::reconcile,
     ),
     modifiers(&SecurityAddon),
     components(schemas(CreateUser))
@@ -91,14 +99,18 @@ impl Modify for SecurityAddon {
                         .build()
                 )
             );
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
         }
     }
 }
 
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let opts = Command::new(crate_name!())
+// Command line options, which tests parse as well to set up state
+fn command() -> Command<'static> {
+    Command::new(crate_name!())
         .version(crate_version!())
         .author("")
         .about(crate_name!())
@@ -156,13 +168,86 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .default_value("users")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("resumable")
+                .long("resumable")
+                .help("MongoDB Resumable Upload Sessions Collection")
+                .env("TACKD_MONGODB_RESUMABLE_COLLECTION")
+                .default_value("resumable")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("blobs")
+                .long("blobs")
+                .help("MongoDB Shared Objects Collection, used with --dedup")
+                .env("TACKD_MONGODB_BLOBS_COLLECTION")
+                .default_value("blobs")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("deletions")
+                .long("deletions")
+                .help("MongoDB Deletion Queue Collection, for storage deletions to be retried")
+                .env("TACKD_MONGODB_DELETIONS_COLLECTION")
+                .default_value("deletions")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("resumable_expires")
+                .long("resumable_expires")
+                .help("Set the seconds an unfinished resumable upload is kept")
+                .env("TACKD_RESUMABLE_EXPIRES")
+                .default_value("86400")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("segment_size")
+                .long("segment_size")
+                .help("Split uploads larger than this many bytes, after compression, into separate objects, 0 stores every upload as one object")
+                .env("TACKD_SEGMENT_SIZE")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("direct_uploads")
+                .long("direct_uploads")
+                .help("Allow clients to upload straight to storage through signed urls, bypassing the upload limit. Direct uploads are never encrypted or compressed.")
+                .env("TACKD_DIRECT_UPLOADS")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("direct_expires")
+                .long("direct_expires")
+                .help("Set the seconds a direct upload url is valid for, at most a week")
+                .env("TACKD_DIRECT_EXPIRES")
+                .default_value("3600")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("mongo")
                 .short('m')
                 .long("mongo")
                 .help("MongoDB connection url")
                 .env("TACKD_MONGODB_URL")
-                .required(true)
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("metadata")
+                .long("metadata")
+                .help("Set metadata store, sqlite if --sqlite is set and mongo otherwise")
+                .env("TACKD_METADATA")
+                .possible_values(["mongo", "sqlite"])
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("sqlite")
+                .long("sqlite")
+                .help("Store metadata in a SQLite database file at this path")
+                .env("TACKD_SQLITE_PATH")
+                .required(false)
                 .takes_value(true),
         )
         .arg(
@@ -171,9 +256,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .long("bucket")
                 .help("Bucket name")
                 .env("TACKD_BUCKET")
-                .required(true)
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("storage")
+                .long("storage")
+                .help("Set storage backend, detected from credentials if not set")
+                .env("TACKD_STORAGE")
+                .possible_values(["gcs", "azure", "s3", "local", "memory"])
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mirror")
+                .long("mirror")
+                .help("Mirror objects to a second storage backend")
+                .env("TACKD_MIRROR")
+                .possible_values(["gcs", "azure", "s3", "local", "memory"])
+                .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("mirror_bucket")
+                .long("mirror_bucket")
+                .help("Bucket name for the mirror, defaults to --bucket")
+                .env("TACKD_MIRROR_BUCKET")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mirror_async")
+                .long("mirror_async")
+                .help("Copy objects to the mirror in the background, after the primary write")
+                .env("TACKD_MIRROR_ASYNC")
+                .takes_value(false),
+        )
         .arg(
             Arg::new("limit")
                 .short('l')
@@ -205,9 +323,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             Arg::new("keys")
                 .short('k')
                 .long("keys")
-                .help("Set encryption keys")
+                .help("Set encryption keys, generated and kept in --data_dir if not set")
                 .env("TACKD_KEYS")
-                .required(true)
+                .required_unless_present("data_dir")
                 .takes_value(true),
         )
         .arg(
@@ -228,6 +346,94 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("azure_connection_string")
+                .long("azure_connection_string")
+                .help("Set Azure Storage connection string, instead of an account and access key")
+                .env("AZURE_STORAGE_CONNECTION_STRING")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("azure_sas_token")
+                .long("azure_sas_token")
+                .help("Set Azure Storage SAS token, scoped to the container or account")
+                .env("AZURE_STORAGE_SAS_TOKEN")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("azure_endpoint")
+                .long("azure_endpoint")
+                .help("Set Azure Blob Storage endpoint, for emulators such as Azurite")
+                .env("TACKD_AZURE_ENDPOINT")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("gcs_endpoint")
+                .long("gcs_endpoint")
+                .help("Set GCS endpoint, for emulators such as fake-gcs-server")
+                .env("STORAGE_EMULATOR_HOST")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("s3_endpoint")
+                .long("s3_endpoint")
+                .help("Set S3 endpoint, for S3-compatible services such as MinIO")
+                .env("TACKD_S3_ENDPOINT")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("s3_region")
+                .long("s3_region")
+                .help("Set S3 region")
+                .env("AWS_REGION")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("s3_access_key_id")
+                .long("s3_access_key_id")
+                .help("Set S3 access key id, defaults to the AWS credential chain")
+                .env("AWS_ACCESS_KEY_ID")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("s3_secret_access_key")
+                .long("s3_secret_access_key")
+                .help("Set S3 secret access key")
+                .env("AWS_SECRET_ACCESS_KEY")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("s3_path_style")
+                .long("s3_path_style")
+                .help("Use path-style S3 addressing, required by most S3-compatible services")
+                .env("TACKD_S3_PATH_STYLE")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("storage_path")
+                .long("storage_path")
+                .help("Store objects on local disk under this directory")
+                .env("TACKD_STORAGE_PATH")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("data_dir")
+                .long("data_dir")
+                .help("Keep metadata, objects and encryption keys under this directory, using SQLite and local storage unless set otherwise")
+                .env("TACKD_DATA_DIR")
+                .required(false)
+                .takes_value(true),
+        )
         .arg(
             Arg::new("ignore_link_key")
                 .short('i')
@@ -246,7 +452,196 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .required(false)
                 .takes_value(false),
         )
-        .get_matches();
+        .arg(
+            Arg::new("reconcile_interval")
+                .long("reconcile_interval")
+                .help("Set the seconds between storage reconciliations, 0 disables them")
+                .env("TACKD_RECONCILE_INTERVAL")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("reconcile_repair")
+                .long("reconcile_repair")
+                .help("Repair discrepancies found by scheduled reconciliations, requires --admin_token")
+                .env("TACKD_RECONCILE_REPAIR")
+                .required(false)
+                .requires("admin_token")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("admin_token")
+                .long("admin_token")
+                .help("Bearer token for admin endpoints")
+                .env("TACKD_ADMIN_TOKEN")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("admin_port")
+                .long("admin_port")
+                .help("Serve admin endpoints on their own port, they are disabled if not set. Requires --admin_token")
+                .env("TACKD_ADMIN_PORT")
+                .required(false)
+                .requires("admin_token")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("compress")
+                .long("compress")
+                .help("Compress data before encrypting and committing to object storage")
+                .env("TACKD_COMPRESS")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("dedup")
+                .long("dedup")
+                .help("Store identical unencrypted uploads as a single object")
+                .env("TACKD_DEDUP")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("opaque_keys")
+                .long("opaque_keys")
+                .help("Store objects under a keyed hash of the upload id, without identifying metadata")
+                .env("TACKD_OPAQUE_KEYS")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("key_shards")
+                .long("key_shards")
+                .help("Nest object keys under this many levels of two character prefixes")
+                .env("TACKD_KEY_SHARDS")
+                .possible_values(["0", "1", "2", "3", "4"])
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("storage_budget")
+                .long("storage_budget")
+                .help("Set the max bytes held in storage by active uploads, 0 for no limit")
+                .env("TACKD_STORAGE_BUDGET")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("budget_policy")
+                .long("budget_policy")
+                .help("Reject uploads over the storage budget, or evict the soonest expiring or oldest uploads")
+                .env("TACKD_BUDGET_POLICY")
+                .possible_values(["reject", "expiring", "oldest"])
+                .default_value("reject")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("expiry")
+                .long("expiry")
+                .help("Remove expired uploads in the cleanup that downloads trigger, or through a TTL index on the metadata store")
+                .env("TACKD_EXPIRY")
+                .possible_values(["cleanup", "ttl"])
+                .default_value("cleanup")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("purge_after")
+                .long("purge_after")
+                .help("Set the seconds deleted and expired uploads are kept before being purged, 0 keeps them forever")
+                .env("TACKD_PURGE_AFTER")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("purge_mode")
+                .long("purge_mode")
+                .help("Remove purged uploads entirely, or keep a tombstone with only their id, owner and dates")
+                .env("TACKD_PURGE_MODE")
+                .possible_values(["delete", "tombstone"])
+                .default_value("delete")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("redirect_downloads")
+                .long("redirect_downloads")
+                .help("Redirect downloads of unencrypted uploads to signed storage urls")
+                .env("TACKD_REDIRECT_DOWNLOADS")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("redirect_expires")
+                .long("redirect_expires")
+                .help("Set the seconds signed download urls stay valid, up to a week")
+                .env("TACKD_REDIRECT_EXPIRES")
+                .default_value("300")
+                .takes_value(true),
+        )
+        .subcommand(
+            Command::new("migrate-storage")
+                .about("Copy the objects of all active uploads to another storage backend")
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .help("Storage backend to copy from")
+                        .possible_values(["gcs", "azure", "s3", "local", "memory"])
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .help("Storage backend to copy to")
+                        .possible_values(["gcs", "azure", "s3", "local", "memory"])
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("from_bucket")
+                        .long("from_bucket")
+                        .help("Bucket name to copy from, defaults to --bucket")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("to_bucket")
+                        .long("to_bucket")
+                        .help("Bucket name to copy to, defaults to --bucket")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .help("Number of objects to copy at once")
+                        .default_value("4")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("migrations")
+                        .long("migrations")
+                        .help("MongoDB Migration Progress Collection")
+                        .env("TACKD_MONGODB_MIGRATIONS_COLLECTION")
+                        .default_value("migrations")
+                        .takes_value(true),
+                )
+                .args(migrate_side_args()),
+        )
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let opts = command().get_matches();
+    // The store decides this rather than a single flag, so check it once the arguments are parsed
+    if opts.value_of("expiry") == Some("ttl") && MetadataClient::kind(&opts) == "sqlite" {
+        command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--expiry ttl needs the mongo metadata store",
+            )
+            .exit();
+    }
 
     // Initialize log Builder
     Builder::new()
@@ -276,38 +671,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         10485760
     });
 
-    // Create mongo client
-    let client_options = ClientOptions::parse(opts.value_of("mongo").unwrap()).await?;
-    let mongo_client = Client::with_options(client_options)?;
-    if let Err(e) = mongo_client.list_database_names(None, None).await {
-        panic!("{}", e);
-    };
+    // Embedded mode keeps everything under one directory
+    if let Some(dir) = opts.value_of("data_dir") {
+        log::info!("\"Using data directory {}\"", dir);
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // Connect to the metadata store
+    let metadata = MetadataClient::from_opts(&opts).await?;
+
+    // Copy objects between backends instead of serving
+    if let Some(("migrate-storage", migrate_opts)) = opts.subcommand() {
+        return migrate_storage(&opts, migrate_opts, metadata).await;
+    }
 
     // Ensure that we can talk to storage
-    let storage_client = if std::env::var("SERVICE_ACCOUNT_JSON").is_ok()
-        || std::env::var("GOOGLE_APPLICATION_CREDENTIALS").is_ok()
-    {
-        let gcs_client = cloud_storage::Client::default();
-        StorageClient::GcsClient(GcsClient::new(opts.value_of("bucket").unwrap(), gcs_client))
-    } else if opts.value_of("azure_storage_account").is_some()
-        && opts.value_of("azure_storage_access_key").is_some()
-    {
-        let account = opts
-            .value_of("azure_storage_account")
-            .expect("Set env variable AZURE_STORAGE_ACCOUNT first");
-        let access_key = opts
-            .value_of("azure_storage_access_key")
-            .expect("Set env variable AZURE_STORAGE_ACCESS_KEY first!");
-        let storage_credentials =
-            StorageCredentials::Key(account.to_string(), access_key.to_string());
-        let service_client = BlobServiceClient::new(account, storage_credentials);
-        StorageClient::AzureBlobClient(AzureBlobClient::new(
-            opts.value_of("bucket").unwrap(),
-            service_client,
-        ))
-    } else {
-        panic!("No storage credentials found!");
-    };
+    let storage_client = StorageClient::from_opts(opts.value_of("storage"), &opts).await?;
 
     // This takes too long to startup
     //    gcs_client
@@ -316,7 +695,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     //        .await?;
 
     // Create state for axum
-    let mut state = State::new(opts.clone(), mongo_client, storage_client).await?;
+    let mut state = State::new(opts.clone(), metadata, storage_client).await?;
     state.init().await?;
 
     // Create prometheus handle
@@ -339,7 +718,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .route("/api/v1/uploads/:id/links", put(add_link).get(get_links))
         .route("/api/v1/uploads/:id/links/:link", delete(delete_link))
         .route("/health", get(health))
-        .route("/upload", post(upload));
+        .route("/upload", post(upload))
+        .route(
+            "/api/v1/resumable",
+            post(resumable_create).options(resumable_options),
+        )
+        .route(
+            "/api/v1/resumable/:id",
+            head(resumable_head)
+                .patch(resumable_patch)
+                .delete(resumable_delete),
+        )
+        .route("/api/v1/resumable/:id/complete", post(resumable_complete))
+        .route("/api/v1/direct", post(direct_create))
+        .route("/api/v1/direct/:id/complete", post(direct_complete));
 
     // These should NOT be authenticated through api keys
     let not_authenticated = Router::new()
@@ -359,14 +751,94 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .layer(DefaultBodyLimit::disable())
         .layer(RequestBodyLimitLayer::new(limit))
         .fallback(handler_404)
-        .layer(Extension(state));
+        .layer(Extension(state.clone()));
+
+    // Admin endpoints act on the whole deployment, so they are kept off the public listener
+    let admin = match opts.value_of("admin_port") {
+        Some(admin_port) => {
+            let admin_port: u16 = admin_port.parse()?;
+            let admin_app = Router::new()
+                .route("/api/v1/admin/reconcile", post(reconcile))
+                .layer(TraceLayer::new_for_http())
+                .fallback(handler_404)
+                .layer(Extension(state));
+            let admin_addr = SocketAddr::from(([0, 0, 0, 0], admin_port));
+            log::info!("\"Serving admin endpoints on {}\"", admin_addr);
+            Some(axum::Server::bind(&admin_addr).serve(admin_app.into_make_service()))
+        }
+        None => None,
+    };
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     log::info!("\"Listening on {}\"", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    let server = axum::Server::bind(&addr).serve(app.into_make_service());
+    match admin {
+        Some(admin) => {
+            tokio::try_join!(server, admin)?;
+        }
+        None => server.await?,
+    }
 
     Ok(())
 }
 
+
+// The storage options once more for each side of migrate-storage, such as --from_storage_path and
+// --to_storage_path, so the two sides can use different accounts or paths
+fn migrate_side_args() -> Vec<Arg<'static>> {
+    STORAGE_SIDE_ARGS
+        .iter()
+        .map(|&(id, help, takes_value)| {
+            Arg::new(id)
+                .long(id)
+                .help(help)
+                .required(false)
+                .takes_value(takes_value)
+        })
+        .collect()
+}
+
+async fn migrate_storage(
+    opts: &ArgMatches,
+    migrate_opts: &ArgMatches,
+    metadata: MetadataClient,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let from = migrate_opts.value_of("from").unwrap();
+    let to = migrate_opts.value_of("to").unwrap();
+    let from_bucket = migrate_opts.value_of("from_bucket").or(opts.value_of("bucket"));
+    let to_bucket = migrate_opts.value_of("to_bucket").or(opts.value_of("bucket"));
+    let from_opts = StorageOpts::side(opts, migrate_opts, "from");
+    let to_opts = StorageOpts::side(opts, migrate_opts, "to");
+    let same_options = STORAGE_SIDE_OPTS.iter().all(|(name, _)| {
+        from_opts.value_of(name) == to_opts.value_of(name)
+            && from_opts.is_present(name) == to_opts.is_present(name)
+    });
+    if from == to && from_bucket == to_bucket && same_options {
+        return Err("--from and --to point at the same storage".into());
+    }
+    let concurrency: usize = migrate_opts.value_of("concurrency").unwrap().parse()?;
+
+    let source = StorageClient::build(from, from_bucket, from_opts).await?;
+    let destination = StorageClient::build(to, to_bucket, to_opts).await?;
+    let target = format!("{}/{}", to, to_bucket.unwrap_or_default());
+
+    let state = State::new(opts.clone(), metadata, source).await?;
+    let report = state
+        .migrate_storage(
+            &destination,
+            &target,
+            migrate_opts.value_of("migrations").unwrap(),
+            concurrency,
+        )
+        .await?;
+    log::info!("{}", serde_json::to_string(&report)?);
+
+    if !report.failed.is_empty() {
+        return Err(format!(
+            "{} objects failed to migrate, run again to retry them",
+            report.failed.len()
+        )
+        .into());
+    }
+    Ok(())
+}
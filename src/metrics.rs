@@ -36,3 +36,55 @@ pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> impl IntoRespon
 
     response
 }
+
+// Results of the last storage reconciliation
+pub fn track_reconcile(objects: usize, orphaned: usize, missing: usize, repaired: usize) {
+    metrics::gauge!("storage_objects", objects as f64);
+    metrics::gauge!("storage_orphaned_objects", orphaned as f64);
+    metrics::gauge!("storage_missing_objects", missing as f64);
+    metrics::counter!("storage_reconcile_repairs_total", repaired as u64);
+}
+
+// Bytes held by active uploads, against the configured budget
+pub fn track_storage_usage(used: usize, budget: usize) {
+    metrics::gauge!("storage_bytes_used", used as f64);
+    metrics::gauge!("storage_bytes_budget", budget as f64);
+}
+
+pub fn track_evictions(evicted: usize) {
+    metrics::counter!("storage_evictions_total", evicted as u64);
+}
+
+pub fn track_budget_rejection() {
+    metrics::increment_counter!("storage_budget_rejections_total");
+}
+
+// Storage deletions that failed and were queued to be retried
+pub fn track_deletion_queued() {
+    metrics::increment_counter!("storage_deletions_queued_total");
+}
+
+pub fn track_deletion_retry(deleted: bool) {
+    let labels = [("result", if deleted { "deleted" } else { "failed" })];
+    metrics::increment_counter!("storage_deletion_retries_total", &labels);
+}
+
+pub fn track_deletion_dead() {
+    metrics::increment_counter!("storage_deletions_dead_total");
+}
+
+// Inactive uploads purged from the metadata store, or that couldn't be
+pub fn track_purge(purged: usize, failed: usize) {
+    metrics::counter!("uploads_purged_total", purged as u64);
+    metrics::counter!("uploads_purge_failures_total", failed as u64);
+}
+
+// Uploads removed by the metadata store's TTL index whose objects were deleted
+pub fn track_ttl_expiry() {
+    metrics::increment_counter!("uploads_ttl_expired_total");
+}
+
+pub fn track_deletion_queue(pending: usize, dead: usize) {
+    metrics::gauge!("storage_deletion_queue", pending as f64, "status" => "pending");
+    metrics::gauge!("storage_deletion_queue", dead as f64, "status" => "dead");
+}
@@ -1,34 +1,86 @@
 use axum::body::Bytes;
 use axum::extract::Query;
-use blake2::{Blake2s256, Digest};
-use bson::{doc, to_document, Document};
+use blake2::digest::Mac;
+use blake2::{Blake2s256, Blake2sMac256, Digest};
 use chrono::{Duration, Utc};
 use clap::ArgMatches;
+use futures::future;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use hex::encode;
 use hyper::HeaderMap;
-use mongodb::options::{FindOptions, IndexOptions};
-use mongodb::IndexModel;
+use rand::distributions::{Alphanumeric, DistString};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use std::collections::HashMap;
 
+use crate::data::{Data, CHUNK_PREFIX_SIZE};
+use crate::database::deletions::{
+    Deletion, DELETION_DEAD, DELETION_MAX_ATTEMPTS, DELETION_PENDING,
+};
 use crate::database::links::{Link, LinkScrubbed, NewLinkResult};
-use crate::database::mongo::MongoClient;
+use crate::database::migrations::Migration;
 //use crate::database::secret::{Secret};
-use crate::database::metadata::{MetaData, MetaDataPayload, MetaDataPublic};
+use crate::database::metadata::{
+    Direct, MetaData, MetaDataPayload, MetaDataPublic, Size, Tombstone,
+};
+use crate::database::resumable::{UploadPart, UploadSession};
+use crate::database::store::{Eviction, MetadataClient, MetadataStore, Page};
 use crate::database::users::{ApiKey, ApiKeyBrief, CurrentUser, UsersAdmin};
 use crate::error::Error as RestError;
 use crate::handlers::QueriesSet;
-use crate::storage::trait_storage::{Storage, StorageClient};
+use crate::helpers::RangeRequest;
+use crate::storage::segments::{self, SEGMENT_CONTENT_TYPE};
+use crate::storage::trait_storage::{ObjectStream, SignedUpload, Storage, StorageClient};
 
 type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 
+// Objects younger than this may belong to uploads that haven't saved their document yet
+const RECONCILE_GRACE_SECONDS: i64 = 3600;
+
+// Objects and uploads are reconciled this many at a time, so neither is held in memory whole
+const RECONCILE_PAGE_SIZE: usize = 1000;
+
+// Uploads whose objects are looked up in storage at once
+const RECONCILE_CONCURRENCY: usize = 16;
+
+// Content type of resumable upload parts in storage
+const PART_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+// Log migration progress every this many objects
+const MIGRATION_PROGRESS_INTERVAL: usize = 100;
+
+// Uploads fetched at a time when looking for ones to evict
+const EVICTION_BATCH_SIZE: i64 = 100;
+
+// Inactive uploads purged by each cleanup at most
+const PURGE_BATCH_SIZE: i64 = 1000;
+
+// Longest lifetime storage backends accept for signed urls
+const SIGNED_URL_MAX_SECONDS: i64 = 604800;
+
+// Encryption keys file used when --keys isn't set and state is kept in --data_dir
+const DATA_DIR_KEYS: &str = "keys.json";
+
+// The instance handling uploads removed by the TTL index renews its lock this often, and another
+// instance takes over once the lock hasn't been renewed for a minute
+const EXPIRY_RENEW_SECONDS: u64 = 10;
+const EXPIRY_LOCK_SECONDS: i64 = 60;
+
+// Time allowed after a direct upload url expires for a write still in flight to finish and be
+// completed, before the upload is treated as abandoned
+const DIRECT_COMPLETE_GRACE_SECONDS: i64 = 3600;
+
+// Appended to a direct upload's id to name the object its client writes to
+const DIRECT_STAGING_SUFFIX: &str = ".direct";
+
 #[derive(Clone, Debug)]
 pub struct State {
     pub configs: Configs,
-    pub db: MongoClient,
+    pub metadata: MetadataClient,
     pub storage: StorageClient,
     pub users_admin: UsersAdmin,
     pub last_cleanup: Arc<Mutex<i64>>,
@@ -57,6 +109,76 @@ pub struct SecretSaved {
     pub tags: Option<Vec<String>>,
 }
 
+pub struct Download {
+    pub data: ObjectStream,
+    // Signed storage url to send the client to, instead of streaming the data
+    pub redirect: Option<String>,
+    pub content_type: String,
+    // Full size of the data, and the inclusive range being sent when only part of it was requested
+    pub size: u64,
+    pub range: Option<(u64, u64)>,
+    pub etag: String,
+    pub last_modified: chrono::DateTime<Utc>,
+}
+
+// Tracks a download as it streams, settling its read accounting when the response ends or is dropped
+struct ReadGuard {
+    state: State,
+    doc_id: String,
+    link_id: String,
+    max_reads: i64,
+    reserved: u64,
+    length: u64,
+    delivered: u64,
+    ended: bool,
+    failed: bool,
+    reaches_end: bool,
+}
+
+impl ReadGuard {
+    fn wrap(self, data: ObjectStream) -> ObjectStream {
+        stream::unfold((data, self), |(mut data, mut guard)| async move {
+            match data.next().await {
+                Some(Ok(bytes)) => {
+                    guard.delivered += bytes.len() as u64;
+                    Some((Ok(bytes), (data, guard)))
+                }
+                Some(Err(e)) => {
+                    guard.failed = true;
+                    Some((Err(e), (data, guard)))
+                }
+                None => {
+                    guard.ended = true;
+                    drop(guard);
+                    None
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let doc_id = std::mem::take(&mut self.doc_id);
+        let link_id = std::mem::take(&mut self.link_id);
+        let max_reads = self.max_reads;
+        // Count the read once everything was sent, even if the stream was not polled to its end
+        let sent = self.ended || (self.length > 0 && self.delivered >= self.length);
+        let completed = self.reaches_end && sent && !self.failed;
+        let refund = self.reserved.saturating_sub(self.delivered);
+        tokio::spawn(async move {
+            if let Err(e) = state
+                .finish_read(&doc_id, &link_id, max_reads, refund, completed)
+                .await
+            {
+                log::error!("\"Unable to record read of {}: {}\"", doc_id, e);
+            }
+        });
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SetResult {
     pub url: String,
@@ -78,24 +200,129 @@ pub struct MetaDataInfo {
     pub pwd: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<Vec<String>>,
+    pub bytes: Size,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DirectUpload {
+    // Document id, used to complete the upload
+    pub id: String,
+    // Where the upload can be downloaded from once it has been completed
+    pub url: String,
+    pub data: DataInfo,
+    // Signed request to PUT the object with
+    pub upload: SignedUpload,
+    pub complete_by: chrono::DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    pub objects: usize,
+    pub documents: usize,
+    // Objects in storage that nothing refers to
+    pub orphaned: Vec<String>,
+    // Active uploads whose object is missing from storage
+    pub missing: Vec<String>,
+    pub repaired: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub objects: usize,
+    pub migrated: usize,
+    // Objects already copied by an earlier run
+    pub skipped: usize,
+    // Objects deleted from the source since the run started, usually read one-time uploads
+    pub gone: usize,
+    pub failed: Vec<String>,
+    pub bytes: usize,
+}
+
+// An object to copy, along with what it was stored with
+struct MigrationObject {
+    id: String,
+    content_type: String,
+    metadata: HashMap<String, String>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Configs {
     pub url: String,
     pub database: String,
+    pub limit: usize,
     pub retention: i64,
     pub reads: i64,
     pub ignore_link_key: bool,
     pub encrypt_data: bool,
-    pub collection_uploads: String,
-    pub collection_admin: String,
-    pub collection_users: String,
+    pub resumable_expires: i64,
+    pub dedup: bool,
+    pub compress: bool,
+    pub reconcile_interval: i64,
+    pub reconcile_repair: bool,
+    pub admin_token: Option<String>,
+    pub opaque_keys: bool,
+    pub key_shards: usize,
+    pub storage_budget: usize,
+    pub budget_policy: String,
+    pub redirect_downloads: bool,
+    pub redirect_expires: i64,
+    pub segment_size: usize,
+    pub direct_uploads: bool,
+    pub direct_expires: i64,
+    pub expiry: String,
+    pub purge_after: i64,
+    pub purge_mode: String,
+    // Identifies this instance when it holds a lock
+    pub instance: String,
     pub gcs_bucket: String,
     pub keys: Keys,
 }
 
 impl Keys {
+    // Keys passed with --keys, otherwise the ones kept in the data directory, which are generated on
+    // first start
+    pub fn from_opts(opts: &ArgMatches) -> BoxResult<Keys> {
+        if let Some(keys) = opts.value_of("keys") {
+            return Ok(serde_json::from_str(keys)?);
+        }
+        let dir = opts.value_of("data_dir").expect("Set --keys or --data_dir");
+        let path = Path::new(dir).join(DATA_DIR_KEYS);
+        if path.exists() {
+            return Ok(serde_json::from_str(&std::fs::read_to_string(&path)?)?);
+        }
+
+        log::warn!(
+            "\"Generating encryption key in {}, uploads can't be read without it\"",
+            path.display()
+        );
+        let keys = Keys {
+            keys: vec![Key {
+                ver: 1,
+                key: Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+            }],
+        };
+
+        // Created readable only by the owner, so the key is never briefly exposed. Another
+        // instance sharing the directory may have just written its own.
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = match options.open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Ok(serde_json::from_str(&std::fs::read_to_string(&path)?)?);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        std::io::Write::write_all(&mut file, serde_json::to_string(&keys)?.as_bytes())?;
+        file.sync_all()?;
+        Ok(keys)
+    }
+
     pub fn latest_key(&self) -> Key {
         self.keys.iter().max_by_key(|x| &x.ver).unwrap().clone()
     }
@@ -105,6 +332,25 @@ impl Keys {
     }
 }
 
+// Personalization of the secret opaque storage keys are hashed with, keeping it apart from anything
+// else derived from the encryption keys
+const OBJECT_KEY_PERSONAL: &[u8] = b"tackdobj";
+
+impl Key {
+    // Secret for hashing opaque storage keys, derived from the encryption key so the key itself is
+    // never used for anything but encryption
+    pub fn object_key_secret(&self) -> Vec<u8> {
+        let mut kdf = Blake2sMac256::new_with_salt_and_personal(
+            &Blake2s256::digest(self.key.as_bytes()),
+            &[],
+            OBJECT_KEY_PERSONAL,
+        )
+        .expect("Blake2s accepts 32 byte keys");
+        kdf.update(b"object keys");
+        kdf.finalize().into_bytes().to_vec()
+    }
+}
+
 pub fn hash(str: &str) -> String {
     let mut hasher = Blake2s256::new();
     hasher.update(str.as_bytes());
@@ -114,30 +360,42 @@ pub fn hash(str: &str) -> String {
 impl State {
     pub async fn new(
         opts: ArgMatches,
-        mongo_client: mongodb::Client,
+        metadata: MetadataClient,
         storage_client: StorageClient,
     ) -> BoxResult<Self> {
         Ok(State {
             configs: Configs {
                 url: opts.value_of("url").unwrap().to_string(),
                 database: opts.value_of("database").unwrap().to_string(),
+                limit: opts.value_of("limit").unwrap().parse().unwrap_or(10485760),
                 retention: opts.value_of("retention").unwrap().parse()?,
                 reads: opts.value_of("reads").unwrap().parse()?,
                 ignore_link_key: opts.is_present("ignore_link_key"),
                 encrypt_data: opts.is_present("encrypt_data"),
-                gcs_bucket: opts.value_of("bucket").unwrap().to_string(),
-                collection_uploads: opts.value_of("collection").unwrap().to_string(),
-                collection_admin: opts.value_of("admin").unwrap().to_string(),
-                collection_users: opts.value_of("users").unwrap().to_string(),
-                keys: serde_json::from_str(opts.value_of("keys").unwrap())?,
+                gcs_bucket: opts.value_of("bucket").unwrap_or_default().to_string(),
+                resumable_expires: opts.value_of("resumable_expires").unwrap().parse()?,
+                dedup: opts.is_present("dedup"),
+                compress: opts.is_present("compress"),
+                reconcile_interval: opts.value_of("reconcile_interval").unwrap().parse()?,
+                reconcile_repair: opts.is_present("reconcile_repair"),
+                admin_token: opts.value_of("admin_token").map(|t| t.to_owned()),
+                opaque_keys: opts.is_present("opaque_keys"),
+                key_shards: opts.value_of("key_shards").unwrap().parse()?,
+                storage_budget: opts.value_of("storage_budget").unwrap().parse()?,
+                budget_policy: opts.value_of("budget_policy").unwrap().to_string(),
+                redirect_downloads: opts.is_present("redirect_downloads"),
+                redirect_expires: opts.value_of("redirect_expires").unwrap().parse()?,
+                segment_size: opts.value_of("segment_size").unwrap().parse()?,
+                direct_uploads: opts.is_present("direct_uploads"),
+                direct_expires: opts.value_of("direct_expires").unwrap().parse()?,
+                expiry: opts.value_of("expiry").unwrap().to_string(),
+                purge_after: opts.value_of("purge_after").unwrap().parse()?,
+                purge_mode: opts.value_of("purge_mode").unwrap().to_string(),
+                instance: uuid::Uuid::new_v4().to_string(),
+                keys: Keys::from_opts(&opts)?,
             },
-            users_admin: UsersAdmin::new(
-                opts.value_of("database").unwrap(),
-                opts.value_of("users").unwrap(),
-                mongo_client.clone(),
-            )
-            .await?,
-            db: MongoClient::new(mongo_client.clone(), opts.value_of("database").unwrap()),
+            users_admin: UsersAdmin::new(metadata.clone()),
+            metadata,
             storage: storage_client,
             last_cleanup: Arc::new(Mutex::new(Utc::now().timestamp())),
         })
@@ -145,26 +403,18 @@ impl State {
 
     pub async fn increment(&self, doc_id: &str, link_id: &str) -> Result<MetaData, RestError> {
         log::debug!("Attempting to increment hit counter on {}", doc_id);
-        let filter = doc! {"id": doc_id, "active": true, "links.id": link_id};
-        let update = doc! { "$inc": { "lifecycle.current.reads": 1, "links.$.reads": 1 } };
-        self.db
-            .find_one_and_update::<MetaData>(&self.configs.collection_uploads, filter, update, None)
-            .await
+        self.metadata.record_read(doc_id, link_id).await
     }
 
     pub async fn delete(&self, id: &str) -> Result<(), RestError> {
         log::info!("\"Deactivating {} from database\"", &id);
 
-        let filter = doc! {"id": id, "active": true};
-        let update = doc! {"$set": {"active": false }};
-
-        // Set mongo doc to active=false
-        self.db
-            .find_one_and_update::<Document>(&self.configs.collection_uploads, filter, update, None)
-            .await?;
+        // Set doc to active=false
+        let metadata = self.metadata.deactivate_upload(id).await?;
+        self.release_budget(metadata.meta.bytes.stored).await;
 
         // Delete object
-        self.storage.delete_object(id).await?;
+        self.release_object(&metadata).await?;
 
         Ok(())
     }
@@ -174,23 +424,20 @@ impl State {
         link_id: &str,
         key: Option<&String>,
         password: Option<&String>,
-    ) -> Result<(Vec<u8>, String), RestError> {
+        range: Option<RangeRequest>,
+    ) -> Result<Download, RestError> {
         // Kick off cleanup
         self.cleanup().await?;
 
-        // Get doc from mongo
-        let filter = doc! {"links.id": link_id, "active": true };
-        let secret = self
-            .db
-            .find_one::<MetaData>(&self.configs.collection_uploads, filter, None)
-            .await?;
+        // Get doc from the metadata store
+        let secret = self.metadata.find_upload_by_link(link_id).await?;
 
         // Compare password hash
-        if let Some(pwd_hash) = secret.facts.pwd {
+        if let Some(pwd_hash) = secret.facts.pwd.as_ref() {
             match password {
                 Some(p) => {
                     let password_hash = hash(p);
-                    if password_hash != pwd_hash {
+                    if &password_hash != pwd_hash {
                         log::warn!("\"Note requested didn't match required password\"");
                         return Err(RestError::NotFound);
                     }
@@ -234,105 +481,343 @@ impl State {
             return Err(RestError::NotFound);
         }
 
-        // Get data from storage
-        let value = self.storage.fetch_object(&secret.id).await?;
+        // Plain objects can be downloaded straight from storage, once the checks above have passed
+        if let Some(url) = self.redirect_url(&secret).await {
+            self.increment(&secret.id, link_id).await?;
+            return Ok(Download {
+                data: stream::empty().boxed(),
+                redirect: Some(url),
+                content_type: secret.meta.content_type,
+                size: secret.meta.bytes.stored as u64,
+                range: None,
+                etag: format!("\"{}\"", hash(&secret.id)),
+                last_modified: secret.meta.created,
+            });
+        }
 
-        let value = if secret.facts.encryption.encrypted {
-            // Get decryption key, either from the mongo doc, or from the client
-            let decryption_key = if !secret.facts.encryption.managed {
-                log::debug!("Using client-provided decryption key");
-                match key {
-                    Some(k) => k.to_owned(),
-                    None => return Err(RestError::NotFound),
+        // Get decryption key, either from the mongo doc, or from the client
+        let decryption_key = if !secret.facts.encryption.encrypted {
+            None
+        } else if !secret.facts.encryption.managed {
+            log::debug!("Using client-provided decryption key");
+            match key {
+                Some(k) => Some(k.to_owned()),
+                None => return Err(RestError::NotFound),
+            }
+        } else {
+            let decrypt_key_ver = secret
+                .facts
+                .encryption
+                .version
+                .expect("Missing requiered encryption key version");
+            let decrypt_key = self
+                .configs
+                .keys
+                .get_ver(decrypt_key_ver)
+                .expect("error getting decryption key version from mongodoc");
+            let encrypted_key = secret
+                .facts
+                .encryption
+                .key
+                .clone()
+                .expect("Missing requiered encryption key");
+
+            // Decrypt encryption key
+            let secret_key = orion::aead::SecretKey::from_slice(decrypt_key.key.as_bytes())?;
+            match orion::aead::open(&secret_key, &encrypted_key) {
+                Ok(e) => {
+                    let key = std::str::from_utf8(&e)?;
+                    Some(key.to_owned())
                 }
-            } else {
-                let decrypt_key_ver = secret
-                    .facts
-                    .encryption
-                    .version
-                    .expect("Missing requiered encryption key version");
-                let decrypt_key = self
-                    .configs
-                    .keys
-                    .get_ver(decrypt_key_ver)
-                    .expect("error getting decryption key version from mongodoc");
-                let encrypted_key = secret
-                    .facts
-                    .encryption
-                    .key
-                    .expect("Missing requiered encryption key");
-
-                // Decrypt encryption key
-                let secret_key = orion::aead::SecretKey::from_slice(decrypt_key.key.as_bytes())?;
-                match orion::aead::open(&secret_key, &encrypted_key) {
-                    Ok(e) => {
-                        let key = std::str::from_utf8(&e)?;
-                        key.to_owned()
-                    }
+                Err(e) => {
+                    log::error!("\"Error decrypting encryption key: {}\"", e);
+                    return Err(RestError::NotFound);
+                }
+            }
+        };
+
+        // Documents encrypted without a chunk size were sealed as a single block, and have to be opened whole
+        let sealed = match decryption_key.as_ref() {
+            Some(k) if secret.facts.encryption.chunk_size.is_none() => {
+                let ciphertext: Vec<u8> = self
+                    .fetch_stored(&secret)
+                    .await?
+                    .map_ok(|b| b.to_vec())
+                    .try_concat()
+                    .await?;
+                let secret_key = orion::aead::SecretKey::from_slice(k.as_bytes())?;
+                match orion::aead::open(&secret_key, &ciphertext) {
+                    Ok(e) => Some(Bytes::from(e)),
                     Err(e) => {
-                        log::error!("\"Error decrypting encryption key: {}\"", e);
+                        log::error!("\"Error decrypting secret: {}\"", e);
                         return Err(RestError::NotFound);
                     }
                 }
-            };
-
-            // Decrypt data
-            let secret_key = orion::aead::SecretKey::from_slice(decryption_key.as_bytes())?;
-            match orion::aead::open(&secret_key, &value) {
-                Ok(e) => e,
-                Err(e) => {
-                    log::error!("\"Error decrypting secret: {}\"", e);
-                    return Err(RestError::NotFound);
-                }
             }
-        } else {
-            value
+            _ => None,
         };
 
-        // If key has been accessed the max number of times, then remove
-        if secret.lifecycle.max.reads > 0
-            && secret.lifecycle.current.reads + 1 >= secret.lifecycle.max.reads
-        {
-            // self.increment(&secret.id).await?;
-            self.delete(&secret.id).await?;
-            log::debug!("Preemptively deleting id, max expire_reads reached");
+        let size = match (&sealed, &decryption_key) {
+            (Some(s), _) => s.len(),
+            (None, _) if secret.facts.compression.is_some() => secret.meta.bytes.original,
+            (None, Some(_)) => match &secret.facts.segments {
+                Some(s) => s
+                    .iter()
+                    .map(|s| Data::plaintext_len(s.bytes as usize))
+                    .sum(),
+                None => Data::plaintext_len(secret.meta.bytes.stored),
+            },
+            (None, None) => secret.meta.bytes.stored,
+        } as u64;
+
+        // Ranges are ignored if the client's copy is out of date
+        let etag = format!("\"{}\"", hash(&secret.id));
+        let range = match range.filter(|r| r.applies(&etag, &secret.meta.created)) {
+            Some(r) => match r.spec.resolve(size) {
+                Some(r) => Some(r),
+                None => return Err(RestError::RangeNotSatisfiable(size)),
+            },
+            None => None,
+        };
+        let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+        let length = if size == 0 { 0 } else { end - start + 1 };
+
+        // Bytes are counted against the read budget up front, and anything not delivered is refunded
+        let reserved = if secret.lifecycle.max.reads > 0 {
+            self.reserve_read(&secret, length, size).await?;
+            length
         } else {
-            // Increment hit count
-            self.increment(&secret.id, link_id).await?;
+            0
+        };
+        let guard = ReadGuard {
+            state: self.clone(),
+            doc_id: secret.id.clone(),
+            link_id: link_id.to_owned(),
+            max_reads: secret.lifecycle.max.reads,
+            reserved,
+            length,
+            delivered: 0,
+            ended: false,
+            failed: false,
+            reaches_end: end + 1 >= size,
+        };
+
+        let value = match (sealed, decryption_key) {
+            (Some(plaintext), _) => {
+                let value = stream::once(future::ready(Ok(plaintext))).boxed();
+                Data::slice(value, start as usize, length as usize)
+            }
+            // Compressed data can only be read from the start, so ranges are cut after decompressing
+            (None, k) if secret.facts.compression.is_some() => {
+                let value = match k {
+                    Some(k) => self.fetch_decrypted(&secret, &k).await?,
+                    None => self.fetch_stored(&secret).await?,
+                };
+                let codec = secret.facts.compression.as_deref().unwrap_or_default();
+                Data::slice(
+                    Data::decompress_stream(codec, value)?,
+                    start as usize,
+                    length as usize,
+                )
+            }
+            (None, Some(k)) if range.is_some() => {
+                self.fetch_encrypted_range(&secret, &k, start, end).await?
+            }
+            (None, Some(k)) => self.fetch_decrypted(&secret, &k).await?,
+            (None, None) if range.is_some() => self.fetch_stored_range(&secret, start, end).await?,
+            (None, None) => self.fetch_stored(&secret).await?,
+        };
+
+        // Read the first chunk up front, so a bad key is still reported before the response starts
+        let value = match Data::prime(value).await {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("\"Error reading secret: {}\"", e);
+                return Err(RestError::NotFound);
+            }
         };
 
-        Ok((value, secret.meta.content_type))
+        Ok(Download {
+            data: guard.wrap(value),
+            redirect: None,
+            content_type: secret.meta.content_type,
+            size,
+            range,
+            etag,
+            last_modified: secret.meta.created,
+        })
+    }
+
+    // Signed url for an upload that storage can serve as is. Uploads with a read limit are always
+    // streamed, since a url could be reused until it expires, opaque objects lack their content
+    // type, and segmented uploads have no single object. Signing failures fall back to streaming.
+    async fn redirect_url(&self, secret: &MetaData) -> Option<String> {
+        if !self.configs.redirect_downloads
+            || self.configs.opaque_keys
+            || secret.facts.segments.is_some()
+            || secret.facts.encryption.encrypted
+            || secret.facts.compression.is_some()
+            || secret.lifecycle.max.reads > 0
+        {
+            return None;
+        }
+
+        // Don't let the url outlive the upload itself
+        let remaining = (secret.lifecycle.max.expires.timestamp_millis()
+            - Utc::now().timestamp_millis())
+            / 1000;
+        let seconds = self
+            .configs
+            .redirect_expires
+            .min(remaining)
+            .clamp(1, SIGNED_URL_MAX_SECONDS) as u64;
+        match self.storage.signed_url(secret.object_id(), seconds).await {
+            Ok(url) => url,
+            Err(e) => {
+                log::warn!("\"Unable to sign url for {}: {}\"", secret.id, e);
+                None
+            }
+        }
+    }
+
+    // Read an upload's stored data, from its object or from each of its segments in turn
+    async fn fetch_stored(&self, secret: &MetaData) -> Result<ObjectStream, RestError> {
+        match &secret.facts.segments {
+            Some(s) => Ok(segments::fetch(&self.storage, s, 0, u64::MAX)),
+            None => self.storage.fetch_object(secret.object_id()).await,
+        }
+    }
+
+    // Read and decrypt an upload's stored data
+    async fn fetch_decrypted(
+        &self,
+        secret: &MetaData,
+        key: &str,
+    ) -> Result<ObjectStream, RestError> {
+        // Encrypted segments are each sealed on their own
+        match &secret.facts.segments {
+            Some(s) => Ok(segments::fetch_sealed(&self.storage, s, key, 0, u64::MAX)),
+            None => Data::decrypt_stream(key, self.fetch_stored(secret).await?),
+        }
+    }
+
+    async fn fetch_stored_range(
+        &self,
+        secret: &MetaData,
+        start: u64,
+        end: u64,
+    ) -> Result<ObjectStream, RestError> {
+        match &secret.facts.segments {
+            Some(s) => Ok(segments::fetch(&self.storage, s, start, end)),
+            None => {
+                self.storage
+                    .fetch_range(secret.object_id(), start, end)
+                    .await
+            }
+        }
+    }
+
+    // Map a plaintext range onto the chunks holding it, then decrypt only those chunks
+    async fn fetch_encrypted_range(
+        &self,
+        secret: &MetaData,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<ObjectStream, RestError> {
+        if let Some(s) = &secret.facts.segments {
+            return Ok(segments::fetch_sealed(&self.storage, s, key, start, end));
+        }
+
+        let span = Data::chunk_span(secret.meta.bytes.stored, start, end);
+        let prefix: Vec<u8> = self
+            .fetch_stored_range(secret, 0, CHUNK_PREFIX_SIZE as u64 - 1)
+            .await?
+            .map_ok(|b| b.to_vec())
+            .try_concat()
+            .await?;
+
+        let ciphertext = self.fetch_stored_range(secret, span.from, span.to).await?;
+        let plaintext =
+            Data::decrypt_chunks(key, &prefix, span.first, span.reaches_end, ciphertext)?;
+        Ok(Data::slice(plaintext, span.skip, span.take))
+    }
+
+    // Reserve bytes against the read budget, so partial or concurrent reads can't exceed max reads worth of data
+    async fn reserve_read(
+        &self,
+        secret: &MetaData,
+        bytes: u64,
+        size: u64,
+    ) -> Result<(), RestError> {
+        let budget = secret.lifecycle.max.reads * size as i64;
+        match self
+            .metadata
+            .reserve_read_bytes(&secret.id, bytes as i64, budget)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(RestError::NotFound) => {
+                log::warn!("\"Read budget exhausted for {}\"", secret.id);
+                Err(RestError::NotFound)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Settle a read once its response has finished, counting it only if the end of the data was sent
+    async fn finish_read(
+        &self,
+        doc_id: &str,
+        link_id: &str,
+        max_reads: i64,
+        refund: u64,
+        completed: bool,
+    ) -> Result<(), RestError> {
+        if refund > 0 {
+            self.metadata
+                .refund_read_bytes(doc_id, refund as i64)
+                .await?;
+        }
+
+        if completed {
+            // Increment hit count, and remove once the max number of reads has been reached
+            let secret = self.increment(doc_id, link_id).await?;
+            if max_reads > 0 && secret.lifecycle.current.reads + 1 >= max_reads {
+                log::debug!("Deleting id, max expire_reads reached");
+                self.delete(doc_id).await?;
+            }
+        }
+        Ok(())
     }
 
     // Generate MetaData and Data from http post request, then persist in backing database and object storage
     pub async fn set(
         &mut self,
-        value: Bytes,
+        value: ObjectStream,
+        declared: Option<usize>,
         queries: &Query<QueriesSet>,
         headers: HeaderMap,
         current_user: CurrentUser,
     ) -> Result<SetResult, RestError> {
+        // Turn away uploads that can't fit before anything is written
+        let (reserved, room) = self.budget_room(declared).await?;
+
         // Generate MetaData doc and Data block
-        let metadata_payload = MetaData::create(
+        let metadata_payload = match MetaData::create(
             value,
             queries,
             headers,
             current_user.id,
             self.configs.clone(),
-        )?;
-
-        let results = SetResult {
-            url: metadata_payload.url.clone(),
-            data: DataInfo {
-                id: metadata_payload.metadata.links.0[0].id.clone(),
-                key: metadata_payload.key.clone(),
-            },
-            metadata: MetaDataInfo {
-                expire_seconds: metadata_payload.metadata.lifecycle.max.seconds,
-                expire_reads: metadata_payload.metadata.lifecycle.max.reads,
-                pwd: queries.pwd.is_some(),
-                tags: queries.tags.clone(),
-            },
+        )
+        .await
+        {
+            Ok(p) => p,
+            Err(e) => {
+                self.release_budget(reserved).await;
+                return Err(e);
+            }
         };
 
         log::debug!(
@@ -341,226 +826,431 @@ impl State {
             metadata_payload.metadata.lifecycle.max.reads
         );
 
-        self.insert_upload(metadata_payload).await?;
-        Ok(results)
+        // Sizes are only known once the data has been written
+        let url = metadata_payload.url.clone();
+        let key = metadata_payload.key.clone();
+        let metadata = self.insert_upload(metadata_payload, reserved, room).await?;
+
+        Ok(SetResult {
+            url,
+            data: DataInfo {
+                id: metadata.links.first().unwrap().id.clone(),
+                key,
+            },
+            metadata: MetaDataInfo {
+                expire_seconds: metadata.lifecycle.max.seconds,
+                expire_reads: metadata.lifecycle.max.reads,
+                pwd: queries.pwd.is_some(),
+                tags: queries.tags.clone(),
+                bytes: metadata.meta.bytes,
+            },
+        })
     }
 
+    // Write an upload's data and save its document. The bytes reserved for it in the budget are
+    // settled with its stored size, and given back if it fails.
     pub async fn insert_upload(
         &mut self,
-        metadata_payload: MetaDataPayload,
-    ) -> Result<String, RestError> {
-        log::debug!("here");
+        mut metadata_payload: MetaDataPayload,
+        reserved: usize,
+        room: Option<usize>,
+    ) -> Result<MetaData, RestError> {
+        metadata_payload.metadata.facts.object = self.object_key(&metadata_payload.metadata.id);
+        metadata_payload.metadata.facts.object_key_ver = self.object_key_ver();
+        let (content_type, metadata) = self.object_attributes(&metadata_payload.metadata);
+
+        // Encrypted objects are unique per upload, so only plaintext can be shared
+        let dedup = self.configs.dedup && !metadata_payload.metadata.facts.encryption.encrypted;
+        let hasher = Arc::new(std::sync::Mutex::new(Blake2s256::new()));
+        let data = match dedup {
+            true => Data::digest(metadata_payload.data, hasher.clone()),
+            false => metadata_payload.data,
+        };
 
-        // Add metadata to HashMap for object injection
-        let mut metadata = HashMap::new();
-        metadata.insert("filename".to_string(), metadata_payload.metadata.meta.filename.clone().unwrap_or("not specified".to_owned()));
-        metadata.insert("expires".to_string(), metadata_payload.metadata.lifecycle.max.expires.clone().to_string());
+        // Stop writing as soon as the upload outgrows the room left in the budget
+        let data = match room {
+            Some(room) => {
+                let written = AtomicUsize::new(0);
+                data.map(move |chunk| {
+                    let bytes = chunk?;
+                    if written.fetch_add(bytes.len(), Ordering::Relaxed) + bytes.len() > room {
+                        log::warn!("\"Rejecting upload, over the storage budget while writing\"");
+                        crate::metrics::track_budget_rejection();
+                        return Err(RestError::InsufficientStorage);
+                    }
+                    Ok(bytes)
+                })
+                .boxed()
+            }
+            None => data,
+        };
 
         log::debug!("inserting data into storage");
-        self.storage
-            .insert_object(
-                &metadata_payload.metadata.id,
-                metadata_payload.data,
-                &metadata_payload.metadata.meta.content_type,
-                &metadata
-            )
-            .await?;
-
-        log::debug!("inserting doc into database");
-        Ok(self
-            .db
-            .insert_one::<MetaData>(
-                &self.configs.collection_uploads,
-                metadata_payload.metadata,
-                None,
-            )
-            .await?
-            .links
-            .first()
-            .unwrap()
-            .id
-            .to_string())
-    }
-
-    pub async fn create_uploads_indexes(&mut self) -> Result<(), RestError> {
-        log::debug!("Creating upload collection indexes");
-        let mut indexes = Vec::new();
-        indexes.push(
-            IndexModel::builder()
-                .keys(doc! {"id":1, "active": 1})
-                .options(IndexOptions::builder().unique(true).build())
-                .build(),
-        );
-
-        indexes.push(
-            IndexModel::builder()
-                .keys(
-                    doc! {"active":1, "facts.owner": 1, "lifecycle.expires_at": 1, "meta.tags": 1},
+        let written = match self.configs.segment_size {
+            0 => self
+                .storage
+                .insert_object(
+                    metadata_payload.metadata.object_id(),
+                    data,
+                    &content_type,
+                    &metadata,
                 )
-                .build(),
-        );
-
-        self.db
-            .create_indexes(&self.configs.collection_uploads, indexes, None)
-            .await
-    }
-
-    pub async fn admin_init(&self) -> Result<(), RestError> {
-        // Create cleanup lock
-        let filter_lock = doc! {"name":"cleanup"};
+                .await
+                .map(|_| ()),
+            // Encrypted data was sealed a segment at a time, so it is cut where each one ends
+            segment_size => {
+                let encrypted = metadata_payload.metadata.facts.encryption.encrypted;
+                let stored_size = match encrypted {
+                    true => Data::sealed_len(segment_size),
+                    false => segment_size,
+                };
+                segments::insert(
+                    &mut self.storage,
+                    metadata_payload.metadata.object_id(),
+                    data,
+                    stored_size,
+                    &content_type,
+                    &metadata,
+                )
+                .await
+                .map(|s| metadata_payload.metadata.facts.segments = s)
+            }
+        };
+        if let Err(e) = written {
+            self.release_budget(reserved).await;
+            return Err(e);
+        }
+        metadata_payload.metadata.meta.bytes = Size {
+            original: metadata_payload.original.load(Ordering::Relaxed),
+            stored: metadata_payload.bytes.load(Ordering::Relaxed),
+        };
 
-        // Check if cleanup doc already exists, and create it if it does not
-        if self
-            .db
-            .find_one::<Document>(&self.configs.collection_admin, filter_lock, None)
+        // Settle with the size actually stored, taking the object back out if it doesn't fit
+        if let Err(e) = self
+            .settle_budget(reserved, metadata_payload.metadata.meta.bytes.stored)
             .await
-            .is_err()
         {
-            log::debug!("Cleanup lock doc does not exist, creating");
-            let cleanup_doc = doc! {"name":"cleanup", "active": false, "modified": Utc::now() };
-
-            self.db
-                .insert_one(&self.configs.collection_admin, cleanup_doc, None)
-                .await?;
-            return Ok(());
+            if let Err(e) = self.release_object(&metadata_payload.metadata).await {
+                log::error!(
+                    "\"Unable to delete rejected object {}: {}\"",
+                    metadata_payload.metadata.object_id(),
+                    e
+                );
+            }
+            return Err(e);
         }
 
-        // Ensure cleanup doc is not in a "failed" state
-        let filter_lock = doc! {"name":"cleanup", "active": true, "modified": { "$lt" : Utc::now() - Duration::minutes(5) } };
-        let update_lock = doc! {"$set": {"active": false, "modified": Utc::now() }};
-        if self
-            .db
-            .find_one_and_update::<Document>(
-                &self.configs.collection_admin,
-                filter_lock,
-                update_lock,
-                None,
-            )
-            .await
-            .is_err()
-        {
-            log::debug!("Cleanup doc already is correct");
-        };
+        // Segmented uploads are never shared, their objects are only ever read together
+        if dedup && metadata_payload.metadata.facts.segments.is_none() {
+            let digest = encode(std::mem::take(&mut *hasher.lock().unwrap()).finalize());
+            self.share_object(&mut metadata_payload.metadata, digest)
+                .await;
+        }
 
-        Ok(())
+        log::debug!("inserting doc into database");
+        let upload = metadata_payload.metadata;
+        match self.metadata.insert_upload(upload.clone()).await {
+            Ok(m) => Ok(m),
+            Err(e) => {
+                // Don't leave an object, or a reference to a shared one, behind
+                self.release_budget(upload.meta.bytes.stored).await;
+                if let Err(e) = self.release_object(&upload).await {
+                    log::error!("\"Unable to release object for {}: {}\"", &upload.id, e);
+                }
+                Err(e)
+            }
+        }
     }
 
-    pub async fn lock_timer(&self) -> Result<(), RestError> {
-        let mut last_cleanup = self.last_cleanup.lock().await;
-        let now = Utc::now().timestamp();
-        if now > *last_cleanup + 60 {
-            *last_cleanup = now;
-            Ok(())
-        } else {
-            log::debug!("\"Cleanup skipped, internal timer not at 60 seconds\"");
-            Err(RestError::CleanupNotRequired)
+    // Storage key for a document or part id, when it differs from the id. Opaque keys are a keyed
+    // hash of the id, so objects in the bucket can't be matched with the ids handed out to users.
+    // The hash is keyed with a secret derived from the encryption key version object_key_ver gives.
+    fn object_key(&self, id: &str) -> Option<String> {
+        if !self.configs.opaque_keys && self.configs.key_shards == 0 {
+            return None;
         }
-    }
+        let key = match self.configs.opaque_keys {
+            true => {
+                let secret = self.configs.keys.latest_key().object_key_secret();
+                let mut mac =
+                    Blake2sMac256::new_from_slice(&secret).expect("Blake2s accepts 32 byte keys");
+                mac.update(id.as_bytes());
+                encode(mac.finalize().into_bytes())
+            }
+            false => id.to_owned(),
+        };
 
-    pub async fn lock_cleanup(&self) -> Result<(), RestError> {
-        log::debug!("\"Attempting to lock cleanup doc\"");
-        let delay = Utc::now() - Duration::seconds(60);
-        let filter_lock = doc! {"active": false, "name":"cleanup", "modified": {"$lt": delay }};
-        let update_lock = doc! {"$set": {"active": true, "modified": Utc::now()}};
+        // Nest keys under two character prefixes, spreading them over storage partitions
+        let mut path: Vec<&str> = (0..self.configs.key_shards)
+            .filter_map(|i| key.get(i * 2..i * 2 + 2))
+            .collect();
+        path.push(&key);
+        Some(path.join("/"))
+    }
 
-        // Try to lock cleanup doc
-        if self
-            .db
-            .find_one_and_update::<Document>(
-                &self.configs.collection_admin,
-                filter_lock,
-                update_lock,
-                None,
-            )
-            .await
-            .is_err()
-        {
-            log::debug!("\"Cleanup not required at this time\"");
-            return Err(RestError::CleanupNotRequired);
-        };
-        Ok(())
+    // Version of the encryption key opaque storage keys are currently derived from
+    fn object_key_ver(&self) -> Option<u8> {
+        self.configs
+            .opaque_keys
+            .then(|| self.configs.keys.latest_key().ver)
     }
 
-    pub async fn unlock_cleanup(&self) -> Result<(), RestError> {
-        log::debug!("\"Freeing cleanup doc\"");
-        let filter_unlock = doc! {"active": true, "name":"cleanup"};
-        let update_unlock = doc! {"$set": {"active": false}};
+    // Content type and metadata to store an upload's object with. Opaque keys would be pointless if
+    // the object still carried the filename, so nothing identifying is stored with them.
+    fn object_attributes(&self, metadata: &MetaData) -> (String, HashMap<String, String>) {
+        match self.configs.opaque_keys {
+            true => ("application/octet-stream".to_owned(), HashMap::new()),
+            false => (
+                metadata.meta.content_type.clone(),
+                metadata.object_metadata(),
+            ),
+        }
+    }
 
-        // Unlock cleanup doc
-        if self
-            .db
-            .find_one_and_update::<Document>(
-                &self.configs.collection_admin,
-                filter_unlock,
-                update_unlock,
-                None,
-            )
+    // Point an upload at an existing object with the same digest, or register its own object to be shared
+    async fn share_object(&self, metadata: &mut MetaData, digest: String) {
+        match self
+            .metadata
+            .share_blob(&digest, metadata.object_id())
             .await
-            .is_err()
         {
-            log::error!("Unable to free cleanup doc");
-        };
-        Ok(())
-    }
-
-    pub async fn expired_ids(&self) -> Result<Vec<String>, RestError> {
-        // Search for docs that are expired here
-        let query = doc! {"active": true, "lifecycle.max.expires": {"$lt": Utc::now()}};
-        let find_options = FindOptions::builder()
-            .sort(doc! { "_id": -1 })
-            .limit(1000)
-            .build();
-
-        let res = self
-            .db
-            .find::<MetaData>(&self.configs.collection_uploads, query, Some(find_options))
-            .await?;
-        let result: Vec<String> = res.iter().map(|s| s.id.to_owned()).collect();
-        Ok(result)
+            Ok(blob) => {
+                if blob.object != metadata.object_id() {
+                    log::debug!("\"Upload {} shares object {}\"", &metadata.id, &blob.object);
+                    if let Err(e) = self.remove_object(metadata.object_id()).await {
+                        log::warn!(
+                            "\"Unable to delete duplicate object {}: {}\"",
+                            &metadata.id,
+                            e
+                        );
+                    }
+                    metadata.facts.object = Some(blob.object);
+                }
+                metadata.facts.digest = Some(digest);
+            }
+            // A blob whose last reference is being released can't be shared, so keep this upload's own copy
+            Err(_) => log::warn!(
+                "\"Unable to share object for {}, storing separately\"",
+                &metadata.id
+            ),
+        }
     }
 
-    pub async fn uploads_owned(
-        &self,
-        id: &str,
-        tags: Option<Vec<String>>,
-    ) -> Result<Vec<MetaDataPublic>, RestError> {
-        let query = match tags {
-            Some(t) => {
-                doc! {"active": true, "facts.owner": id, "lifecycle.max.expires": {"$gt": Utc::now()}, "meta.tags": { "$all": t } }
-            }
+    // Delete a document's object from storage, unless other documents still reference it
+    async fn release_object(&self, metadata: &MetaData) -> Result<(), RestError> {
+        let digest = match &metadata.facts.digest {
+            Some(d) => d,
             None => {
-                doc! {"active": true, "facts.owner": id, "lifecycle.max.expires": {"$gt": Utc::now()}}
+                for id in metadata.object_ids() {
+                    self.remove_object(&id).await?;
+                }
+                return Ok(());
             }
         };
 
-        let find_options = FindOptions::builder()
-            .sort(doc! { "_id": -1 })
-            .limit(1000)
-            .build();
+        let blob = match self.metadata.release_blob(digest, &metadata.id).await {
+            Ok(b) => b,
+            // Released by an earlier attempt, such as an expiry handled again after a restart
+            Err(RestError::NotFound) => {
+                log::debug!("\"Upload {} released its object already\"", &metadata.id);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if blob.refs > 0 {
+            log::debug!(
+                "\"Object {} is still referenced by {} uploads\"",
+                &blob.object,
+                blob.refs
+            );
+            return Ok(());
+        }
 
-        let res = self
-            .db
-            .find::<MetaData>(&self.configs.collection_uploads, query, Some(find_options))
-            .await?;
-        let result: Vec<MetaDataPublic> = res.iter().map(|s| s.to_json()).collect();
+        self.metadata.delete_blob(digest).await?;
+        self.remove_object(&blob.object).await
+    }
+
+    // Delete an object from storage, queueing it to be retried if storage fails
+    async fn remove_object(&self, id: &str) -> Result<(), RestError> {
+        match self.storage.delete_object(id).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::warn!("\"Unable to delete {}, queueing it for retry: {}\"", id, e);
+                self.queue_deletion(id, &e.to_string()).await
+            }
+        }
+    }
+
+    //
+    // Deletion Queue
+    //
+
+    async fn queue_deletion(&self, object: &str, error: &str) -> Result<(), RestError> {
+        self.metadata.queue_deletion(object, error).await?;
+        crate::metrics::track_deletion_queued();
+        Ok(())
+    }
+
+    // Retry queued deletions that are due, backing off further after each failure. Entries are
+    // dead-lettered once they run out of attempts, but are still retried now and then.
+    pub async fn retry_deletions(&self) -> Result<(), RestError> {
+        let due = self.metadata.due_deletions(1000).await?;
+
+        for deletion in due {
+            match self.storage.delete_object(&deletion.object).await {
+                Ok(_) => {
+                    log::info!(
+                        "\"Deleted {} after {} failed attempts\"",
+                        &deletion.object,
+                        deletion.attempts + 1
+                    );
+                    self.metadata.remove_deletion(&deletion.object).await?;
+                    crate::metrics::track_deletion_retry(true);
+                }
+                Err(e) => {
+                    let attempts = deletion.attempts + 1;
+                    let status = match attempts >= DELETION_MAX_ATTEMPTS {
+                        true => DELETION_DEAD,
+                        false => DELETION_PENDING,
+                    };
+                    if status == DELETION_DEAD && deletion.status != DELETION_DEAD {
+                        log::error!(
+                            "\"Giving up on deleting {} after {} attempts: {}\"",
+                            &deletion.object,
+                            attempts,
+                            e
+                        );
+                        crate::metrics::track_deletion_dead();
+                    }
+                    let retry = Deletion {
+                        status: status.to_owned(),
+                        attempts,
+                        next_attempt: (Utc::now() + Deletion::backoff(attempts)).into(),
+                        last_error: e.to_string(),
+                        ..deletion
+                    };
+                    self.metadata.reschedule_deletion(&retry).await?;
+                    crate::metrics::track_deletion_retry(false);
+                }
+            }
+        }
+
+        let (pending, dead) = self.metadata.deletion_counts().await?;
+        crate::metrics::track_deletion_queue(pending, dead);
+        Ok(())
+    }
+
+    //
+    // Purging
+    //
+
+    // Remove uploads that have been inactive for longer than the grace period, or reduce them to a
+    // tombstone, so their passwords, keys and uploader details don't outlive them
+    pub async fn purge_inactive(&self) -> Result<(), RestError> {
+        if self.configs.purge_after <= 0 {
+            return Ok(());
+        }
+        let cutoff = Utc::now() - Duration::seconds(self.configs.purge_after);
+        let uploads = self
+            .metadata
+            .purgeable_uploads(cutoff, PURGE_BATCH_SIZE)
+            .await?;
+
+        let (mut purged, mut failed) = (0, 0);
+        for upload in uploads {
+            let result = match self.configs.purge_mode.as_str() {
+                "tombstone" => {
+                    self.metadata
+                        .tombstone_upload(&Tombstone::from(&upload))
+                        .await
+                }
+                _ => self.metadata.delete_upload(&upload.id).await,
+            };
+            match result {
+                Ok(_) => {
+                    log::info!("\"Purged inactive upload {}\"", &upload.id);
+                    purged += 1;
+                }
+                Err(e) => {
+                    log::error!("\"Unable to purge inactive upload {}: {}\"", &upload.id, e);
+                    failed += 1;
+                }
+            }
+        }
+        crate::metrics::track_purge(purged, failed);
+        Ok(())
+    }
+
+    pub async fn admin_init(&self) -> Result<(), RestError> {
+        // Create the locks, freeing a cleanup lock held past five minutes
+        self.metadata
+            .init_locks(Utc::now() - Duration::minutes(5))
+            .await
+    }
+
+    pub async fn lock_timer(&self) -> Result<(), RestError> {
+        let mut last_cleanup = self.last_cleanup.lock().await;
+        let now = Utc::now().timestamp();
+        if now > *last_cleanup + 60 {
+            *last_cleanup = now;
+            Ok(())
+        } else {
+            log::debug!("\"Cleanup skipped, internal timer not at 60 seconds\"");
+            Err(RestError::CleanupNotRequired)
+        }
+    }
+
+    pub async fn lock_cleanup(&self) -> Result<(), RestError> {
+        log::debug!("\"Attempting to lock cleanup doc\"");
+        let delay = Utc::now() - Duration::seconds(60);
+
+        // Try to lock cleanup doc
+        if self.metadata.lock_cleanup(delay).await.is_err() {
+            log::debug!("\"Cleanup not required at this time\"");
+            return Err(RestError::CleanupNotRequired);
+        };
+        Ok(())
+    }
+
+    pub async fn unlock_cleanup(&self) -> Result<(), RestError> {
+        log::debug!("\"Freeing cleanup doc\"");
+
+        // Unlock cleanup doc
+        if self.metadata.unlock_cleanup().await.is_err() {
+            log::error!("Unable to free cleanup doc");
+        };
+        Ok(())
+    }
+
+    pub async fn expired_ids(&self) -> Result<Vec<String>, RestError> {
+        // Search for docs that are expired here
+        let res = self.metadata.expired_uploads(1000).await?;
+        let result: Vec<String> = res.iter().map(|s| s.id.to_owned()).collect();
         Ok(result)
     }
 
+    pub async fn uploads_owned(
+        &self,
+        id: &str,
+        tags: Option<Vec<String>>,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<MetaDataPublic>, RestError> {
+        let res = self.metadata.owned_uploads(id, tags, after, limit).await?;
+        Ok(res.map(|s| s.to_json()))
+    }
+
     pub async fn get_doc(&self, user_id: &str, doc_id: &str) -> Result<MetaDataPublic, RestError> {
-        let filter = doc! {"active": true, "facts.owner": user_id, "id": doc_id };
         Ok(self
-            .db
-            .find_one::<MetaData>(&self.configs.collection_uploads, filter, None)
+            .metadata
+            .find_owned_upload(user_id, doc_id)
             .await?
             .to_json())
     }
 
     pub async fn delete_doc(&self, user_id: &str, doc_id: &str) -> Result<(), RestError> {
-        let filter = doc! {"active": true, "facts.owner": user_id, "id": doc_id };
         // Ensure that doc exists, and is owned by user
-        self.db
-            .find_one::<MetaData>(&self.configs.collection_uploads, filter, None)
-            .await?;
+        self.metadata.find_owned_upload(user_id, doc_id).await?;
         self.delete(doc_id).await?;
         Ok(())
     }
@@ -571,13 +1261,8 @@ impl State {
         doc_id: &str,
         link_id: &str,
     ) -> Result<(), RestError> {
-        let filter =
-            doc! {"active": true, "facts.owner": user_id, "id": doc_id, "links.id": link_id };
-        let update = doc! { "$pull": { "links": { "id": link_id } } };
         // Ensure that doc exists, and is owned by user
-        self.db
-            .find_one_and_update::<MetaData>(&self.configs.collection_uploads, filter, update, None)
-            .await?;
+        self.metadata.pull_link(user_id, doc_id, link_id).await?;
         Ok(())
     }
 
@@ -585,15 +1270,17 @@ impl State {
         &self,
         user_id: &str,
         doc_id: &str,
-    ) -> Result<Vec<LinkScrubbed>, RestError> {
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<LinkScrubbed>, RestError> {
         log::debug!("Attempting to locate doc: {}", doc_id);
-        let filter = doc! {"active": true, "facts.owner": user_id, "id": doc_id };
-        Ok(self
-            .db
-            .find_one::<MetaData>(&self.configs.collection_uploads, filter, None)
+        let links = self
+            .metadata
+            .find_owned_upload(user_id, doc_id)
             .await?
             .links
-            .to_vec())
+            .to_vec();
+        Page::slice(links, |l| (l.created, l.id.clone()), after, limit)
     }
 
     pub async fn add_link(
@@ -604,11 +1291,9 @@ impl State {
     ) -> Result<NewLinkResult, RestError> {
         log::debug!("Attempting to locate doc to add link: {}", doc_id);
         let new_link = Link::new(Some(&user_id.to_owned()), &self.configs, tags)?;
-        let filter = doc! {"active": true, "facts.owner": user_id, "id": doc_id };
-        let update = doc! { "$push": { "links": to_document(&new_link.link)? } };
         let doc = self
-            .db
-            .find_one_and_update::<MetaData>(&self.configs.collection_uploads, filter, update, None)
+            .metadata
+            .push_link(user_id, doc_id, &new_link.link)
             .await?;
         Ok(NewLinkResult {
             filename: doc.meta.filename.clone(), 
@@ -624,15 +1309,8 @@ impl State {
     ) -> Result<Vec<String>, RestError> {
         if let Some(tags_unwrapped) = tags {
             log::debug!("Attempting to locate doc to add tags: {}", doc_id);
-            let filter = doc! {"active": true, "facts.owner": user_id, "id": doc_id };
-            let update = doc! { "$addToSet": { "meta.tags": { "$each": tags_unwrapped.clone() } } };
-            self.db
-                .find_one_and_update::<MetaData>(
-                    &self.configs.collection_uploads,
-                    filter,
-                    update,
-                    None,
-                )
+            self.metadata
+                .add_tags(user_id, doc_id, &tags_unwrapped)
                 .await?;
             Ok(tags_unwrapped)
         } else {
@@ -648,15 +1326,8 @@ impl State {
     ) -> Result<Vec<String>, RestError> {
         if let Some(tags_unwrapped) = tags {
             log::debug!("Attempting to locate doc to delete tags: {}", doc_id);
-            let filter = doc! {"active": true, "facts.owner": user_id, "id": doc_id };
-            let update = doc! { "$pull": { "meta.tags": { "$in": tags_unwrapped.clone() } } };
-            self.db
-                .find_one_and_update::<MetaData>(
-                    &self.configs.collection_uploads,
-                    filter,
-                    update,
-                    None,
-                )
+            self.metadata
+                .remove_tags(user_id, doc_id, &tags_unwrapped)
                 .await?;
             Ok(tags_unwrapped)
         } else {
@@ -670,11 +1341,7 @@ impl State {
         doc_id: &str,
     ) -> Result<Vec<String>, RestError> {
         log::debug!("Attempting to locate doc to get tags: {}", doc_id);
-        let filter = doc! {"active": true, "facts.owner": user_id, "id": doc_id };
-        let doc = self
-            .db
-            .find_one::<MetaData>(&self.configs.collection_uploads, filter, None)
-            .await?;
+        let doc = self.metadata.find_owned_upload(user_id, doc_id).await?;
         if let Some(tags) = doc.meta.tags {
             Ok(tags)
         } else {
@@ -683,19 +1350,59 @@ impl State {
     }
 
     pub async fn cleanup_work(&self) -> Result<(), RestError> {
-        // Get expired ids
-        let ids = self.expired_ids().await?;
-
-        for id in ids {
-            self.delete(&id.to_string()).await?;
+        if let Err(e) = self.cleanup_batch().await {
+            log::error!("\"Cleanup did not finish: {}\"", e);
         }
 
-        // Unlock the cleanup doc
+        // Unlock the cleanup doc, even if part of the batch failed
         self.unlock_cleanup().await?;
 
         Ok(())
     }
 
+    // Each failed item is logged and skipped, so one bad upload doesn't hold up the rest
+    async fn cleanup_batch(&self) -> Result<(), RestError> {
+        // Expired uploads are removed by the TTL index instead, when it is enabled
+        if self.configs.expiry != "ttl" {
+            for id in self.expired_ids().await? {
+                if let Err(e) = self.delete(&id).await {
+                    log::error!("\"Unable to delete expired upload {}: {}\"", &id, e);
+                }
+            }
+        }
+
+        // Remove abandoned resumable uploads, along with their parts
+        for session in self.expired_sessions().await? {
+            log::debug!("\"Removing expired upload session {}\"", &session.id);
+            if let Err(e) = self.delete_session_parts(&session).await {
+                log::error!("\"Unable to remove upload session {}: {}\"", &session.id, e);
+            }
+        }
+
+        // Remove direct uploads that were never completed, along with anything written for them
+        for upload in self.expired_direct().await? {
+            log::debug!("\"Removing abandoned direct upload {}\"", &upload.id);
+            if let Err(e) = self.abandon_direct(&upload).await {
+                log::error!("\"Unable to remove direct upload {}: {}\"", &upload.id, e);
+            }
+        }
+
+        if let Err(e) = self.retry_deletions().await {
+            log::error!("\"Unable to retry queued deletions: {}\"", e);
+        }
+
+        if let Err(e) = self.purge_inactive().await {
+            log::error!("\"Unable to purge inactive uploads: {}\"", e);
+        }
+
+        // Keep the usage gauge current between uploads, when there is a budget to measure against
+        if self.configs.storage_budget > 0 {
+            self.storage_used().await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn cleanup(&self) -> Result<(), RestError> {
         // Only perform cleanup if internal timeout has breached 60 seconds
         if self.lock_timer().await.is_err() {
@@ -732,8 +1439,17 @@ impl State {
     }
 
     pub async fn init(&mut self) -> Result<(), RestError> {
+        // Fail startup if the metadata store can't expire uploads itself
+        self.metadata
+            .set_ttl_expiry(self.configs.expiry == "ttl")
+            .await?;
+
+        // Start the storage budget's running total from the uploads already stored
+        let used = self.metadata.stored_bytes().await?;
+        self.metadata.init_budget(used).await?;
+
         // Send initialization to background thread
-        let mut me = self.clone();
+        let me = self.clone();
         tokio::spawn(async move {
             if me.admin_init().await.is_err() {
                 log::error!("Error initializing admin collection");
@@ -741,13 +1457,1000 @@ impl State {
             if me.cleanup_init().await.is_err() {
                 log::error!("Error starting initial cleanup");
             };
-            if me.create_uploads_indexes().await.is_err() {
-                log::error!("Error creating upload collection indexes");
+            if me.metadata.create_indexes().await.is_err() {
+                log::error!("Error creating metadata indexes");
             };
+            if me.configs.expiry == "ttl" {
+                me.expiry_thread();
+            }
+            me.reconcile_thread();
         });
         Ok(())
     }
 
+    //
+    // TTL Expiry
+    //
+
+    // Delete the objects of uploads the metadata store removes as they expire. One instance at a
+    // time handles removals, and every instance runs the rest of the cleanup on a timer, so neither
+    // waits for downloads.
+    pub fn expiry_thread(&self) {
+        let me = self.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_secs(EXPIRY_RENEW_SECONDS));
+            loop {
+                interval.tick().await;
+                if let Err(e) = me.cleanup().await {
+                    log::error!("\"Unable to run cleanup: {}\"", e);
+                }
+                if me.lock_expiry().await.is_err() {
+                    log::debug!("\"Expired uploads are handled by another instance\"");
+                    continue;
+                }
+                if let Err(e) = me.handle_expired(&mut interval).await {
+                    log::error!("\"Stopped handling expired uploads: {}\"", e);
+                }
+            }
+        });
+    }
+
+    async fn lock_expiry(&self) -> Result<(), RestError> {
+        let since = Utc::now() - Duration::seconds(EXPIRY_LOCK_SECONDS);
+        self.metadata
+            .lock_expiry(&self.configs.instance, since)
+            .await
+    }
+
+    // Handle removed uploads until the watch fails or the lock is lost, renewing the lock and
+    // running the cleanup on every tick
+    async fn handle_expired(&self, interval: &mut tokio::time::Interval) -> Result<(), RestError> {
+        let mut expired = self.metadata.watch_expired(&self.configs.instance).await?;
+        log::info!("\"Handling uploads removed by the TTL index\"");
+        loop {
+            tokio::select! {
+                expired_upload = expired.next() => match expired_upload {
+                    Some(expired_upload) => {
+                        let expired_upload = expired_upload?;
+                        let released = self.expire(expired_upload.upload).await;
+                        self.metadata
+                            .save_expiry_position(&self.configs.instance, &expired_upload.position)
+                            .await?;
+                        // Only once the position is saved, so an upload handled again after a
+                        // restart doesn't give its bytes back twice
+                        self.release_budget(released).await;
+                    }
+                    None => return Ok(()),
+                },
+                _ = interval.tick() => {
+                    self.lock_expiry().await?;
+                    if let Err(e) = self.cleanup().await {
+                        log::error!("\"Unable to run cleanup: {}\"", e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Delete the objects of an upload the TTL index removed, returning the bytes it held in the
+    // storage budget
+    async fn expire(&self, upload: MetaData) -> usize {
+        // Uploads deleted before they expired have released their objects already
+        if !upload.active && upload.facts.direct.is_none() {
+            return 0;
+        }
+        log::info!("\"Upload {} expired, deleting its objects\"", &upload.id);
+        if let Err(e) = self.release_object(&upload).await {
+            log::error!(
+                "\"Unable to delete objects of expired upload {}: {}\"",
+                &upload.id,
+                e
+            );
+        }
+        crate::metrics::track_ttl_expiry();
+        match upload.active {
+            true => upload.meta.bytes.stored,
+            false => 0,
+        }
+    }
+
+    //
+    // Reconciliation
+    //
+
+    // Compare storage against the uploads collection, optionally removing orphaned objects and
+    // deactivating uploads whose object is gone. The listing is checked against the metadata store
+    // a page at a time, and uploads are then checked against storage a page at a time.
+    pub async fn reconcile(&self, repair: bool) -> Result<ReconcileReport, RestError> {
+        let started = Utc::now();
+        let settled = started - Duration::seconds(RECONCILE_GRACE_SECONDS);
+        log::info!("\"Starting storage reconciliation\"");
+        let mut report = ReconcileReport::default();
+
+        let mut listing = self
+            .storage
+            .list_objects()
+            .await?
+            .chunks(RECONCILE_PAGE_SIZE);
+        while let Some(page) = listing.next().await {
+            let page = page.into_iter().collect::<Result<Vec<_>, RestError>>()?;
+            report.objects += page.len();
+            let ids: Vec<String> = page.iter().map(|o| o.id.clone()).collect();
+            let referenced = self.metadata.referenced_objects(&ids).await?;
+            report.orphaned.extend(
+                page.into_iter()
+                    .filter(|o| !referenced.contains(&o.id) && o.modified < settled)
+                    .map(|o| o.id),
+            );
+        }
+        report.orphaned.sort();
+
+        // Uploads created since the start may not have written their objects yet
+        let mut after: Option<String> = None;
+        loop {
+            let uploads = self
+                .metadata
+                .active_uploads_after(after.as_deref(), RECONCILE_PAGE_SIZE as i64)
+                .await?;
+            after = match uploads.last() {
+                Some(u) => Some(u.id.clone()),
+                None => break,
+            };
+            report.documents += uploads.len();
+            let mut checks = Vec::new();
+            for upload in uploads {
+                if upload.meta.created < started {
+                    checks.push(self.missing_upload(upload));
+                }
+            }
+            let missing: Vec<Option<String>> = stream::iter(checks)
+                .buffer_unordered(RECONCILE_CONCURRENCY)
+                .try_collect()
+                .await?;
+            report.missing.extend(missing.into_iter().flatten());
+        }
+        report.missing.sort();
+
+        if repair {
+            for id in report.orphaned.iter() {
+                match self.storage.delete_object(id).await {
+                    Ok(_) => report.repaired += 1,
+                    Err(e) => log::error!("\"Unable to delete orphaned object {}: {}\"", id, e),
+                }
+            }
+            for id in report.missing.iter() {
+                match self.deactivate_missing(id).await {
+                    Ok(_) => report.repaired += 1,
+                    Err(e) => log::error!("\"Unable to deactivate upload {}: {}\"", id, e),
+                }
+            }
+        }
+
+        log::info!(
+            "\"Reconciled {} objects against {} uploads, {} orphaned, {} missing, {} repaired\"",
+            report.objects,
+            report.documents,
+            report.orphaned.len(),
+            report.missing.len(),
+            report.repaired
+        );
+        crate::metrics::track_reconcile(
+            report.objects,
+            report.orphaned.len(),
+            report.missing.len(),
+            report.repaired,
+        );
+        Ok(report)
+    }
+
+    // Id of the upload if any of its objects is gone from storage
+    async fn missing_upload(&self, upload: MetaData) -> Result<Option<String>, RestError> {
+        for id in upload.object_ids() {
+            match self.storage.object_size(&id).await {
+                Ok(_) => (),
+                Err(RestError::NotFound) => return Ok(Some(upload.id)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    // Deactivate an upload that has lost its object, dropping its reference if the object was shared
+    async fn deactivate_missing(&self, id: &str) -> Result<(), RestError> {
+        let metadata = self.metadata.deactivate_upload(id).await?;
+        self.release_budget(metadata.meta.bytes.stored).await;
+        if metadata.facts.digest.is_some() {
+            if let Err(e) = self.release_object(&metadata).await {
+                log::debug!("\"Shared object for {} was already gone: {}\"", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn lock_reconcile(&self) -> Result<(), RestError> {
+        let delay = Utc::now() - Duration::seconds(self.configs.reconcile_interval);
+        self.metadata.lock_reconcile(delay).await
+    }
+
+    // Reconcile on a fixed interval, the lock doc keeps it to one instance per interval
+    pub fn reconcile_thread(&self) {
+        if self.configs.reconcile_interval <= 0 {
+            return;
+        }
+        let me = self.clone();
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(me.configs.reconcile_interval as u64);
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                if me.lock_reconcile().await.is_err() {
+                    log::debug!("\"Reconcile not required at this time\"");
+                    continue;
+                }
+                if let Err(e) = me.reconcile(me.configs.reconcile_repair).await {
+                    log::error!("\"Storage reconciliation failed: {}\"", e);
+                }
+            }
+        });
+    }
+
+    //
+    // Storage budget
+    //
+
+    // Bytes held in storage by active uploads, and reserved by uploads being written, as counted
+    // by the budget's running total. Shared objects count once per upload.
+    pub async fn storage_used(&self) -> Result<usize, RestError> {
+        let used = self.metadata.budget_used().await?;
+        crate::metrics::track_storage_usage(used, self.configs.storage_budget);
+        Ok(used)
+    }
+
+    // Reserve room for an upload before it is written, going by the length the client declared,
+    // and return the bytes reserved and how many it may write. Compressed uploads are stored
+    // smaller than declared, so nothing is reserved for them up front and they are only held to
+    // what can be written. The reservation is settled with the stored size once it is written.
+    async fn budget_room(
+        &self,
+        declared: Option<usize>,
+    ) -> Result<(usize, Option<usize>), RestError> {
+        let reserved = match declared.filter(|_| !self.configs.compress) {
+            Some(bytes) => {
+                self.reserve_budget(bytes).await?;
+                bytes
+            }
+            None => 0,
+        };
+        let budget = self.configs.storage_budget;
+        if budget == 0 {
+            return Ok((reserved, None));
+        }
+
+        // Evicting policies can make room for anything up to the whole budget
+        match self.configs.budget_policy.as_str() {
+            "expiring" | "oldest" => Ok((reserved, Some(budget))),
+            _ => match self.storage_used().await {
+                Ok(used) => Ok((reserved, Some(budget.saturating_sub(used) + reserved))),
+                Err(e) => {
+                    self.release_budget(reserved).await;
+                    Err(e)
+                }
+            },
+        }
+    }
+
+    // Settle an upload's reservation with the size it was stored with, giving back whatever was
+    // reserved if the difference doesn't fit
+    async fn settle_budget(&self, reserved: usize, stored: usize) -> Result<(), RestError> {
+        if stored <= reserved {
+            self.release_budget(reserved - stored).await;
+            return Ok(());
+        }
+        if let Err(e) = self.reserve_budget(stored - reserved).await {
+            self.release_budget(reserved).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Count bytes against the storage budget, evicting uploads to make room when the policy allows
+    // it. The total is checked and raised in one conditional update, so concurrent uploads can't
+    // overshoot the budget together. Without a budget the total is still kept.
+    async fn reserve_budget(&self, bytes: usize) -> Result<(), RestError> {
+        let budget = self.configs.storage_budget;
+        let order = match self.configs.budget_policy.as_str() {
+            "expiring" if bytes <= budget => Some(Eviction::Expiring),
+            "oldest" if bytes <= budget => Some(Eviction::Oldest),
+            _ => None,
+        };
+
+        // Evictions go through delete, just as if the uploads had expired
+        let mut evicted = 0;
+        loop {
+            match self
+                .metadata
+                .reserve_storage(bytes as i64, budget as i64)
+                .await
+            {
+                Ok(_) => break,
+                Err(RestError::NotFound) => (),
+                Err(e) => return Err(e),
+            }
+
+            let mut excess = (self.storage_used().await? + bytes).saturating_sub(budget);
+            let order = match order {
+                Some(o) => o,
+                None => {
+                    log::warn!(
+                        "\"Rejecting upload of {} bytes, {} bytes over the storage budget\"",
+                        bytes,
+                        excess
+                    );
+                    crate::metrics::track_budget_rejection();
+                    return Err(RestError::InsufficientStorage);
+                }
+            };
+
+            let uploads = self
+                .metadata
+                .eviction_candidates(order, EVICTION_BATCH_SIZE)
+                .await?;
+            let before = evicted;
+            for upload in uploads.iter() {
+                if excess == 0 {
+                    break;
+                }
+                match self.delete(&upload.id).await {
+                    Ok(_) => {
+                        log::info!(
+                            "\"Evicted upload {} to free {} bytes\"",
+                            &upload.id,
+                            upload.meta.bytes.stored
+                        );
+                        excess = excess.saturating_sub(upload.meta.bytes.stored);
+                        evicted += 1;
+                    }
+                    Err(e) => log::error!("\"Unable to evict upload {}: {}\"", &upload.id, e),
+                }
+            }
+            if evicted == before {
+                crate::metrics::track_evictions(evicted);
+                log::warn!(
+                    "\"Rejecting upload of {} bytes, unable to evict enough uploads\"",
+                    bytes
+                );
+                crate::metrics::track_budget_rejection();
+                return Err(RestError::InsufficientStorage);
+            }
+        }
+        crate::metrics::track_evictions(evicted);
+        Ok(())
+    }
+
+    // Take bytes back off the budget's running total, for uploads deactivated or never stored
+    async fn release_budget(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        if let Err(e) = self.metadata.release_storage(bytes as i64).await {
+            log::error!(
+                "\"Unable to release {} bytes from the storage budget: {}\"",
+                bytes,
+                e
+            );
+        }
+    }
+
+    //
+    // Storage migration
+    //
+
+    // Copy the object of every active upload and open upload session to another backend,
+    // skipping objects an earlier run already copied and verified
+    pub async fn migrate_storage(
+        &self,
+        to: &StorageClient,
+        target: &str,
+        collection: &str,
+        concurrency: usize,
+    ) -> Result<MigrationReport, RestError> {
+        // Shared objects are referenced by several uploads but only need copying once
+        let mut objects: HashMap<String, MigrationObject> = HashMap::new();
+        let uploads = self.metadata.active_uploads().await?;
+        for upload in uploads.iter() {
+            let (mut content_type, metadata) = self.object_attributes(upload);
+            if upload.facts.segments.is_some() {
+                content_type = SEGMENT_CONTENT_TYPE.to_owned();
+            }
+            for id in upload.object_ids() {
+                objects
+                    .entry(id.clone())
+                    .or_insert_with(|| MigrationObject {
+                        id,
+                        content_type: content_type.clone(),
+                        metadata: metadata.clone(),
+                    });
+            }
+        }
+        let sessions = self.metadata.sessions().await?;
+        for part in sessions.into_iter().flat_map(|s| s.parts) {
+            objects.insert(
+                part.id.clone(),
+                MigrationObject {
+                    id: part.id,
+                    content_type: PART_CONTENT_TYPE.to_owned(),
+                    metadata: HashMap::new(),
+                },
+            );
+        }
+        // Pending direct uploads are written by the client to their staging key, and copied to
+        // their own object once completed, so either may be in storage already
+        let pending = self.metadata.pending_uploads().await?;
+        for upload in pending.iter() {
+            let (content_type, metadata) = self.object_attributes(upload);
+            objects
+                .entry(upload.object_id().to_owned())
+                .or_insert_with(|| MigrationObject {
+                    id: upload.object_id().to_owned(),
+                    content_type: content_type.clone(),
+                    metadata,
+                });
+            if let Some(staging) = upload.staging_id() {
+                objects
+                    .entry(staging.to_owned())
+                    .or_insert_with(|| MigrationObject {
+                        id: staging.to_owned(),
+                        content_type,
+                        metadata: HashMap::new(),
+                    });
+            }
+        }
+
+        let migrated: HashSet<String> = self
+            .metadata
+            .migrated_objects(collection, target)
+            .await?
+            .into_iter()
+            .map(|m| m.object)
+            .collect();
+
+        let mut report = MigrationReport {
+            objects: objects.len(),
+            ..Default::default()
+        };
+        let pending: Vec<MigrationObject> = objects
+            .into_values()
+            .filter(|o| !migrated.contains(&o.id))
+            .collect();
+        report.skipped = report.objects - pending.len();
+        log::info!(
+            "\"Migrating {} objects to {}, {} already migrated\"",
+            pending.len(),
+            target,
+            report.skipped
+        );
+
+        let mut results = stream::iter(pending)
+            .map(|object| self.migrate_object(to.clone(), object, target, collection))
+            .buffer_unordered(concurrency.max(1));
+        let mut done = 0;
+        while let Some((id, result)) = results.next().await {
+            match result {
+                Ok(Some(bytes)) => {
+                    report.migrated += 1;
+                    report.bytes += bytes;
+                }
+                Ok(None) => report.gone += 1,
+                Err(e) => {
+                    log::error!("\"Unable to migrate {}: {}\"", id, e);
+                    report.failed.push(id);
+                }
+            }
+            done += 1;
+            if done % MIGRATION_PROGRESS_INTERVAL == 0 {
+                log::info!(
+                    "\"Migrated {} of {} objects, {} bytes, {} failed\"",
+                    report.migrated,
+                    report.objects - report.skipped,
+                    report.bytes,
+                    report.failed.len()
+                );
+            }
+        }
+
+        report.failed.sort();
+        log::info!(
+            "\"Migration to {} finished, {} migrated, {} skipped, {} gone, {} failed\"",
+            target,
+            report.migrated,
+            report.skipped,
+            report.gone,
+            report.failed.len()
+        );
+        Ok(report)
+    }
+
+    // Copy one object and read it back to check it arrived intact. Returns the bytes copied, or
+    // None when the object no longer exists in the source.
+    async fn migrate_object(
+        &self,
+        mut to: StorageClient,
+        object: MigrationObject,
+        target: &str,
+        collection: &str,
+    ) -> (String, Result<Option<usize>, RestError>) {
+        let id = object.id.clone();
+        let data = match self.storage.fetch_object(&id).await {
+            Ok(d) => d,
+            Err(RestError::NotFound) => {
+                log::warn!("\"{} is no longer in source storage\"", id);
+                return (id, Ok(None));
+            }
+            Err(e) => return (id, Err(e)),
+        };
+
+        let hasher = Arc::new(std::sync::Mutex::new(Blake2s256::new()));
+        let bytes = Arc::new(AtomicUsize::new(0));
+        let data = Data::count(Data::digest(data, hasher.clone()), bytes.clone());
+        if let Err(e) = to
+            .insert_object(&id, data, &object.content_type, &object.metadata)
+            .await
+        {
+            return (id, Err(e));
+        }
+        let digest = encode(std::mem::take(&mut *hasher.lock().unwrap()).finalize());
+        let bytes = bytes.load(Ordering::Relaxed);
+
+        // Read the copy back before recording it as done
+        let mut copy = match to.fetch_object(&id).await {
+            Ok(c) => c,
+            Err(e) => return (id, Err(e)),
+        };
+        let mut copy_hasher = Blake2s256::new();
+        let mut copy_bytes = 0;
+        while let Some(chunk) = copy.next().await {
+            match chunk {
+                Ok(c) => {
+                    copy_bytes += c.len();
+                    copy_hasher.update(&c);
+                }
+                Err(e) => return (id, Err(e)),
+            }
+        }
+        if copy_bytes != bytes || encode(copy_hasher.finalize()) != digest {
+            log::error!(
+                "\"Copy of {} does not match, {} bytes copied but {} read back\"",
+                id,
+                bytes,
+                copy_bytes
+            );
+            return (id, Err(RestError::BadInsert));
+        }
+
+        let migration = Migration {
+            object: id.clone(),
+            target: target.to_owned(),
+            bytes: bytes as i64,
+            digest,
+            migrated: Utc::now(),
+        };
+        let recorded = self.metadata.record_migration(collection, &migration).await;
+        (id, recorded.map(|_| Some(bytes)))
+    }
+
+    //
+    // Direct Uploads
+    //
+
+    // Reserve an inactive document for an object the client writes straight to storage, and sign
+    // the request to write it with. The declared size is checked when the upload is completed.
+    pub async fn create_direct(
+        &self,
+        size: u64,
+        queries: &Query<QueriesSet>,
+        headers: HeaderMap,
+        current_user: CurrentUser,
+    ) -> Result<DirectUpload, RestError> {
+        if !self.configs.direct_uploads {
+            return Err(RestError::NotFound);
+        }
+
+        let seconds = self.configs.direct_expires.clamp(1, SIGNED_URL_MAX_SECONDS);
+        let complete_by = Utc::now() + Duration::seconds(seconds + DIRECT_COMPLETE_GRACE_SECONDS);
+        let payload = MetaData::create_pending(
+            queries,
+            headers,
+            current_user.id,
+            self.configs.clone(),
+            |id| {
+                let staging = format!("{}{}", id, DIRECT_STAGING_SUFFIX);
+                Direct {
+                    complete_by: complete_by.into(),
+                    staging: self.object_key(&staging).unwrap_or(staging),
+                    completing: None,
+                }
+            },
+        )?;
+        let mut metadata = payload.metadata;
+        metadata.facts.object = self.object_key(&metadata.id);
+        metadata.facts.object_key_ver = self.object_key_ver();
+        let staging = metadata.staging_id().unwrap_or_default().to_owned();
+        metadata.meta.bytes = Size {
+            original: size as usize,
+            stored: size as usize,
+        };
+
+        let (content_type, _) = self.object_attributes(&metadata);
+        let upload = match self
+            .storage
+            .signed_upload(&staging, seconds as u64, &content_type)
+            .await?
+        {
+            Some(u) => u,
+            None => {
+                return Err(RestError::BadRequest(
+                    "Direct uploads are not supported by this storage backend",
+                ))
+            }
+        };
+
+        let metadata = self.metadata.insert_upload(metadata).await?;
+        log::debug!("\"Created direct upload {}\"", &metadata.id);
+
+        Ok(DirectUpload {
+            id: metadata.id.clone(),
+            url: payload.url,
+            data: DataInfo {
+                id: metadata.links.first().unwrap().id.clone(),
+                key: payload.key,
+            },
+            upload,
+            complete_by,
+        })
+    }
+
+    // Activate a direct upload once its object is in storage with the declared size. The object is
+    // copied out of the key the client was given, counting its bytes as they go.
+    pub async fn complete_direct(
+        &self,
+        id: &str,
+        current_user: &CurrentUser,
+    ) -> Result<MetaDataInfo, RestError> {
+        // Claim the pending document, so the upload can't be completed twice at once
+        let metadata = self
+            .metadata
+            .claim_pending_upload(current_user.id.as_deref(), id)
+            .await?;
+        let staging = metadata.staging_id().ok_or(RestError::NotFound)?;
+
+        if let Err(e) = self.copy_direct(&metadata, staging).await {
+            if let Err(e) = self.metadata.release_pending_upload(id).await {
+                log::error!("\"Unable to release direct upload {}: {}\"", id, e);
+            }
+            return Err(e);
+        }
+
+        let metadata = match self.metadata.activate_pending_upload(id).await {
+            Ok(m) => m,
+            Err(e) => {
+                // Abandoned by the cleanup in the meantime, so drop the copy made
+                self.release_budget(metadata.meta.bytes.stored).await;
+                if let Err(e) = self.remove_object(metadata.object_id()).await {
+                    log::error!("\"Unable to delete {}: {}\"", metadata.object_id(), e);
+                }
+                return Err(e);
+            }
+        };
+        if let Err(e) = self.remove_object(staging).await {
+            log::error!("\"Unable to delete {}: {}\"", staging, e);
+        }
+        log::debug!("\"Completed direct upload {}\"", id);
+
+        Ok(MetaDataInfo {
+            expire_seconds: metadata.lifecycle.max.seconds,
+            expire_reads: metadata.lifecycle.max.reads,
+            pwd: metadata.facts.pwd.is_some(),
+            tags: metadata.meta.tags,
+            bytes: metadata.meta.bytes,
+        })
+    }
+
+    // Check a direct upload's object against the declared size and the budget, then copy it to the
+    // upload's own object
+    async fn copy_direct(&self, metadata: &MetaData, staging: &str) -> Result<(), RestError> {
+        let declared = metadata.meta.bytes.stored;
+        let size = match self.storage.object_size(staging).await {
+            Ok(s) => s as usize,
+            Err(RestError::NotFound) => {
+                return Err(RestError::BadRequest("Object has not been uploaded"))
+            }
+            Err(e) => return Err(e),
+        };
+        if size != declared {
+            log::warn!(
+                "\"Direct upload {} is {} bytes, expected {}\"",
+                &metadata.id,
+                size,
+                declared
+            );
+            return Err(RestError::BadRequest(
+                "Uploaded object does not match the declared size",
+            ));
+        }
+
+        if let Err(e) = self.reserve_budget(size).await {
+            if let Err(e) = self.remove_object(staging).await {
+                log::error!("\"Unable to delete rejected object {}: {}\"", staging, e);
+            }
+            return Err(e);
+        }
+
+        if let Err(e) = self.copy_staging(metadata, staging).await {
+            self.release_budget(size).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Copy a direct upload's object out of its staging key. The client can still write to that
+    // key, so only what was counted on the way is kept.
+    async fn copy_staging(&self, metadata: &MetaData, staging: &str) -> Result<(), RestError> {
+        let declared = metadata.meta.bytes.stored;
+        let (content_type, attributes) = self.object_attributes(metadata);
+        let copied = Arc::new(AtomicUsize::new(0));
+        let data = Data::count(self.storage.fetch_object(staging).await?, copied.clone());
+        self.storage
+            .clone()
+            .insert_object(metadata.object_id(), data, &content_type, &attributes)
+            .await?;
+        if copied.load(Ordering::Relaxed) != declared {
+            log::warn!(
+                "\"Direct upload {} changed while it was completed\"",
+                &metadata.id
+            );
+            if let Err(e) = self.remove_object(metadata.object_id()).await {
+                log::error!("\"Unable to delete {}: {}\"", metadata.object_id(), e);
+            }
+            return Err(RestError::BadRequest(
+                "Uploaded object does not match the declared size",
+            ));
+        }
+        Ok(())
+    }
+
+    // Drop an uncompleted direct upload. The document is kept, inactive, like a deleted upload's.
+    // Every step is attempted, so a failed removal is picked up again by the next cleanup.
+    async fn abandon_direct(&self, metadata: &MetaData) -> Result<(), RestError> {
+        let staging = match metadata.staging_id() {
+            Some(staging) => self.remove_object(staging).await,
+            None => Ok(()),
+        };
+        let object = self.remove_object(metadata.object_id()).await;
+        staging?;
+        object?;
+        self.metadata.drop_pending_upload(&metadata.id).await
+    }
+
+    pub async fn expired_direct(&self) -> Result<Vec<MetaData>, RestError> {
+        self.metadata.expired_pending_uploads(1000).await
+    }
+
+    //
+    // Resumable Uploads
+    //
+
+    pub async fn create_session(
+        &self,
+        length: i64,
+        current_user: &CurrentUser,
+    ) -> Result<UploadSession, RestError> {
+        // Resumable uploads get around the request size limit, but not the upload size limit
+        if length as usize > self.configs.limit {
+            return Err(RestError::PayloadTooLarge);
+        }
+        let session = UploadSession::new(length, current_user.id.clone(), &self.configs)?;
+        log::debug!("\"Creating upload session {}\"", &session.id);
+        self.metadata.insert_session(session).await
+    }
+
+    pub async fn get_session(
+        &self,
+        id: &str,
+        current_user: &CurrentUser,
+    ) -> Result<UploadSession, RestError> {
+        self.metadata
+            .find_open_session(id, current_user.id.as_deref())
+            .await
+    }
+
+    // Store the body of a PATCH as the next part of a session, returning the new offset
+    pub async fn patch_session(
+        &mut self,
+        id: &str,
+        offset: i64,
+        value: ObjectStream,
+        current_user: &CurrentUser,
+    ) -> Result<i64, RestError> {
+        let session = self.get_session(id, current_user).await?;
+        if offset != session.offset {
+            log::warn!(
+                "\"Upload offset {} does not match session offset {}\"",
+                offset,
+                session.offset
+            );
+            return Err(RestError::Conflict);
+        }
+        if session.length as usize > self.configs.limit {
+            return Err(RestError::PayloadTooLarge);
+        }
+
+        // Keep whatever arrived before a dropped connection, clients resume from the offset we report
+        let remaining = (session.length - session.offset) as usize;
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let value: ObjectStream = value
+            .take_while(|chunk| {
+                if let Err(e) = chunk {
+                    log::warn!("\"Upload interrupted, keeping partial chunk: {}\"", e);
+                }
+                future::ready(chunk.is_ok())
+            })
+            .map(move |chunk| {
+                let bytes = chunk?;
+                if counter.fetch_add(bytes.len(), Ordering::Relaxed) + bytes.len() > remaining {
+                    return Err(RestError::PayloadTooLarge);
+                }
+                Ok(bytes)
+            })
+            .boxed();
+
+        // The part is written on its own task, as the request is dropped when the client
+        // disconnects and the bytes it did send still have to be stored and recorded
+        let mut me = self.clone();
+        let id = id.to_owned();
+        tokio::spawn(async move { me.write_session_part(&id, &session, value, received).await })
+            .await
+            .map_err(|_| RestError::BadInsert)?
+    }
+
+    async fn write_session_part(
+        &mut self,
+        id: &str,
+        session: &UploadSession,
+        value: ObjectStream,
+        received: Arc<AtomicUsize>,
+    ) -> Result<i64, RestError> {
+        let value = match session.key(&self.configs)? {
+            Some(k) => Data::encrypt_stream(&k, value)?,
+            None => value,
+        };
+
+        let part_id = session.part_id();
+        let part_id = self.object_key(&part_id).unwrap_or(part_id);
+        self.storage
+            .insert_object(&part_id, value, PART_CONTENT_TYPE, &HashMap::new())
+            .await?;
+
+        let bytes = received.load(Ordering::Relaxed) as i64;
+        if bytes == 0 {
+            self.delete_part(&part_id).await;
+            return Ok(session.offset);
+        }
+
+        // Only advance if nothing else wrote to the session in the meantime
+        let part = UploadPart {
+            id: part_id.clone(),
+            bytes,
+        };
+        if let Err(e) = self
+            .metadata
+            .append_session_part(id, session.offset, &part)
+            .await
+        {
+            log::warn!("\"Upload session {} changed during patch\"", id);
+            self.delete_part(&part_id).await;
+            return Err(match e {
+                RestError::NotFound => RestError::Conflict,
+                e => e,
+            });
+        }
+
+        Ok(session.offset + bytes)
+    }
+
+    // Assemble a finished session into a regular upload
+    pub async fn complete_session(
+        &mut self,
+        id: &str,
+        queries: &Query<QueriesSet>,
+        headers: HeaderMap,
+        current_user: CurrentUser,
+    ) -> Result<SetResult, RestError> {
+        // Claim the session, so it can't be patched or completed twice
+        let session = self
+            .metadata
+            .claim_session(id, current_user.id.as_deref())
+            .await?;
+
+        let result = if session.offset != session.length {
+            log::warn!(
+                "\"Upload session {} is incomplete, {} of {} bytes\"",
+                id,
+                session.offset,
+                session.length
+            );
+            Err(RestError::Conflict)
+        } else {
+            match self.session_stream(&session) {
+                Ok(value) => {
+                    let declared = Some(session.length as usize);
+                    self.set(value, declared, queries, headers, current_user)
+                        .await
+                }
+                Err(e) => Err(e),
+            }
+        };
+
+        match result {
+            Ok(r) => {
+                self.delete_session_parts(&session).await?;
+                Ok(r)
+            }
+            Err(e) => {
+                self.metadata.reopen_session(id).await?;
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn delete_session(
+        &self,
+        id: &str,
+        current_user: &CurrentUser,
+    ) -> Result<(), RestError> {
+        let session = self.get_session(id, current_user).await?;
+        self.delete_session_parts(&session).await
+    }
+
+    // Read the parts of a session back in order, one at a time
+    fn session_stream(&self, session: &UploadSession) -> Result<ObjectStream, RestError> {
+        let key = session.key(&self.configs)?;
+        let storage = self.storage.clone();
+        let parts = session.parts.clone();
+        Ok(stream::iter(parts)
+            .then(move |part| {
+                let storage = storage.clone();
+                let key = key.clone();
+                async move {
+                    let value = storage.fetch_object(&part.id).await?;
+                    match key {
+                        Some(k) => Data::decrypt_stream(&k, value),
+                        None => Ok(value),
+                    }
+                }
+            })
+            .try_flatten()
+            .boxed())
+    }
+
+    async fn delete_part(&self, id: &str) {
+        if let Err(e) = self.remove_object(id).await {
+            log::warn!("\"Unable to delete upload part {}: {}\"", id, e);
+        }
+    }
+
+    async fn delete_session_parts(&self, session: &UploadSession) -> Result<(), RestError> {
+        for part in session.parts.iter() {
+            self.delete_part(&part.id).await;
+        }
+        self.metadata.delete_session(&session.id).await
+    }
+
+    pub async fn expired_sessions(&self) -> Result<Vec<UploadSession>, RestError> {
+        self.metadata.expired_sessions(1000).await
+    }
+
     //
     // Send User Requests
     //
@@ -769,8 +2472,13 @@ impl State {
         self.users_admin.create_api_key(id, tags, role).await
     }
 
-    pub async fn list_api_keys(&self, id: &str) -> Result<Vec<ApiKeyBrief>, RestError> {
-        match self.users_admin.list_api_keys(id).await {
+    pub async fn list_api_keys(
+        &self,
+        id: &str,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Page<ApiKeyBrief>, RestError> {
+        match self.users_admin.list_api_keys(id, after, limit).await {
             Ok(u) => Ok(u),
             Err(e) => Err(e),
         }
@@ -780,3 +2488,258 @@ impl State {
         self.users_admin.delete_api_key(id, key).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::users::Access;
+    use std::path::PathBuf;
+
+    fn key(ver: u8, key: &str) -> Key {
+        Key {
+            ver,
+            key: key.to_owned(),
+        }
+    }
+
+    fn data_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("tackd-state-{}", uuid::Uuid::new_v4()))
+    }
+
+    // State as main sets it up, embedded in dir, with args on top
+    async fn state(dir: &Path, args: &[&str]) -> State {
+        std::fs::create_dir_all(dir).unwrap();
+        let mut argv = vec!["tackd", "--data_dir", dir.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        let opts = crate::command().get_matches_from(argv);
+        let metadata = MetadataClient::from_opts(&opts).await.unwrap();
+        let storage = StorageClient::from_opts(opts.value_of("storage"), &opts)
+            .await
+            .unwrap();
+        let mut state = State::new(opts, metadata, storage).await.unwrap();
+        state.init().await.unwrap();
+        state
+    }
+
+    async fn upload(state: &mut State, data: &'static [u8], queries: QueriesSet) -> SetResult {
+        try_upload(state, data, queries).await.unwrap()
+    }
+
+    async fn try_upload(
+        state: &mut State,
+        data: &'static [u8],
+        queries: QueriesSet,
+    ) -> Result<SetResult, RestError> {
+        let user = CurrentUser {
+            id: None,
+            access: Access {
+                role: "upload".to_owned(),
+            },
+        };
+        let payload = stream::once(future::ready(Ok(Bytes::from_static(data)))).boxed();
+        state
+            .set(payload, None, &Query(queries), HeaderMap::new(), user)
+            .await
+    }
+
+    fn queries(expires: Option<&str>, reads: Option<i64>) -> QueriesSet {
+        QueriesSet {
+            filename: None,
+            expires: expires.map(|e| e.to_owned()),
+            reads,
+            pwd: None,
+            tags: None,
+        }
+    }
+
+    #[test]
+    fn object_key_secret_is_derived_apart_from_the_key() {
+        let first = key(1, "0123456789abcdef0123456789abcdef");
+        let secret = first.object_key_secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(secret, first.object_key_secret());
+        // The digest the key used to be hashed with directly
+        assert_ne!(secret, Blake2s256::digest(first.key.as_bytes()).to_vec());
+        assert_ne!(
+            secret,
+            key(2, "fedcba9876543210fedcba9876543210").object_key_secret()
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_plain_downloads_to_signed_urls() {
+        let dir = data_dir();
+        let mut local = state(&dir, &["--bucket", "tackd"]).await;
+        let plain = upload(&mut local, b"plain", queries(Some("3600"), None)).await;
+        let short = upload(&mut local, b"short", queries(Some("10"), None)).await;
+        let limited = upload(&mut local, b"limited", queries(None, Some(3))).await;
+
+        // The same uploads served from S3, which signs urls without contacting it
+        let mut s3 = state(
+            &dir,
+            &[
+                "--storage",
+                "s3",
+                "--s3_endpoint",
+                "http://127.0.0.1:9",
+                "--s3_region",
+                "us-east-1",
+                "--s3_path_style",
+                "--s3_access_key_id",
+                "key",
+                "--s3_secret_access_key",
+                "secret",
+                "--bucket",
+                "tackd",
+                "--redirect_downloads",
+                "--redirect_expires",
+                "60",
+            ],
+        )
+        .await;
+        let download = s3
+            .get(&plain.data.id, plain.data.key.as_ref(), None, None)
+            .await
+            .unwrap();
+        let url = download.redirect.unwrap();
+        assert!(url.starts_with("http://127.0.0.1:9/tackd/"));
+        assert!(url.contains("X-Amz-Expires=60&"));
+        let doc = s3
+            .metadata
+            .find_upload_by_link(&plain.data.id)
+            .await
+            .unwrap();
+        assert_eq!(doc.lifecycle.current.reads, 1);
+
+        // Urls don't outlive their upload, and uploads with a read limit are always streamed
+        let doc = s3
+            .metadata
+            .find_upload_by_link(&short.data.id)
+            .await
+            .unwrap();
+        let url = s3.redirect_url(&doc).await.unwrap();
+        let expires: u64 = url
+            .split("X-Amz-Expires=")
+            .nth(1)
+            .and_then(|e| e.split('&').next())
+            .and_then(|e| e.parse().ok())
+            .unwrap();
+        assert!((1..=10).contains(&expires));
+        let doc = s3
+            .metadata
+            .find_upload_by_link(&limited.data.id)
+            .await
+            .unwrap();
+        assert!(s3.redirect_url(&doc).await.is_none());
+        assert!(local.redirect_url(&doc).await.is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn embedded_mode_keeps_everything_in_the_data_dir() {
+        let dir = data_dir();
+        let mut first = state(&dir, &["--encrypt_data"]).await;
+        let uploaded = upload(&mut first, b"embedded", queries(None, Some(5))).await;
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|n| !n.starts_with("tackd.db-"))
+            .collect();
+        names.sort();
+        assert_eq!(names, ["keys.json", "objects", "tackd.db"]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join("keys.json"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A restart picks up the generated key, so encrypted uploads can still be read
+        let keys = std::fs::read_to_string(dir.join("keys.json")).unwrap();
+        let mut second = state(&dir, &["--encrypt_data"]).await;
+        assert_eq!(serde_json::to_string(&second.configs.keys).unwrap(), keys);
+        let download = second
+            .get(&uploaded.data.id, uploaded.data.key.as_ref(), None, None)
+            .await
+            .unwrap();
+        let data: Vec<u8> = download
+            .data
+            .map_ok(|b| b.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(data, b"embedded");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removed_uploads_release_their_objects_once() {
+        let dir = data_dir();
+        let mut state = state(&dir, &["--dedup"]).await;
+        let contents: [&'static [u8]; 3] = [b"shared", b"shared", b"deleted"];
+        let mut docs = Vec::new();
+        for data in contents {
+            let uploaded = upload(&mut state, data, queries(None, Some(5))).await;
+            let doc = state.metadata.find_upload_by_link(&uploaded.data.id);
+            docs.push(doc.await.unwrap());
+        }
+        let object = docs[0].object_id().to_owned();
+        assert_eq!(docs[1].object_id(), object);
+
+        // Handling a removal again, as after a restart, keeps the other upload's reference
+        assert_eq!(state.expire(docs[0].clone()).await, 6);
+        state.expire(docs[0].clone()).await;
+        assert_eq!(state.storage.object_size(&object).await.unwrap(), 6);
+        state.expire(docs[1].clone()).await;
+        assert!(matches!(
+            state.storage.object_size(&object).await,
+            Err(RestError::NotFound)
+        ));
+
+        // Uploads deleted before they expired have released everything already
+        state.delete(&docs[2].id).await.unwrap();
+        let mut deleted = docs[2].clone();
+        deleted.active = false;
+        assert_eq!(state.expire(deleted).await, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_uploads_to_stay_within_the_budget() {
+        // The third upload only fits once one of the first two is evicted
+        for (policy, evicted) in [("oldest", 0), ("expiring", 1)] {
+            let dir = data_dir();
+            let args = ["--storage_budget", "20", "--budget_policy", policy];
+            let mut state = state(&dir, &args).await;
+            let mut ids = Vec::new();
+            for expires in ["3600", "60", "7200"] {
+                let uploaded = upload(&mut state, b"8 bytes!", queries(Some(expires), None)).await;
+                ids.push(uploaded.data.id);
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            for (i, id) in ids.iter().enumerate() {
+                let found = state.metadata.find_upload_by_link(id).await;
+                assert_eq!(found.is_err(), i == evicted, "{} upload {}", policy, i);
+            }
+            assert_eq!(state.metadata.budget_used().await.unwrap(), 16);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        // Rejected uploads leave nothing behind
+        let dir = data_dir();
+        let mut state = state(&dir, &["--storage_budget", "20"]).await;
+        upload(&mut state, b"8 bytes!", queries(None, None)).await;
+        upload(&mut state, b"8 bytes!", queries(None, None)).await;
+        assert!(matches!(
+            try_upload(&mut state, b"8 bytes!", queries(None, None)).await,
+            Err(RestError::InsufficientStorage)
+        ));
+        assert_eq!(state.metadata.budget_used().await.unwrap(), 16);
+        assert_eq!(state.metadata.stored_bytes().await.unwrap(), 16);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,40 +1,318 @@
 use crate::error::Error as RestError;
 use async_trait::async_trait;
-use futures::StreamExt;
+use azure_core::error::ErrorKind;
+use azure_core::StatusCode;
+use azure_storage::prelude::BlobSasPermissions;
+use azure_storage::{
+    CloudLocation, ConnectionString, EndpointProtocol, StorageCredentials, EMULATOR_ACCOUNT,
+    EMULATOR_ACCOUNT_KEY,
+};
+use azure_storage_blobs::blob::{BlobBlockType, BlockList};
+use azure_storage_blobs::prelude::{BlockId, ClientBuilder};
+use bytes::BytesMut;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
 use std::sync::Arc;
 use std::collections::HashMap;
 use azure_core::request_options::Metadata;
 
-use crate::storage::trait_storage::Storage;
+use crate::storage::trait_storage::{
+    ObjectInfo, ObjectListing, ObjectStream, SignedUpload, Storage, StorageOpts,
+};
+
+// Size of each block staged before the block list is committed
+const BLOCK_SIZE: usize = 4194304;
+
+// Warn at startup when a SAS token expires within this many days
+const SAS_EXPIRY_WARNING_DAYS: i64 = 7;
 
 #[derive(Clone, Debug)]
 pub struct AzureBlobClient {
     container: String,
     client: Arc<azure_storage_blobs::prelude::BlobServiceClient>,
+    sas_expiry: Option<DateTime<Utc>>,
 }
 
 impl AzureBlobClient {
     pub fn new(
         container: &str,
         client: azure_storage_blobs::prelude::BlobServiceClient,
+        sas_expiry: Option<DateTime<Utc>>,
     ) -> AzureBlobClient {
         AzureBlobClient {
             container: container.to_owned(),
             client: Arc::new(client),
+            sas_expiry,
+        }
+    }
+
+    // Build a client from a connection string, a SAS token or an access key, in that order
+    pub fn from_opts(container: &str, opts: StorageOpts<'_>) -> Result<AzureBlobClient, RestError> {
+        let connection_string = opts.value_of("azure_connection_string");
+        let sas_token = opts.value_of("azure_sas_token");
+        let account = opts.value_of("azure_storage_account");
+        let access_key = opts.value_of("azure_storage_access_key");
+        let endpoint = opts.value_of("azure_endpoint");
+
+        if connection_string.is_some() && (sas_token.is_some() || access_key.is_some()) {
+            return Err(config_error(
+                "Set either an Azure connection string or an access key or SAS token, not both",
+            ));
         }
+        if sas_token.is_some() && access_key.is_some() {
+            return Err(config_error(
+                "Set either an Azure access key or a SAS token, not both",
+            ));
+        }
+
+        let (location, sas_expiry) = match (connection_string, sas_token) {
+            (Some(c), _) => AzureBlobClient::parse_connection_string(c, endpoint)?,
+            (None, Some(sas)) => {
+                let expiry = AzureBlobClient::validate_sas(sas)?;
+                let credentials = StorageCredentials::sas_token(sas)
+                    .map_err(|_| config_error("Unable to parse Azure SAS token"))?;
+                let location = match endpoint {
+                    Some(e) => CloudLocation::Custom {
+                        uri: e.trim_end_matches('/').to_owned(),
+                        credentials,
+                    },
+                    None => CloudLocation::Public {
+                        account: account
+                            .ok_or_else(|| {
+                                config_error(
+                                    "Set env variable AZURE_STORAGE_ACCOUNT to use a SAS token",
+                                )
+                            })?
+                            .to_owned(),
+                        credentials,
+                    },
+                };
+                (location, Some(expiry))
+            }
+            (None, None) => match endpoint {
+                Some(e) => {
+                    // Emulators like Azurite accept a well known development account
+                    let credentials = StorageCredentials::Key(
+                        account.unwrap_or(EMULATOR_ACCOUNT).to_owned(),
+                        access_key.unwrap_or(EMULATOR_ACCOUNT_KEY).to_owned(),
+                    );
+                    let location = CloudLocation::Custom {
+                        uri: e.trim_end_matches('/').to_owned(),
+                        credentials,
+                    };
+                    (location, None)
+                }
+                None => {
+                    let account = account.ok_or_else(|| {
+                        config_error("Set env variable AZURE_STORAGE_ACCOUNT first")
+                    })?;
+                    let access_key = access_key.ok_or_else(|| {
+                        config_error("Set env variable AZURE_STORAGE_ACCESS_KEY first")
+                    })?;
+                    let location = CloudLocation::Public {
+                        account: account.to_owned(),
+                        credentials: StorageCredentials::Key(
+                            account.to_owned(),
+                            access_key.to_owned(),
+                        ),
+                    };
+                    (location, None)
+                }
+            },
+        };
+
+        if let Some(e) = endpoint {
+            log::info!("\"Using Azure Blob Storage at {}\"", e);
+        }
+        let client = ClientBuilder::with_location(location).blob_service_client();
+        Ok(AzureBlobClient::new(container, client, sas_expiry))
+    }
+
+    fn parse_connection_string(
+        value: &str,
+        endpoint: Option<&str>,
+    ) -> Result<(CloudLocation, Option<DateTime<Utc>>), RestError> {
+        // Parse errors can echo the whole string, account key included, so they aren't logged
+        let parsed = ConnectionString::new(value).map_err(|_| {
+            config_error("Invalid Azure connection string, expected Key=Value pairs separated by ;")
+        })?;
+        if parsed.use_development_storage == Some(true) {
+            let location = CloudLocation::Emulator {
+                address: "127.0.0.1".to_owned(),
+                port: 10000,
+            };
+            return Ok((location, None));
+        }
+
+        let sas_expiry = parsed.sas.map(AzureBlobClient::validate_sas).transpose()?;
+        let credentials = parsed.storage_credentials().map_err(|_| {
+            config_error(
+                "Azure connection string needs either an AccountKey or a SharedAccessSignature",
+            )
+        })?;
+        let uri = match (endpoint.or(parsed.blob_endpoint), parsed.account_name) {
+            (Some(e), _) => e.trim_end_matches('/').to_owned(),
+            (None, Some(account)) => {
+                let protocol = match parsed.default_endpoints_protocol {
+                    Some(EndpointProtocol::Http) => "http",
+                    _ => "https",
+                };
+                let suffix = parsed.endpoint_suffix.unwrap_or("core.windows.net");
+                format!("{}://{}.blob.{}", protocol, account, suffix)
+            }
+            (None, None) => {
+                return Err(config_error(
+                    "Azure connection string needs an AccountName or BlobEndpoint",
+                ))
+            }
+        };
+        Ok((CloudLocation::Custom { uri, credentials }, sas_expiry))
+    }
+
+    // Check a SAS token up front so a bad one fails at startup, not on the first upload
+    fn validate_sas(token: &str) -> Result<DateTime<Utc>, RestError> {
+        let params: HashMap<String, String> =
+            url::form_urlencoded::parse(token.trim_start_matches('?').as_bytes())
+                .into_owned()
+                .collect();
+
+        if !params.contains_key("sig") {
+            return Err(config_error("Azure SAS token has no signature"));
+        }
+        // Account tokens have no resource, blob tokens can only reach a single object
+        if let Some(resource) = params.get("sr") {
+            if resource != "c" {
+                return Err(RestError::StorageConfig(format!(
+                    "Azure SAS token must be scoped to a container, not sr={}",
+                    resource
+                )));
+            }
+        }
+        let permissions = params.get("sp").map(String::as_str).unwrap_or_default();
+        for (permission, name) in [('r', "read"), ('w', "write"), ('d', "delete")] {
+            if !permissions.contains(permission) {
+                return Err(RestError::StorageConfig(format!(
+                    "Azure SAS token is missing the {} permission",
+                    name
+                )));
+            }
+        }
+        if !permissions.contains('l') {
+            log::warn!(
+                "\"Azure SAS token has no list permission, storage reconciliation will fail\""
+            );
+        }
+
+        let expiry = params
+            .get("se")
+            .and_then(|e| parse_sas_time(e))
+            .ok_or_else(|| config_error("Azure SAS token has no valid expiry time"))?;
+        if expiry <= Utc::now() {
+            return Err(RestError::StorageConfig(format!(
+                "Azure SAS token expired at {}",
+                expiry
+            )));
+        }
+        if expiry - Utc::now() < Duration::days(SAS_EXPIRY_WARNING_DAYS) {
+            log::warn!("\"Azure SAS token expires at {}\"", expiry);
+        }
+        Ok(expiry)
+    }
+
+    // Fail with a clear error once the SAS token has expired, instead of waiting on a 403
+    fn check_expiry(&self) -> Result<(), RestError> {
+        match self.sas_expiry {
+            Some(expiry) if expiry <= Utc::now() => {
+                log::error!("\"Azure SAS token expired at {}\"", expiry);
+                Err(RestError::StorageCredentials(format!(
+                    "Azure SAS token expired at {}",
+                    expiry
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_blob(&self, id: &str, range: Option<std::ops::Range<u64>>) -> ObjectStream {
+        let blob_client = self
+            .client
+            .container_client(&self.container)
+            .blob_client(id);
+
+        let mut get = blob_client.get().chunk_size(1048576u64);
+        if let Some(r) = range {
+            get = get.range(r);
+        }
+
+        // Each response covers one chunk of the blob, and its body is streamed in turn
+        let sas_expiry = self.sas_expiry;
+        get.into_stream()
+            .map_ok(|response| response.data)
+            .try_flatten()
+            .map_err(move |e| azure_error(e, sas_expiry))
+            .boxed()
     }
 }
 
+// SAS tokens can be revoked or have their policy changed before they expire, so say which
+// credential Azure refused rather than passing on a bare 403. Missing blobs are NotFound, as with
+// the other backends.
+fn azure_error(err: azure_core::error::Error, sas_expiry: Option<DateTime<Utc>>) -> RestError {
+    match (err.kind(), sas_expiry) {
+        (
+            ErrorKind::HttpResponse {
+                status: StatusCode::Forbidden,
+                ..
+            },
+            Some(expiry),
+        ) => {
+            log::error!("\"Azure rejected the SAS token: {}\"", err);
+            RestError::StorageCredentials(format!(
+                "Azure rejected the SAS token, which expires at {}",
+                expiry
+            ))
+        }
+        (
+            ErrorKind::HttpResponse {
+                status: StatusCode::NotFound,
+                ..
+            },
+            _,
+        ) => RestError::NotFound,
+        _ => RestError::from(err),
+    }
+}
+
+// Bad Azure settings are reported at startup
+fn config_error(msg: &str) -> RestError {
+    RestError::StorageConfig(msg.to_owned())
+}
+
+// SAS times are ISO 8601 in UTC, down to the day, minute or second
+fn parse_sas_time(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%MZ") {
+        return Some(Utc.from_utc_datetime(&t));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|t| Utc.from_utc_datetime(&t))
+}
+
 #[async_trait]
 impl Storage for AzureBlobClient {
     async fn insert_object<'a>(
         &mut self,
         id: &'a str,
-        data: Vec<u8>,
+        mut data: ObjectStream,
         content_type: &str,
         metadata: &HashMap<String, String>
     ) -> Result<&'a str, RestError> {
         log::debug!("Inserting data into Azure Blob");
+        self.check_expiry()?;
         let blob_client = self
             .client
             .container_client(&self.container)
@@ -47,41 +325,206 @@ impl Storage for AzureBlobClient {
             metadata_map.insert(k, value.into_bytes());
         };
 
+        // Stage blocks as data arrives, then commit them all at once
+        let mut block_list = BlockList::default();
+        let mut buffer = BytesMut::new();
+        let mut finished = false;
+        while !finished {
+            match data.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => finished = true,
+            }
+
+            if buffer.len() >= BLOCK_SIZE || (finished && !buffer.is_empty()) {
+                let block_id = BlockId::new(format!("{:08}", block_list.blocks.len()));
+                log::debug!("staging block of {} bytes", buffer.len());
+                blob_client
+                    .put_block(block_id.clone(), buffer.split().freeze())
+                    .await
+                    .map_err(|e| azure_error(e, self.sas_expiry))?;
+                block_list
+                    .blocks
+                    .push(BlobBlockType::new_uncommitted(block_id));
+            }
+        }
+
         blob_client
-            .put_block_blob(data)
+            .put_block_list(block_list)
             .content_type(content_type.to_owned())
             .metadata(metadata_map)
-            .await?;
+            .await
+            .map_err(|e| azure_error(e, self.sas_expiry))?;
 
         Ok(id)
     }
 
-    async fn fetch_object(&self, id: &str) -> Result<Vec<u8>, RestError> {
+    async fn fetch_object(&self, id: &str) -> Result<ObjectStream, RestError> {
         log::debug!("Downloading {} from azure blob", id);
+        self.check_expiry()?;
+        Ok(self.get_blob(id, None))
+    }
+
+    async fn fetch_range(&self, id: &str, start: u64, end: u64) -> Result<ObjectStream, RestError> {
+        log::debug!(
+            "Downloading bytes {}-{} of {} from azure blob",
+            start,
+            end,
+            id
+        );
+        self.check_expiry()?;
+        Ok(self.get_blob(id, Some(start..end + 1)))
+    }
+
+    async fn delete_object(&self, id: &str) -> Result<(), RestError> {
+        log::debug!("Deleting {} from azure blob", id);
+        self.check_expiry()?;
         let blob_client = self
             .client
             .container_client(&self.container)
             .blob_client(id);
-        let mut complete_response = vec![];
+        // Delete value from container
+        match blob_client.delete().await {
+            Ok(_) => Ok(()),
+            Err(e) => match azure_error(e, self.sas_expiry) {
+                RestError::NotFound => {
+                    log::debug!("\"{} was already gone from azure blob\"", id);
+                    Ok(())
+                }
+                e => Err(e),
+            },
+        }
+    }
 
-        let mut stream = blob_client.get().chunk_size(1048576u64).into_stream();
-        while let Some(value) = stream.next().await {
-            let data = value?.data.collect().await?;
-            log::debug!("received {:?} bytes", data.len());
-            complete_response.extend(&data);
+    async fn list_objects(&self) -> Result<ObjectListing, RestError> {
+        self.check_expiry()?;
+        let sas_expiry = self.sas_expiry;
+        let pages = self
+            .client
+            .container_client(&self.container)
+            .list_blobs()
+            .into_stream();
+
+        Ok(pages
+            .map(move |page| match page {
+                Ok(p) => p
+                    .blobs
+                    .blobs()
+                    .map(|b| {
+                        Ok(ObjectInfo {
+                            id: b.name.clone(),
+                            modified: Utc
+                                .timestamp_opt(b.properties.last_modified.unix_timestamp(), 0)
+                                .single()
+                                .unwrap_or_else(Utc::now),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                Err(e) => vec![Err(azure_error(e, sas_expiry))],
+            })
+            .flat_map(stream::iter)
+            .boxed())
+    }
+
+    // Only account keys can sign urls, clients using a SAS token keep proxying downloads
+    async fn signed_url(&self, id: &str, seconds: u64) -> Result<Option<String>, RestError> {
+        if self.sas_expiry.is_some() {
+            return Ok(None);
         }
+        log::debug!("Signing download url for {} in azure blob", id);
+        let blob_client = self
+            .client
+            .container_client(&self.container)
+            .blob_client(id);
+        let expiry = Utc::now() + Duration::seconds(seconds as i64);
+        let permissions = BlobSasPermissions {
+            read: true,
+            ..Default::default()
+        };
+        let signature = blob_client.shared_access_signature(
+            permissions,
+            azure_core::date::parse_rfc3339(&expiry.to_rfc3339())?,
+        )?;
+        Ok(Some(
+            blob_client
+                .generate_signed_blob_url(&signature)?
+                .to_string(),
+        ))
+    }
 
-        Ok(complete_response)
+    async fn signed_upload(
+        &self,
+        id: &str,
+        seconds: u64,
+        content_type: &str,
+    ) -> Result<Option<SignedUpload>, RestError> {
+        if self.sas_expiry.is_some() {
+            return Ok(None);
+        }
+        log::debug!("Signing upload url for {} in azure blob", id);
+        let blob_client = self
+            .client
+            .container_client(&self.container)
+            .blob_client(id);
+        let expiry = Utc::now() + Duration::seconds(seconds as i64);
+        let permissions = BlobSasPermissions {
+            create: true,
+            write: true,
+            ..Default::default()
+        };
+        let signature = blob_client.shared_access_signature(
+            permissions,
+            azure_core::date::parse_rfc3339(&expiry.to_rfc3339())?,
+        )?;
+        let mut headers = HashMap::new();
+        headers.insert("x-ms-blob-type".to_owned(), "BlockBlob".to_owned());
+        headers.insert("content-type".to_owned(), content_type.to_owned());
+        Ok(Some(SignedUpload {
+            url: blob_client
+                .generate_signed_blob_url(&signature)?
+                .to_string(),
+            headers,
+        }))
     }
 
-    async fn delete_object(&self, id: &str) -> Result<(), RestError> {
-        log::debug!("Deleting {} from azure blob", id);
+    async fn object_size(&self, id: &str) -> Result<u64, RestError> {
+        self.check_expiry()?;
         let blob_client = self
             .client
             .container_client(&self.container)
             .blob_client(id);
-        // Delete value from container
-        blob_client.delete().await?;
-        Ok(())
+        let properties = blob_client
+            .get_properties()
+            .await
+            .map_err(|e| azure_error(e, self.sas_expiry))?;
+        Ok(properties.blob.properties.content_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sas(sp: &str, se: &str) -> String {
+        format!("sv=2022-11-02&ss=b&srt=co&sp={}&se={}&sig=abc%3D", sp, se)
+    }
+
+    #[test]
+    fn rejects_unusable_sas_tokens() {
+        let expiry = (Utc::now() + Duration::days(30)).format("%Y-%m-%dT%H:%M:%SZ");
+        let valid = AzureBlobClient::validate_sas(&sas("rwdl", &expiry.to_string())).unwrap();
+        assert!(valid > Utc::now());
+
+        for token in [
+            sas("rwl", &expiry.to_string()),
+            sas("rwdl", "2020-01-01"),
+            sas("rwdl", "soon"),
+            format!("sp=rwdl&se={}", expiry),
+            format!("sr=b&{}", sas("rwdl", &expiry.to_string())),
+        ] {
+            assert!(matches!(
+                AzureBlobClient::validate_sas(&token),
+                Err(RestError::StorageConfig(_))
+            ));
+        }
     }
 }
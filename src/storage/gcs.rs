@@ -1,14 +1,59 @@
 use crate::error::Error as RestError;
 use async_trait::async_trait;
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use cloud_storage::{ListRequest, Object};
+use futures::{stream, StreamExt, TryStreamExt};
+use hyper::header::{CONTENT_TYPE, RANGE};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
 use std::sync::Arc;
 use std::collections::HashMap;
+use tokio_stream::wrappers::ReceiverStream;
 
-use crate::storage::trait_storage::Storage;
+use crate::storage::trait_storage::{
+    ObjectInfo, ObjectListing, ObjectStream, SignedUpload, Storage, StorageOpts,
+};
+
+// Upper bound on the size of each chunk handed back when streaming an object
+const READ_CHUNK_SIZE: usize = 65536;
 
 #[derive(Clone, Debug)]
 pub struct GcsClient {
     bucket: String,
     client: Arc<cloud_storage::client::Client>,
+    // Set when requests go to another endpoint than storage.googleapis.com, such as an emulator
+    endpoint: Option<Endpoint>,
+}
+
+// The GCS client always targets storage.googleapis.com, so requests to an endpoint override are
+// made through the same JSON API directly. Emulators such as fake-gcs-server don't need the
+// client's authentication either.
+#[derive(Clone, Debug)]
+struct Endpoint {
+    url: String,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectList {
+    #[serde(default)]
+    items: Vec<ObjectItem>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ObjectItem {
+    name: String,
+    updated: Option<DateTime<Utc>>,
+}
+
+// The JSON API encodes 64 bit integers as strings
+#[derive(Deserialize)]
+struct ObjectResource {
+    size: String,
 }
 
 impl GcsClient {
@@ -16,8 +61,208 @@ impl GcsClient {
         GcsClient {
             bucket: bucket.to_owned(),
             client: Arc::new(client),
+            endpoint: None,
+        }
+    }
+
+    pub fn from_opts(bucket: &str, opts: StorageOpts<'_>) -> GcsClient {
+        let mut client = GcsClient::new(bucket, cloud_storage::Client::default());
+        if let Some(endpoint) = opts.value_of("gcs_endpoint") {
+            log::info!("\"Using GCS endpoint {}\"", endpoint);
+            // STORAGE_EMULATOR_HOST is conventionally just a host and port
+            let url = match endpoint.contains("://") {
+                true => endpoint.trim_end_matches('/').to_owned(),
+                false => format!("http://{}", endpoint.trim_end_matches('/')),
+            };
+            client.endpoint = Some(Endpoint {
+                url,
+                http: reqwest::Client::new(),
+            });
+        }
+        client
+    }
+
+    // The GCS client has no ranged reads, so leading bytes are downloaded and skipped over
+    async fn download(
+        &self,
+        id: &str,
+        skip: usize,
+        take: usize,
+    ) -> Result<ObjectStream, RestError> {
+        // Get value from bucket, the GCS client yields single bytes so regroup them into chunks
+        match self
+            .client
+            .object()
+            .download_streamed(&self.bucket, id)
+            .await
+        {
+            Ok(v) => Ok(v
+                .skip(skip)
+                .take(take)
+                .ready_chunks(READ_CHUNK_SIZE)
+                .map(
+                    |chunk| match chunk.into_iter().collect::<Result<Vec<u8>, _>>() {
+                        Ok(c) => Ok(Bytes::from(c)),
+                        Err(e) => Err(RestError::from(e)),
+                    },
+                )
+                .boxed()),
+            Err(e) => {
+                log::error!("\"Got error attempting to fetch id from GCS: {}\"", e);
+                Err(RestError::NotFound)
+            }
+        }
+    }
+
+    // Signing only needs the bucket and name, but the client signs urls from an object resource,
+    // which doesn't exist yet when the url is for uploading it
+    fn unwritten_object(&self, id: &str) -> Object {
+        let now = Utc::now();
+        Object {
+            kind: "storage#object".to_owned(),
+            id: format!("{}/{}", self.bucket, id),
+            self_link: String::new(),
+            name: id.to_owned(),
+            bucket: self.bucket.clone(),
+            generation: 0,
+            metageneration: 0,
+            content_type: None,
+            time_created: now,
+            updated: now,
+            time_deleted: None,
+            temporary_hold: None,
+            event_based_hold: None,
+            retention_expiration_time: None,
+            storage_class: String::new(),
+            time_storage_class_updated: now,
+            size: 0,
+            md5_hash: None,
+            media_link: String::new(),
+            content_encoding: None,
+            content_disposition: None,
+            content_language: None,
+            cache_control: None,
+            metadata: None,
+            acl: None,
+            owner: None,
+            crc32c: String::new(),
+            component_count: None,
+            etag: String::new(),
+            customer_encryption: None,
+            kms_key_name: None,
+        }
+    }
+}
+
+impl Endpoint {
+    fn objects_url(&self, bucket: &str) -> String {
+        format!("{}/storage/v1/b/{}/o", self.url, bucket)
+    }
+
+    fn object_url(&self, bucket: &str, id: &str) -> String {
+        let id: String = url::form_urlencoded::byte_serialize(id.as_bytes()).collect();
+        format!("{}/{}", self.objects_url(bucket), id)
+    }
+
+    fn check(response: reqwest::Response) -> Result<reqwest::Response, RestError> {
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(RestError::NotFound),
+            _ => Ok(response.error_for_status()?),
+        }
+    }
+
+    async fn insert(
+        &self,
+        bucket: &str,
+        id: &str,
+        data: ReceiverStream<Result<Bytes, RestError>>,
+        content_type: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<(), RestError> {
+        let request = self
+            .http
+            .post(format!("{}/upload/storage/v1/b/{}/o", self.url, bucket))
+            .query(&[("uploadType", "media"), ("name", id)])
+            .header(CONTENT_TYPE, content_type)
+            .body(reqwest::Body::wrap_stream(data));
+        Self::check(request.send().await?)?;
+
+        // Media uploads can't carry metadata, so it is added afterwards as with the GCS client
+        let request = self
+            .http
+            .patch(self.object_url(bucket, id))
+            .json(&json!({ "metadata": metadata }));
+        Self::check(request.send().await?)?;
+        Ok(())
+    }
+
+    async fn download(
+        &self,
+        bucket: &str,
+        id: &str,
+        range: Option<String>,
+    ) -> Result<ObjectStream, RestError> {
+        let mut request = self
+            .http
+            .get(self.object_url(bucket, id))
+            .query(&[("alt", "media")]);
+        if let Some(r) = range {
+            request = request.header(RANGE, r);
+        }
+        let response = Self::check(request.send().await?)?;
+        Ok(response.bytes_stream().map_err(RestError::from).boxed())
+    }
+
+    async fn delete(&self, bucket: &str, id: &str) -> Result<(), RestError> {
+        match Self::check(self.http.delete(self.object_url(bucket, id)).send().await?) {
+            Ok(_) | Err(RestError::NotFound) => Ok(()),
+            Err(e) => Err(e),
         }
     }
+
+    async fn list_page(
+        &self,
+        bucket: &str,
+        token: Option<String>,
+    ) -> Result<ObjectList, RestError> {
+        let mut request = self.http.get(self.objects_url(bucket));
+        if let Some(t) = token {
+            request = request.query(&[("pageToken", t)]);
+        }
+        Ok(Self::check(request.send().await?)?.json().await?)
+    }
+
+    fn list(&self, bucket: &str) -> ObjectListing {
+        let (me, bucket) = (self.clone(), bucket.to_owned());
+        stream::unfold(Some(None), move |token| {
+            let (me, bucket) = (me.clone(), bucket.clone());
+            async move {
+                let page = match me.list_page(&bucket, token?).await {
+                    Ok(p) => p,
+                    Err(e) => return Some((vec![Err(e)], None)),
+                };
+                let objects = page
+                    .items
+                    .into_iter()
+                    .map(|o| {
+                        Ok(ObjectInfo {
+                            id: o.name,
+                            modified: o.updated.unwrap_or_else(Utc::now),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                Some((objects, page.next_page_token.map(Some)))
+            }
+        })
+        .flat_map(stream::iter)
+        .boxed()
+    }
+
+    async fn size(&self, bucket: &str, id: &str) -> Result<u64, RestError> {
+        let response = Self::check(self.http.get(self.object_url(bucket, id)).send().await?)?;
+        let object: ObjectResource = response.json().await?;
+        object.size.parse().map_err(|_| RestError::BadInsert)
+    }
 }
 
 #[async_trait]
@@ -25,39 +270,165 @@ impl Storage for GcsClient {
     async fn insert_object<'a>(
         &mut self,
         id: &'a str,
-        data: Vec<u8>,
+        mut data: ObjectStream,
         content_type: &str,
         metadata: &HashMap<String, String>
     ) -> Result<&'a str, RestError> {
         log::debug!("inserting data into GCS");
-        let mut object = self.client
+
+        // The GCS client requires a Sync stream, so feed it through a channel
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Some(chunk) = data.next().await {
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        if let Some(endpoint) = &self.endpoint {
+            endpoint
+                .insert(
+                    &self.bucket,
+                    id,
+                    ReceiverStream::new(rx),
+                    content_type,
+                    metadata,
+                )
+                .await?;
+            return Ok(id);
+        }
+
+        let mut object = self
+            .client
             .object()
-            .create(&self.bucket, data, id, content_type)
+            .create_streamed(
+                &self.bucket,
+                ReceiverStream::new(rx),
+                None,
+                id,
+                content_type,
+            )
             .await?;
         object.metadata = Some(metadata.clone());
         self.client.object().update(&object).await?;
         Ok(id)
     }
 
-    async fn fetch_object(&self, id: &str) -> Result<Vec<u8>, RestError> {
+    async fn fetch_object(&self, id: &str) -> Result<ObjectStream, RestError> {
         log::debug!("Downloading {} from bucket", id);
-        // Get value from bucket
-        match self.client.object().download(&self.bucket, id).await {
-            Ok(v) => Ok(v),
-            Err(e) => {
-                log::error!("\"Got error attempting to fetch id from GCS: {}\"", e);
-                Err(RestError::NotFound)
-            }
+        if let Some(endpoint) = &self.endpoint {
+            return endpoint.download(&self.bucket, id, None).await;
         }
+        self.download(id, 0, usize::MAX).await
+    }
+
+    async fn fetch_range(&self, id: &str, start: u64, end: u64) -> Result<ObjectStream, RestError> {
+        log::debug!("Downloading bytes {}-{} of {} from bucket", start, end, id);
+        if let Some(endpoint) = &self.endpoint {
+            let range = format!("bytes={}-{}", start, end);
+            return endpoint.download(&self.bucket, id, Some(range)).await;
+        }
+        self.download(id, start as usize, (end - start) as usize + 1)
+            .await
     }
 
     async fn delete_object(&self, id: &str) -> Result<(), RestError> {
+        if let Some(endpoint) = &self.endpoint {
+            return endpoint.delete(&self.bucket, id).await;
+        }
+
         // Delete value from bucket
         match self.client.object().delete(&self.bucket, id).await {
             Ok(_) => Ok(()),
+            Err(cloud_storage::Error::Google(e)) if e.error.code == 404 => {
+                log::debug!("\"{} was already gone from GCS\"", id);
+                Ok(())
+            }
             Err(e) => {
-                log::error!("\"Got error attempting to fetch id from GCS: {}\"", e);
-                Err(RestError::NotFound)
+                log::error!("\"Got error attempting to delete id from GCS: {}\"", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn list_objects(&self) -> Result<ObjectListing, RestError> {
+        if let Some(endpoint) = &self.endpoint {
+            return Ok(endpoint.list(&self.bucket));
+        }
+
+        // The GCS listing borrows the client, so page through it on a task of its own
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let client = self.client.clone();
+        let bucket = self.bucket.clone();
+        tokio::spawn(async move {
+            let pages = match client.object().list(&bucket, ListRequest::default()).await {
+                Ok(p) => p,
+                Err(e) => {
+                    let _ = tx.send(Err(RestError::from(e))).await;
+                    return;
+                }
+            };
+            futures::pin_mut!(pages);
+            while let Some(page) = pages.next().await {
+                let objects = match page {
+                    Ok(p) => p
+                        .items
+                        .into_iter()
+                        .map(|o| {
+                            Ok(ObjectInfo {
+                                id: o.name,
+                                modified: o.updated,
+                            })
+                        })
+                        .collect(),
+                    Err(e) => vec![Err(RestError::from(e))],
+                };
+                for object in objects {
+                    if tx.send(object).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(ReceiverStream::new(rx).boxed())
+    }
+    // Urls are signed for storage.googleapis.com, which an endpoint override doesn't serve
+    async fn signed_url(&self, id: &str, seconds: u64) -> Result<Option<String>, RestError> {
+        if self.endpoint.is_some() {
+            return Ok(None);
+        }
+        log::debug!("Signing download url for {} in GCS", id);
+        let object = self.client.object().read(&self.bucket, id).await?;
+        Ok(Some(object.download_url(seconds as u32)?))
+    }
+
+    async fn signed_upload(
+        &self,
+        id: &str,
+        seconds: u64,
+        content_type: &str,
+    ) -> Result<Option<SignedUpload>, RestError> {
+        if self.endpoint.is_some() {
+            return Ok(None);
+        }
+        log::debug!("Signing upload url for {} in GCS", id);
+        let url = self.unwritten_object(id).upload_url(seconds as u32)?;
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_owned(), content_type.to_owned());
+        Ok(Some(SignedUpload { url, headers }))
+    }
+
+    async fn object_size(&self, id: &str) -> Result<u64, RestError> {
+        if let Some(endpoint) = &self.endpoint {
+            return endpoint.size(&self.bucket, id).await;
+        }
+        match self.client.object().read(&self.bucket, id).await {
+            Ok(o) => Ok(o.size),
+            Err(cloud_storage::Error::Google(e)) if e.error.code == 404 => Err(RestError::NotFound),
+            Err(e) => {
+                log::error!("\"Got error attempting to read id from GCS: {}\"", e);
+                Err(e.into())
             }
         }
     }
@@ -0,0 +1,322 @@
+use crate::error::Error as RestError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::storage::trait_storage::{
+    ObjectInfo, ObjectListing, ObjectStream, SignedUpload, Storage,
+};
+
+#[derive(Clone, Debug)]
+pub struct LocalClient {
+    path: Arc<PathBuf>,
+}
+
+// Sidecar persisted next to each object, holding what cloud backends keep as object metadata
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LocalObjectMeta {
+    pub content_type: String,
+    pub metadata: HashMap<String, String>,
+}
+
+impl LocalClient {
+    pub fn new(path: &str, bucket: &str) -> Result<LocalClient, RestError> {
+        let path = Path::new(path).join(bucket);
+        std::fs::create_dir_all(&path)?;
+        Ok(LocalClient {
+            path: Arc::new(path),
+        })
+    }
+
+    fn object_path(&self, id: &str) -> Result<PathBuf, RestError> {
+        // Ids are generated by tackd, but never allow them to escape the storage directory. Sharded
+        // ids are nested in directories, one per prefix.
+        if id.contains('\\') || id.split('/').any(|s| s.is_empty() || s.starts_with('.')) {
+            log::error!("\"Refusing to use invalid object id: {}\"", id);
+            return Err(RestError::NotFound);
+        }
+        Ok(self.path.join(id))
+    }
+
+    fn meta_path(&self, id: &str) -> Result<PathBuf, RestError> {
+        self.object_path(&format!("{}.meta.json", id))
+    }
+
+    // Each write gets its own temp file, so concurrent writes of one id can't interleave
+    fn tmp_path(path: &Path) -> PathBuf {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}.tmp", Uuid::new_v4().simple()));
+        PathBuf::from(tmp)
+    }
+
+    // Write to a temp file first, so that readers never see a partial object
+    async fn write_atomic(path: &Path, data: &[u8]) -> Result<(), RestError> {
+        let tmp = Self::tmp_path(path);
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    async fn write_stream(path: &Path, mut data: ObjectStream) -> Result<(), RestError> {
+        let tmp = Self::tmp_path(path);
+        let mut file = tokio::fs::File::create(&tmp).await?;
+
+        let written: Result<(), RestError> = async {
+            while let Some(chunk) = data.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.sync_all().await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = written {
+            drop(file);
+            if let Err(e) = tokio::fs::remove_file(&tmp).await {
+                log::warn!("\"Unable to remove partial object {:?}: {}\"", tmp, e);
+            }
+            return Err(e);
+        }
+
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LocalClient {
+    async fn insert_object<'a>(
+        &mut self,
+        id: &'a str,
+        data: ObjectStream,
+        content_type: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<&'a str, RestError> {
+        log::debug!("Inserting data into local storage");
+        let sidecar = LocalObjectMeta {
+            content_type: content_type.to_owned(),
+            metadata: metadata.clone(),
+        };
+
+        let path = self.object_path(id)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let meta_path = self.meta_path(id)?;
+        Self::write_atomic(&meta_path, &serde_json::to_vec(&sidecar)?).await?;
+        if let Err(e) = Self::write_stream(&path, data).await {
+            let _ = tokio::fs::remove_file(&meta_path).await;
+            return Err(e);
+        }
+
+        Ok(id)
+    }
+
+    async fn fetch_object(&self, id: &str) -> Result<ObjectStream, RestError> {
+        log::debug!("Reading {} from local storage", id);
+        match tokio::fs::File::open(self.object_path(id)?).await {
+            Ok(f) => Ok(ReaderStream::new(f).map_err(RestError::from).boxed()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(RestError::NotFound),
+            Err(e) => {
+                log::error!(
+                    "\"Got error attempting to read id from local storage: {}\"",
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn fetch_range(&self, id: &str, start: u64, end: u64) -> Result<ObjectStream, RestError> {
+        log::debug!(
+            "Reading bytes {}-{} of {} from local storage",
+            start,
+            end,
+            id
+        );
+        let mut file = match tokio::fs::File::open(self.object_path(id)?).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(RestError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let reader = file.take(end.saturating_sub(start) + 1);
+        Ok(ReaderStream::new(reader).map_err(RestError::from).boxed())
+    }
+
+    async fn delete_object(&self, id: &str) -> Result<(), RestError> {
+        log::debug!("Deleting {} from local storage", id);
+        match tokio::fs::remove_file(self.object_path(id)?).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::debug!("\"{} was already gone from local storage\"", id)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Sidecar is only informational, so a missing one is not an error
+        if let Err(e) = tokio::fs::remove_file(self.meta_path(id)?).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("\"Unable to delete metadata for {}: {}\"", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn list_objects(&self) -> Result<ObjectListing, RestError> {
+        // Walk shard directories depth first, tracking the id prefix of each
+        let root = tokio::fs::read_dir(self.path.as_ref()).await?;
+        Ok(
+            stream::unfold(vec![(root, String::new())], |mut dirs| async move {
+                loop {
+                    let (entries, prefix) = dirs.last_mut()?;
+                    let prefix = prefix.clone();
+                    let entry = match entries.next_entry().await {
+                        Ok(Some(e)) => e,
+                        Ok(None) => {
+                            dirs.pop();
+                            continue;
+                        }
+                        Err(e) => {
+                            dirs.pop();
+                            return Some((Err(e.into()), dirs));
+                        }
+                    };
+
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    let id = format!("{}{}", prefix, name);
+                    match entry.file_type().await {
+                        Ok(t) if t.is_dir() => {
+                            match tokio::fs::read_dir(entry.path()).await {
+                                Ok(d) => dirs.push((d, format!("{}/", id))),
+                                Err(e) => return Some((Err(e.into()), dirs)),
+                            }
+                            continue;
+                        }
+                        Ok(_) => (),
+                        Err(e) => return Some((Err(e.into()), dirs)),
+                    }
+
+                    // Skip sidecars and writes still in progress
+                    if name.ends_with(".meta.json") || name.ends_with(".tmp") {
+                        continue;
+                    }
+                    let info =
+                        entry
+                            .metadata()
+                            .await
+                            .and_then(|m| m.modified())
+                            .map(|m| ObjectInfo {
+                                id,
+                                modified: DateTime::<Utc>::from(m),
+                            });
+                    return Some((info.map_err(RestError::from), dirs));
+                }
+            })
+            .boxed(),
+        )
+    }
+    async fn signed_url(&self, _id: &str, _seconds: u64) -> Result<Option<String>, RestError> {
+        Ok(None)
+    }
+
+    async fn signed_upload(
+        &self,
+        _id: &str,
+        _seconds: u64,
+        _content_type: &str,
+    ) -> Result<Option<SignedUpload>, RestError> {
+        Ok(None)
+    }
+
+    async fn object_size(&self, id: &str) -> Result<u64, RestError> {
+        match tokio::fs::metadata(self.object_path(id)?).await {
+            Ok(m) => Ok(m.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(RestError::NotFound),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn stream_of(data: Vec<u8>) -> ObjectStream {
+        let parts: Vec<Result<Bytes, RestError>> = data
+            .chunks(1000)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        stream::iter(parts).boxed()
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_of_one_object_dont_interleave() {
+        let dir = std::env::temp_dir().join(format!("tackd-local-{}", Uuid::new_v4()));
+        let client = LocalClient::new(dir.to_str().unwrap(), "bucket").unwrap();
+
+        let writes = (0..8u8).map(|n| {
+            let mut client = client.clone();
+            async move {
+                client
+                    .insert_object(
+                        "obj",
+                        stream_of(vec![n; 50000]),
+                        "text/plain",
+                        &HashMap::new(),
+                    )
+                    .await
+                    .map(|_| ())
+            }
+        });
+        futures::future::try_join_all(writes).await.unwrap();
+
+        // One whole write wins, and no temp files are left behind
+        let stored: Vec<u8> = client
+            .fetch_object("obj")
+            .await
+            .unwrap()
+            .map_ok(|b| b.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 50000);
+        assert!(stored.iter().all(|b| *b == stored[0]));
+        let mut names = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir.join("bucket")).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        assert_eq!(names, ["obj", "obj.meta.json"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deleting_a_missing_object_succeeds() {
+        let dir = std::env::temp_dir().join(format!("tackd-local-{}", Uuid::new_v4()));
+        let mut client = LocalClient::new(dir.to_str().unwrap(), "bucket").unwrap();
+        client
+            .insert_object("obj", stream_of(vec![1; 10]), "text/plain", &HashMap::new())
+            .await
+            .unwrap();
+
+        client.delete_object("obj").await.unwrap();
+        client.delete_object("obj").await.unwrap();
+        assert!(matches!(
+            client.fetch_object("obj").await,
+            Err(RestError::NotFound)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
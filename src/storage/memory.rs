@@ -0,0 +1,116 @@
+use crate::error::Error as RestError;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures::{future, stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::storage::trait_storage::{
+    ObjectInfo, ObjectListing, ObjectStream, SignedUpload, Storage,
+};
+
+// Objects only live as long as the process, so this is meant for demos, development and tests
+#[derive(Clone, Debug, Default)]
+pub struct MemoryClient {
+    objects: Arc<DashMap<String, MemoryObject>>,
+}
+
+#[derive(Clone, Debug)]
+struct MemoryObject {
+    data: Bytes,
+    modified: DateTime<Utc>,
+}
+
+impl MemoryClient {
+    pub fn new() -> MemoryClient {
+        MemoryClient::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryClient {
+    async fn insert_object<'a>(
+        &mut self,
+        id: &'a str,
+        mut data: ObjectStream,
+        _content_type: &str,
+        _metadata: &HashMap<String, String>,
+    ) -> Result<&'a str, RestError> {
+        log::debug!("Inserting data into memory");
+        let mut object = BytesMut::new();
+        while let Some(chunk) = data.next().await {
+            object.extend_from_slice(&chunk?);
+        }
+        self.objects.insert(
+            id.to_owned(),
+            MemoryObject {
+                data: object.freeze(),
+                modified: Utc::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    async fn fetch_object(&self, id: &str) -> Result<ObjectStream, RestError> {
+        log::debug!("Reading {} from memory", id);
+        match self.objects.get(id) {
+            Some(object) => Ok(stream::once(future::ready(Ok(object.data.clone()))).boxed()),
+            None => Err(RestError::NotFound),
+        }
+    }
+
+    async fn fetch_range(&self, id: &str, start: u64, end: u64) -> Result<ObjectStream, RestError> {
+        log::debug!("Reading bytes {}-{} of {} from memory", start, end, id);
+        match self.objects.get(id) {
+            Some(object) => {
+                let end = std::cmp::min(end as usize + 1, object.data.len());
+                let start = std::cmp::min(start as usize, end);
+                Ok(stream::once(future::ready(Ok(object.data.slice(start..end)))).boxed())
+            }
+            None => Err(RestError::NotFound),
+        }
+    }
+
+    async fn delete_object(&self, id: &str) -> Result<(), RestError> {
+        log::debug!("Deleting {} from memory", id);
+        self.objects.remove(id);
+        Ok(())
+    }
+
+    async fn list_objects(&self) -> Result<ObjectListing, RestError> {
+        let objects: Vec<Result<ObjectInfo, RestError>> = self
+            .objects
+            .iter()
+            .map(|o| {
+                Ok(ObjectInfo {
+                    id: o.key().clone(),
+                    modified: o.value().modified,
+                })
+            })
+            .collect();
+        Ok(stream::iter(objects).boxed())
+    }
+
+    async fn signed_url(&self, _id: &str, _seconds: u64) -> Result<Option<String>, RestError> {
+        Ok(None)
+    }
+
+    async fn signed_upload(
+        &self,
+        _id: &str,
+        _seconds: u64,
+        _content_type: &str,
+    ) -> Result<Option<SignedUpload>, RestError> {
+        Ok(None)
+    }
+
+    async fn object_size(&self, id: &str) -> Result<u64, RestError> {
+        match self.objects.get(id) {
+            Some(object) => Ok(object.data.len() as u64),
+            None => Err(RestError::NotFound),
+        }
+    }
+}
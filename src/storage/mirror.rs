@@ -0,0 +1,231 @@
+use crate::data::Data;
+use crate::error::Error as RestError;
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::collections::HashMap;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::storage::trait_storage::{
+    ObjectListing, ObjectStream, SignedUpload, Storage, StorageClient,
+};
+
+// Writes every object to two backends, reading from the secondary when the primary is unavailable
+#[derive(Clone, Debug)]
+pub struct MirrorClient {
+    primary: Box<StorageClient>,
+    secondary: Box<StorageClient>,
+    // Copy to the secondary in the background once the primary write has finished
+    lazy: bool,
+}
+
+impl MirrorClient {
+    pub fn new(primary: StorageClient, secondary: StorageClient, lazy: bool) -> MirrorClient {
+        MirrorClient {
+            primary: Box::new(primary),
+            secondary: Box::new(secondary),
+            lazy,
+        }
+    }
+
+    // Feed one stream into two, so both backends are written as data arrives
+    fn tee(mut data: ObjectStream) -> (ObjectStream, ObjectStream) {
+        let (primary_tx, primary_rx) = tokio::sync::mpsc::channel(4);
+        let (secondary_tx, secondary_rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Some(chunk) = data.next().await {
+                let (primary_chunk, secondary_chunk) = match chunk {
+                    Ok(bytes) => (Ok(bytes.clone()), Ok(bytes)),
+                    Err(e) => {
+                        log::error!("\"Mirrored upload failed: {}\"", e);
+                        (Err(e), Err(RestError::BadInsert))
+                    }
+                };
+                let primary_sent = primary_tx.send(primary_chunk).await.is_ok();
+                let secondary_sent = secondary_tx.send(secondary_chunk).await.is_ok();
+                if !primary_sent && !secondary_sent {
+                    break;
+                }
+            }
+        });
+        (
+            ReceiverStream::new(primary_rx).boxed(),
+            ReceiverStream::new(secondary_rx).boxed(),
+        )
+    }
+
+    fn copy_to_secondary(&self, id: &str, content_type: &str, metadata: &HashMap<String, String>) {
+        let primary = self.primary.clone();
+        let mut secondary = self.secondary.clone();
+        let id = id.to_owned();
+        let content_type = content_type.to_owned();
+        let metadata = metadata.clone();
+        tokio::spawn(async move {
+            let copied = match primary.fetch_object(&id).await {
+                Ok(data) => secondary
+                    .insert_object(&id, data, &content_type, &metadata)
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = copied {
+                log::error!("\"Unable to copy {} to secondary storage: {}\"", id, e);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Storage for MirrorClient {
+    async fn insert_object<'a>(
+        &mut self,
+        id: &'a str,
+        data: ObjectStream,
+        content_type: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<&'a str, RestError> {
+        if self.lazy {
+            self.primary
+                .insert_object(id, data, content_type, metadata)
+                .await?;
+            self.copy_to_secondary(id, content_type, metadata);
+            return Ok(id);
+        }
+
+        let (primary_data, secondary_data) = MirrorClient::tee(data);
+        let written = tokio::try_join!(
+            self.primary
+                .insert_object(id, primary_data, content_type, metadata),
+            self.secondary
+                .insert_object(id, secondary_data, content_type, metadata),
+        );
+
+        // Don't leave a copy behind in one backend if the other failed
+        if let Err(e) = written {
+            log::error!("\"Mirrored insert of {} failed: {}\"", id, e);
+            let _ = self.primary.delete_object(id).await;
+            let _ = self.secondary.delete_object(id).await;
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    // Some backends only fail once the stream is read, so the first chunk is pulled before
+    // committing to the primary. Failover doesn't cover errors later in a download.
+    async fn fetch_object(&self, id: &str) -> Result<ObjectStream, RestError> {
+        let primary = match self.primary.fetch_object(id).await {
+            Ok(data) => Data::prime(data).await,
+            Err(e) => Err(e),
+        };
+        match primary {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                log::warn!("\"Reading {} from secondary storage: {}\"", id, e);
+                self.secondary.fetch_object(id).await
+            }
+        }
+    }
+
+    async fn fetch_range(&self, id: &str, start: u64, end: u64) -> Result<ObjectStream, RestError> {
+        let primary = match self.primary.fetch_range(id, start, end).await {
+            Ok(data) => Data::prime(data).await,
+            Err(e) => Err(e),
+        };
+        match primary {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                log::warn!("\"Reading {} from secondary storage: {}\"", id, e);
+                self.secondary.fetch_range(id, start, end).await
+            }
+        }
+    }
+
+    // Only done once neither backend still holds the object, so a failure on either side is
+    // returned and the whole delete retried. Deleting again from the side that succeeded just
+    // succeeds again.
+    async fn delete_object(&self, id: &str) -> Result<(), RestError> {
+        let primary = self.primary.delete_object(id).await;
+        let secondary = self.secondary.delete_object(id).await;
+        match (primary, secondary) {
+            (Ok(_), Ok(_)) => Ok(()),
+            (Err(e), _) | (_, Err(e)) => {
+                log::warn!("\"{} was not deleted from both backends: {}\"", id, e);
+                Err(e)
+            }
+        }
+    }
+
+    // The primary is the source of truth, the secondary only holds copies of its objects
+    async fn list_objects(&self) -> Result<ObjectListing, RestError> {
+        self.primary.list_objects().await
+    }
+
+    async fn signed_url(&self, id: &str, seconds: u64) -> Result<Option<String>, RestError> {
+        match self.primary.signed_url(id, seconds).await {
+            Ok(url) => Ok(url),
+            Err(e) => {
+                log::warn!("\"Signing {} with secondary storage: {}\"", id, e);
+                self.secondary.signed_url(id, seconds).await
+            }
+        }
+    }
+
+    // An object written straight to the primary would never be copied to the secondary
+    async fn signed_upload(
+        &self,
+        _id: &str,
+        _seconds: u64,
+        _content_type: &str,
+    ) -> Result<Option<SignedUpload>, RestError> {
+        Ok(None)
+    }
+
+    async fn object_size(&self, id: &str) -> Result<u64, RestError> {
+        match self.primary.object_size(id).await {
+            Ok(size) => Ok(size),
+            Err(e) => {
+                log::warn!(
+                    "\"Reading the size of {} from secondary storage: {}\"",
+                    id,
+                    e
+                );
+                self.secondary.object_size(id).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryClient;
+    use bytes::Bytes;
+    use futures::{stream, TryStreamExt};
+
+    #[tokio::test]
+    async fn falls_back_to_the_secondary() {
+        let primary = StorageClient::MemoryClient(MemoryClient::new());
+        let mut secondary = StorageClient::MemoryClient(MemoryClient::new());
+        let data: ObjectStream = stream::iter(vec![Ok(Bytes::from_static(b"mirrored"))]).boxed();
+        secondary
+            .insert_object("obj", data, "text/plain", &HashMap::new())
+            .await
+            .unwrap();
+
+        // The object only made it to the secondary
+        let mirror = MirrorClient::new(primary, secondary, false);
+        assert_eq!(mirror.object_size("obj").await.unwrap(), 8);
+        let read: Vec<u8> = mirror
+            .fetch_object("obj")
+            .await
+            .unwrap()
+            .map_ok(|b| b.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(read, b"mirrored");
+        assert!(matches!(
+            mirror.object_size("other").await,
+            Err(RestError::NotFound)
+        ));
+    }
+}
@@ -1,3 +1,8 @@
 pub mod azure_blob;
 pub mod gcs;
+pub mod local;
+pub mod memory;
+pub mod mirror;
+pub mod s3;
+pub mod segments;
 pub mod trait_storage;
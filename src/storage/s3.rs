@@ -0,0 +1,445 @@
+use crate::error::Error as RestError;
+use async_trait::async_trait;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use bytes::BytesMut;
+use chrono::{TimeZone, Utc};
+use futures::{stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::storage::trait_storage::{
+    ObjectInfo, ObjectListing, ObjectStream, SignedUpload, Storage,
+};
+
+// S3 requires every part but the last to be at least 5MiB
+const PART_SIZE: usize = 8388608;
+
+#[derive(Clone, Debug)]
+pub struct S3Client {
+    bucket: String,
+    client: Arc<aws_sdk_s3::Client>,
+}
+
+impl S3Client {
+    pub fn new(bucket: &str, client: aws_sdk_s3::Client) -> S3Client {
+        S3Client {
+            bucket: bucket.to_owned(),
+            client: Arc::new(client),
+        }
+    }
+
+    async fn get_object(&self, id: &str, range: Option<String>) -> Result<ObjectStream, RestError> {
+        let object = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(id)
+            .set_range(range)
+            .send()
+            .await
+        {
+            Ok(o) => o,
+            Err(e) => {
+                if e.as_service_error().map(|s| s.is_no_such_key()) == Some(true) {
+                    log::error!("\"Object {} is missing from S3\"", id);
+                    return Err(RestError::NotFound);
+                }
+                return Err(aws_sdk_s3::Error::from(e).into());
+            }
+        };
+
+        Ok(stream::unfold(object.body, |mut body| async move {
+            let chunk = body.next().await?;
+            Some((chunk.map_err(RestError::from), body))
+        })
+        .boxed())
+    }
+
+    // Upload parts as data arrives, returning the completed parts. Small objects skip multipart entirely.
+    async fn upload_parts(
+        &self,
+        id: &str,
+        mut data: ObjectStream,
+        content_type: &str,
+        metadata: &HashMap<String, String>,
+        upload_id: &mut Option<String>,
+    ) -> Result<Vec<CompletedPart>, RestError> {
+        let mut parts = Vec::new();
+        let mut buffer = BytesMut::new();
+        let mut finished = false;
+
+        while !finished {
+            match data.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => finished = true,
+            }
+
+            if finished && upload_id.is_none() {
+                log::debug!("Object fits in a single part, using put_object");
+                self.client
+                    .put_object()
+                    .bucket(&self.bucket)
+                    .key(id)
+                    .body(ByteStream::from(buffer.split().freeze()))
+                    .content_type(content_type)
+                    .set_metadata(Some(metadata.clone()))
+                    .send()
+                    .await
+                    .map_err(aws_sdk_s3::Error::from)?;
+                break;
+            }
+
+            if buffer.len() >= PART_SIZE || (finished && !buffer.is_empty()) {
+                let current_upload_id = match upload_id {
+                    Some(u) => u.clone(),
+                    None => {
+                        let created = self
+                            .client
+                            .create_multipart_upload()
+                            .bucket(&self.bucket)
+                            .key(id)
+                            .content_type(content_type)
+                            .set_metadata(Some(metadata.clone()))
+                            .send()
+                            .await
+                            .map_err(aws_sdk_s3::Error::from)?;
+                        let created_id = created.upload_id().unwrap_or_default().to_owned();
+                        *upload_id = Some(created_id.clone());
+                        created_id
+                    }
+                };
+
+                let part_number = parts.len() as i32 + 1;
+                log::debug!("Uploading part {} of {} bytes", part_number, buffer.len());
+                let part = self
+                    .client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(id)
+                    .upload_id(current_upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(buffer.split().freeze()))
+                    .send()
+                    .await
+                    .map_err(aws_sdk_s3::Error::from)?;
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(part.e_tag().map(|t| t.to_owned()))
+                        .build(),
+                );
+            }
+        }
+        Ok(parts)
+    }
+}
+
+#[async_trait]
+impl Storage for S3Client {
+    async fn insert_object<'a>(
+        &mut self,
+        id: &'a str,
+        data: ObjectStream,
+        content_type: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<&'a str, RestError> {
+        log::debug!("Inserting data into S3");
+        let mut upload_id = None;
+        let parts = match self
+            .upload_parts(id, data, content_type, metadata, &mut upload_id)
+            .await
+        {
+            Ok(p) => p,
+            Err(e) => {
+                // Don't leave orphaned parts behind
+                if let Some(u) = upload_id {
+                    if let Err(e) = self
+                        .client
+                        .abort_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(id)
+                        .upload_id(u)
+                        .send()
+                        .await
+                    {
+                        log::error!("\"Unable to abort multipart upload for {}: {}\"", id, e);
+                    }
+                }
+                return Err(e);
+            }
+        };
+
+        if let Some(u) = upload_id {
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(id)
+                .upload_id(u)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send()
+                .await
+                .map_err(aws_sdk_s3::Error::from)?;
+        }
+
+        Ok(id)
+    }
+
+    async fn fetch_object(&self, id: &str) -> Result<ObjectStream, RestError> {
+        log::debug!("Downloading {} from S3", id);
+        self.get_object(id, None).await
+    }
+
+    async fn fetch_range(&self, id: &str, start: u64, end: u64) -> Result<ObjectStream, RestError> {
+        log::debug!("Downloading bytes {}-{} of {} from S3", start, end, id);
+        self.get_object(id, Some(format!("bytes={}-{}", start, end)))
+            .await
+    }
+
+    async fn delete_object(&self, id: &str) -> Result<(), RestError> {
+        log::debug!("Deleting {} from S3", id);
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(id)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        Ok(())
+    }
+
+    async fn list_objects(&self) -> Result<ObjectListing, RestError> {
+        let pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .into_paginator()
+            .send();
+
+        Ok(stream::unfold(pages, |mut pages| async move {
+            let page = match pages.next().await? {
+                Ok(p) => p,
+                Err(e) => return Some((vec![Err(aws_sdk_s3::Error::from(e).into())], pages)),
+            };
+            let objects = page
+                .contents()
+                .iter()
+                .filter_map(|o| {
+                    let modified = o
+                        .last_modified()
+                        .and_then(|m| Utc.timestamp_opt(m.secs(), 0).single())
+                        .unwrap_or_else(Utc::now);
+                    o.key().map(|k| {
+                        Ok(ObjectInfo {
+                            id: k.to_owned(),
+                            modified,
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            Some((objects, pages))
+        })
+        .flat_map(stream::iter)
+        .boxed())
+    }
+    async fn signed_url(&self, id: &str, seconds: u64) -> Result<Option<String>, RestError> {
+        log::debug!("Signing download url for {} in S3", id);
+        let config = PresigningConfig::expires_in(std::time::Duration::from_secs(seconds))?;
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(id)
+            .presigned(config)
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        Ok(Some(request.uri().to_owned()))
+    }
+
+    async fn signed_upload(
+        &self,
+        id: &str,
+        seconds: u64,
+        content_type: &str,
+    ) -> Result<Option<SignedUpload>, RestError> {
+        log::debug!("Signing upload url for {} in S3", id);
+        let config = PresigningConfig::expires_in(std::time::Duration::from_secs(seconds))?;
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(id)
+            .content_type(content_type)
+            .presigned(config)
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        Ok(Some(SignedUpload {
+            url: request.uri().to_owned(),
+            headers: request
+                .headers()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        }))
+    }
+
+    async fn object_size(&self, id: &str) -> Result<u64, RestError> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(id)
+            .send()
+            .await
+        {
+            Ok(o) => Ok(o.content_length().unwrap_or_default() as u64),
+            Err(e) => {
+                if e.as_service_error().map(|s| s.is_not_found()) == Some(true) {
+                    return Err(RestError::NotFound);
+                }
+                Err(aws_sdk_s3::Error::from(e).into())
+            }
+        }
+    }
+}
+
+// Runs against an S3 compatible server such as MinIO, as in the emulators workflow, so these are
+// ignored unless run with --ignored and TACKD_TEST_S3_ENDPOINT set
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use rand::RngCore;
+
+    fn client() -> S3Client {
+        let endpoint =
+            std::env::var("TACKD_TEST_S3_ENDPOINT").expect("TACKD_TEST_S3_ENDPOINT is set");
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_owned());
+        let credentials = aws_sdk_s3::config::Credentials::new(
+            env("TACKD_TEST_S3_ACCESS_KEY_ID", "minioadmin"),
+            env("TACKD_TEST_S3_SECRET_ACCESS_KEY", "minioadmin"),
+            None,
+            None,
+            "tackd",
+        );
+        let config = aws_sdk_s3::config::Builder::new()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .credentials_provider(credentials)
+            .endpoint_url(endpoint)
+            .force_path_style(true)
+            .build();
+        S3Client::new(
+            &env("TACKD_TEST_BUCKET", "tackd"),
+            aws_sdk_s3::Client::from_conf(config),
+        )
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    fn stream_of(data: &[u8]) -> ObjectStream {
+        let parts: Vec<Result<Bytes, RestError>> = data
+            .chunks(65536)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        stream::iter(parts).boxed()
+    }
+
+    async fn collect(input: ObjectStream) -> Vec<u8> {
+        input.map_ok(|b| b.to_vec()).try_concat().await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn stores_and_reads_objects() {
+        let mut client = client();
+        let id = uuid::Uuid::new_v4().to_string();
+        let data = payload(100_000);
+        let mut metadata = HashMap::new();
+        metadata.insert("upload".to_owned(), "abc".to_owned());
+
+        client
+            .insert_object(&id, stream_of(&data), "text/plain", &metadata)
+            .await
+            .unwrap();
+        let head = client
+            .client
+            .head_object()
+            .bucket(&client.bucket)
+            .key(&id)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(head.content_type(), Some("text/plain"));
+        assert_eq!(head.metadata(), Some(&metadata));
+
+        assert_eq!(collect(client.fetch_object(&id).await.unwrap()).await, data);
+        assert_eq!(
+            collect(client.fetch_range(&id, 70_000, 89_999).await.unwrap()).await,
+            &data[70_000..90_000]
+        );
+        assert_eq!(client.object_size(&id).await.unwrap(), 100_000);
+
+        let listed: Vec<ObjectInfo> = client
+            .list_objects()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(listed.iter().any(|o| o.id == id));
+
+        client.delete_object(&id).await.unwrap();
+        assert!(matches!(
+            client.fetch_object(&id).await,
+            Err(RestError::NotFound)
+        ));
+        assert!(matches!(
+            client.object_size(&id).await,
+            Err(RestError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn uploads_large_objects_in_parts() {
+        let mut client = client();
+        let id = uuid::Uuid::new_v4().to_string();
+        let data = payload(PART_SIZE + 100_000);
+
+        client
+            .insert_object(
+                &id,
+                stream_of(&data),
+                "application/octet-stream",
+                &HashMap::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(client.object_size(&id).await.unwrap(), data.len() as u64);
+        assert_eq!(collect(client.fetch_object(&id).await.unwrap()).await, data);
+
+        // A range spanning the boundary between the two parts
+        let (start, end) = (PART_SIZE - 1000, PART_SIZE + 999);
+        assert_eq!(
+            collect(
+                client
+                    .fetch_range(&id, start as u64, end as u64)
+                    .await
+                    .unwrap()
+            )
+            .await,
+            &data[start..=end]
+        );
+        client.delete_object(&id).await.unwrap();
+    }
+}
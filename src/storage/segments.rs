@@ -0,0 +1,544 @@
+use crate::error::Error as RestError;
+use axum::body::Bytes;
+use bytes::BytesMut;
+use futures::{future, stream, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::data::{Data, CHUNK_PREFIX_SIZE};
+use crate::database::metadata::Segment;
+use crate::storage::trait_storage::{ObjectStream, Storage, StorageClient};
+
+// Segments are pieces of an upload's stored bytes, so they carry no content type of their own
+pub const SEGMENT_CONTENT_TYPE: &str = "application/octet-stream";
+
+// Bytes read from storage ahead of what has been sent, whatever segments they are from
+const READ_AHEAD_BYTES: usize = 4194304;
+
+// Each segment is read this many times before the download fails
+const SEGMENT_ATTEMPTS: u32 = 3;
+const SEGMENT_RETRY_MILLIS: u64 = 500;
+
+// Data being written out as segments, each taking its share and leaving the rest for the next
+struct Source {
+    input: ObjectStream,
+    pending: Option<Bytes>,
+}
+
+impl Source {
+    async fn next(&mut self) -> Option<Result<Bytes, RestError>> {
+        match self.pending.take() {
+            Some(bytes) => Some(Ok(bytes)),
+            None => self.input.next().await,
+        }
+    }
+
+    // Stream the next limit bytes, counting them as they go
+    fn take(source: Arc<Mutex<Source>>, limit: usize, written: Arc<AtomicU64>) -> ObjectStream {
+        Box::pin(stream::unfold(
+            (source, limit),
+            move |(source, remaining)| {
+                let written = written.clone();
+                async move {
+                    if remaining == 0 {
+                        return None;
+                    }
+                    let next = {
+                        let mut s = source.lock().await;
+                        match s.next().await? {
+                            Ok(mut bytes) => {
+                                if bytes.len() > remaining {
+                                    s.pending = Some(bytes.split_off(remaining));
+                                }
+                                Ok(bytes)
+                            }
+                            Err(e) => Err(e),
+                        }
+                    };
+                    match next {
+                        Ok(bytes) => {
+                            written.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                            let remaining = remaining - bytes.len();
+                            Some((Ok(bytes), (source, remaining)))
+                        }
+                        Err(e) => Some((Err(e), (source, 0))),
+                    }
+                }
+            },
+        ))
+    }
+}
+
+pub fn segment_id(id: &str, index: usize) -> String {
+    format!("{}.{}", id, index)
+}
+
+// Store data under id, split into objects of segment_size bytes when it is larger than that. The
+// first segment is written under id itself, so data that fits is simply stored as one object.
+// Returns the segments written, or None when the data fit in a single object.
+pub async fn insert(
+    storage: &mut StorageClient,
+    id: &str,
+    data: ObjectStream,
+    segment_size: usize,
+    content_type: &str,
+    metadata: &HashMap<String, String>,
+) -> Result<Option<Vec<Segment>>, RestError> {
+    let mut segments = Vec::new();
+    match insert_segments(
+        storage,
+        id,
+        data,
+        segment_size,
+        content_type,
+        metadata,
+        &mut segments,
+    )
+    .await
+    {
+        Ok(_) if segments.len() > 1 => Ok(Some(segments)),
+        Ok(_) => Ok(None),
+        Err(e) => {
+            // Don't leave the segments already written behind
+            for segment in segments.iter() {
+                if let Err(e) = storage.delete_object(&segment.id).await {
+                    log::error!("\"Unable to delete segment {}: {}\"", &segment.id, e);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+// Segments are streamed to storage as the data arrives, so a failed segment fails the upload
+async fn insert_segments(
+    storage: &mut StorageClient,
+    id: &str,
+    data: ObjectStream,
+    segment_size: usize,
+    content_type: &str,
+    metadata: &HashMap<String, String>,
+    segments: &mut Vec<Segment>,
+) -> Result<(), RestError> {
+    // Fused, as the source is checked for more data again once a segment has ended
+    let source = Arc::new(Mutex::new(Source {
+        input: data.fuse().boxed(),
+        pending: None,
+    }));
+
+    loop {
+        let (segment, segment_type) = match segments.len() {
+            0 => (id.to_owned(), content_type),
+            index => (segment_id(id, index), SEGMENT_CONTENT_TYPE),
+        };
+        log::debug!("Writing segment {}", &segment);
+        let written = Arc::new(AtomicU64::new(0));
+        let value = Source::take(source.clone(), segment_size, written.clone());
+        storage
+            .insert_object(&segment, value, segment_type, metadata)
+            .await?;
+        segments.push(Segment {
+            id: segment,
+            bytes: written.load(Ordering::Relaxed),
+        });
+
+        // Only start another segment once more data is known to follow
+        let mut source = source.lock().await;
+        loop {
+            match source.next().await {
+                Some(Ok(bytes)) if bytes.is_empty() => continue,
+                Some(Ok(bytes)) => {
+                    source.pending = Some(bytes);
+                    break;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+// Segment, and the bytes from and to of it, inclusive, holding bytes start to end of the data
+// segments of sizes make up
+fn overlaps(sizes: &[u64], start: u64, end: u64) -> Vec<(usize, u64, u64)> {
+    let mut spans = Vec::new();
+    let mut offset = 0;
+    for (index, size) in sizes.iter().enumerate() {
+        let (first, last) = (offset, offset + size.saturating_sub(1));
+        offset += size;
+        if *size == 0 || last < start || first > end {
+            continue;
+        }
+        let from = start.saturating_sub(first);
+        let to = std::cmp::min(end, last) - first;
+        spans.push((index, from, to));
+    }
+    spans
+}
+
+// Read bytes start to end, inclusive, of segmented data. Each segment's bytes are passed on as
+// they arrive, and a segment is picked up where it stopped if storage fails part way through it.
+pub fn fetch(storage: &StorageClient, segments: &[Segment], start: u64, end: u64) -> ObjectStream {
+    let sizes: Vec<u64> = segments.iter().map(|s| s.bytes).collect();
+    let reads: Vec<(String, u64, u64, bool)> = overlaps(&sizes, start, end)
+        .into_iter()
+        .map(|(index, from, to)| {
+            let segment = &segments[index];
+            let whole = from == 0 && to + 1 == segment.bytes;
+            (segment.id.clone(), from, to, whole)
+        })
+        .collect();
+
+    let storage = storage.clone();
+    let data = stream::iter(reads)
+        .map(move |(id, from, to, whole)| read_segment(storage.clone(), id, from, to, whole))
+        .flatten()
+        .boxed();
+    read_ahead(data)
+}
+
+// Read plaintext bytes start to end, inclusive, of segments that were each sealed on their own.
+// Only the chunks holding the range are fetched from each segment, which is decrypted by itself.
+pub fn fetch_sealed(
+    storage: &StorageClient,
+    segments: &[Segment],
+    key: &str,
+    start: u64,
+    end: u64,
+) -> ObjectStream {
+    let sizes: Vec<u64> = segments
+        .iter()
+        .map(|s| Data::plaintext_len(s.bytes as usize) as u64)
+        .collect();
+    let reads: Vec<(Segment, u64, u64)> = overlaps(&sizes, start, end)
+        .into_iter()
+        .map(|(index, from, to)| (segments[index].clone(), from, to))
+        .collect();
+
+    let storage = storage.clone();
+    let key = key.to_owned();
+    let data = stream::iter(reads)
+        .then(move |(segment, from, to)| {
+            let storage = storage.clone();
+            let key = key.clone();
+            async move { open_segment(storage, &segment, &key, from, to).await }
+        })
+        .map(|opened| match opened {
+            Ok(plaintext) => plaintext,
+            Err(e) => stream::once(future::ready(Err(e))).boxed(),
+        })
+        .flatten()
+        .boxed();
+    read_ahead(data)
+}
+
+async fn open_segment(
+    storage: StorageClient,
+    segment: &Segment,
+    key: &str,
+    from: u64,
+    to: u64,
+) -> Result<ObjectStream, RestError> {
+    let span = Data::chunk_span(segment.bytes as usize, from, to);
+    let prefix_size = CHUNK_PREFIX_SIZE as u64;
+    let prefix = read_segment(
+        storage.clone(),
+        segment.id.clone(),
+        0,
+        prefix_size - 1,
+        false,
+    )
+    .try_fold(BytesMut::new(), |mut buffer, chunk| async move {
+        buffer.extend_from_slice(&chunk);
+        Ok(buffer)
+    })
+    .await?;
+
+    let ciphertext = read_segment(storage, segment.id.clone(), span.from, span.to, false);
+    let plaintext = Data::decrypt_chunks(key, &prefix, span.first, span.reaches_end, ciphertext)?;
+    Ok(Data::slice(plaintext, span.skip, span.take))
+}
+
+// A read of bytes from to to, inclusive, of one segment, and how far it got
+struct SegmentRead {
+    storage: StorageClient,
+    id: String,
+    from: u64,
+    to: u64,
+    whole: bool,
+    read: u64,
+    attempt: u32,
+}
+
+impl SegmentRead {
+    async fn open(&self) -> Result<ObjectStream, RestError> {
+        match self.whole && self.read == 0 {
+            true => self.storage.fetch_object(&self.id).await,
+            false => {
+                self.storage
+                    .fetch_range(&self.id, self.from + self.read, self.to)
+                    .await
+            }
+        }
+    }
+
+    // Whether the read should be picked up again after the error
+    async fn retry(&mut self, e: &RestError) -> bool {
+        match e {
+            RestError::NotFound => {
+                log::error!("\"Segment {} is missing from storage\"", self.id);
+                false
+            }
+            _ if self.attempt < SEGMENT_ATTEMPTS => {
+                log::warn!("\"Retrying read of segment {}: {}\"", self.id, e);
+                tokio::time::sleep(Duration::from_millis(
+                    SEGMENT_RETRY_MILLIS * self.attempt as u64,
+                ))
+                .await;
+                self.attempt += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn read_segment(
+    storage: StorageClient,
+    id: String,
+    from: u64,
+    to: u64,
+    whole: bool,
+) -> ObjectStream {
+    let read = SegmentRead {
+        storage,
+        id,
+        from,
+        to,
+        whole,
+        read: 0,
+        attempt: 1,
+    };
+    // The body being read is dropped on errors, and opened again where the read got to
+    Box::pin(stream::unfold(Some((read, None)), |state| async move {
+        let (mut read, mut body): (SegmentRead, Option<ObjectStream>) = state?;
+        loop {
+            if read.from + read.read > read.to {
+                return None;
+            }
+            let mut current = match body.take() {
+                Some(b) => b,
+                None => match read.open().await {
+                    Ok(b) => b,
+                    Err(e) if read.retry(&e).await => continue,
+                    Err(e) => return Some((Err(e), None)),
+                },
+            };
+            match current.next().await {
+                Some(Ok(bytes)) => {
+                    read.read += bytes.len() as u64;
+                    return Some((Ok(bytes), Some((read, Some(current)))));
+                }
+                Some(Err(e)) if read.retry(&e).await => continue,
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => return None,
+            }
+        }
+    }))
+}
+
+// Read data from storage on a task of its own, up to READ_AHEAD_BYTES ahead of what has been
+// taken, so the next segment is already being read when one ends
+fn read_ahead(mut input: ObjectStream) -> ObjectStream {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let budget = Arc::new(Semaphore::new(READ_AHEAD_BYTES));
+    tokio::spawn(async move {
+        while let Some(chunk) = input.next().await {
+            let size = chunk.as_ref().map_or(0, |b| b.len()).min(READ_AHEAD_BYTES);
+            let permit = match budget.clone().acquire_many_owned(size as u32).await {
+                Ok(p) => p,
+                Err(_) => return,
+            };
+            if tx.send((chunk, permit)).is_err() {
+                return;
+            }
+        }
+    });
+    // Bytes are released from the budget as they are taken
+    UnboundedReceiverStream::new(rx)
+        .map(|(chunk, _permit)| chunk)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::CHUNK_SIZE;
+    use crate::storage::memory::MemoryClient;
+    use rand::RngCore;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn payload(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    fn stream_of(data: &[u8]) -> ObjectStream {
+        let parts: Vec<Result<Bytes, RestError>> = data
+            .chunks(7000)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        stream::iter(parts).boxed()
+    }
+
+    async fn collect(input: ObjectStream) -> Vec<u8> {
+        input.map_ok(|b| b.to_vec()).try_concat().await.unwrap()
+    }
+
+    #[test]
+    fn overlaps_maps_ranges_onto_segments() {
+        let sizes = [10, 10, 5];
+        assert_eq!(overlaps(&sizes, 0, 24), [(0, 0, 9), (1, 0, 9), (2, 0, 4)]);
+        assert_eq!(overlaps(&sizes, 5, 14), [(0, 5, 9), (1, 0, 4)]);
+        assert_eq!(overlaps(&sizes, 10, 10), [(1, 0, 0)]);
+        assert_eq!(overlaps(&sizes, 9, 10), [(0, 9, 9), (1, 0, 0)]);
+        assert_eq!(overlaps(&sizes, 24, 24), [(2, 4, 4)]);
+        assert_eq!(overlaps(&sizes, 20, 100), [(2, 0, 4)]);
+        assert!(overlaps(&sizes, 25, 30).is_empty());
+    }
+
+    #[test]
+    fn overlaps_skips_empty_segments() {
+        assert_eq!(overlaps(&[10, 0, 10], 5, 15), [(0, 5, 9), (2, 0, 5)]);
+        assert!(overlaps(&[0], 0, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn inserts_and_fetches_segments() {
+        let mut storage = StorageClient::MemoryClient(MemoryClient::new());
+        let data = payload(25_000);
+        let segments = insert(
+            &mut storage,
+            "upload",
+            stream_of(&data),
+            10_000,
+            SEGMENT_CONTENT_TYPE,
+            &HashMap::new(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let ids: Vec<&str> = segments.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["upload", "upload.1", "upload.2"]);
+        let sizes: Vec<u64> = segments.iter().map(|s| s.bytes).collect();
+        assert_eq!(sizes, [10_000, 10_000, 5_000]);
+
+        for (start, end) in [
+            (0, 24_999),
+            (0, 0),
+            (9_999, 10_000),
+            (12_345, 23_456),
+            (24_999, 24_999),
+        ] {
+            let range = collect(fetch(&storage, &segments, start, end)).await;
+            assert_eq!(
+                range,
+                &data[start as usize..=end as usize],
+                "range {}-{}",
+                start,
+                end
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn read_ahead_is_capped_in_bytes() {
+        let data = payload(4 * READ_AHEAD_BYTES);
+        let pulled = Arc::new(AtomicU64::new(0));
+        let counter = pulled.clone();
+        let input = stream_of(&data)
+            .inspect(move |chunk| {
+                if let Ok(bytes) = chunk {
+                    counter.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                }
+            })
+            .boxed();
+
+        let mut output = read_ahead(input);
+        let first = output.next().await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pulled.load(Ordering::Relaxed) <= (READ_AHEAD_BYTES + 2 * 7000) as u64);
+
+        let rest = collect(output).await;
+        assert_eq!([first.to_vec(), rest].concat(), data);
+    }
+
+    #[tokio::test]
+    async fn data_that_fits_is_one_object() {
+        let mut storage = StorageClient::MemoryClient(MemoryClient::new());
+        let data = payload(10_000);
+        let segments = insert(
+            &mut storage,
+            "upload",
+            stream_of(&data),
+            10_000,
+            SEGMENT_CONTENT_TYPE,
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert!(segments.is_none());
+        assert_eq!(
+            collect(storage.fetch_object("upload").await.unwrap()).await,
+            data
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_ranges_of_sealed_segments() {
+        let mut storage = StorageClient::MemoryClient(MemoryClient::new());
+        let segment = 2 * CHUNK_SIZE;
+        let data = payload(5 * CHUNK_SIZE + 123);
+        let sealed = Data::encrypt_segments(KEY, stream_of(&data), segment).unwrap();
+        let segments = insert(
+            &mut storage,
+            "upload",
+            sealed,
+            Data::sealed_len(segment),
+            SEGMENT_CONTENT_TYPE,
+            &HashMap::new(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(segments.len(), 3);
+
+        let chunk = CHUNK_SIZE as u64;
+        let last = data.len() as u64 - 1;
+        for (start, end) in [
+            (0, last),
+            (0, 0),
+            (chunk - 1, chunk),
+            (2 * chunk - 1, 2 * chunk),
+            (chunk + 5, 4 * chunk + 5),
+            (4 * chunk, last),
+            (last, last),
+        ] {
+            let range = collect(fetch_sealed(&storage, &segments, KEY, start, end)).await;
+            assert_eq!(
+                range,
+                &data[start as usize..=end as usize],
+                "range {}-{}",
+                start,
+                end
+            );
+        }
+    }
+}
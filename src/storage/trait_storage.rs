@@ -1,10 +1,125 @@
 use async_trait::async_trait;
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
 use enum_dispatch::enum_dispatch;
+use futures::stream::BoxStream;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::path::Path;
 
 use crate::error::Error as RestError;
 use crate::storage::azure_blob::AzureBlobClient;
 use crate::storage::gcs::GcsClient;
+use crate::storage::local::LocalClient;
+use crate::storage::memory::MemoryClient;
+use crate::storage::mirror::MirrorClient;
+use crate::storage::s3::S3Client;
+
+// Objects are passed to and from storage as streams, so they never have to be held in memory whole
+pub type ObjectStream = BoxStream<'static, Result<Bytes, RestError>>;
+
+// Buckets can hold any number of objects, so listings are streamed as well
+pub type ObjectListing = BoxStream<'static, Result<ObjectInfo, RestError>>;
+
+// Directory objects are stored under when they are kept in --data_dir
+const DATA_DIR_OBJECTS: &str = "objects";
+
+// Clap needs argument names and help as static strings, so the per side arguments are spelled out
+// from the option names at compile time
+macro_rules! storage_side_opts {
+    ($(($name:literal, $takes_value:expr)),* $(,)?) => {
+        // Storage options migrate-storage also takes once per side, as --from_<name> and
+        // --to_<name>, and whether they take a value
+        pub const STORAGE_SIDE_OPTS: &[(&str, bool)] = &[$(($name, $takes_value)),*];
+
+        // Name, help and whether it takes a value, for each of those arguments
+        pub const STORAGE_SIDE_ARGS: &[(&str, &str, bool)] = &[
+            $((
+                concat!("from_", $name),
+                concat!("Set --", $name, " for the storage to copy from"),
+                $takes_value,
+            ),)*
+            $((
+                concat!("to_", $name),
+                concat!("Set --", $name, " for the storage to copy to"),
+                $takes_value,
+            ),)*
+        ];
+    };
+}
+
+storage_side_opts![
+    ("storage_path", true),
+    ("gcs_endpoint", true),
+    ("azure_connection_string", true),
+    ("azure_sas_token", true),
+    ("azure_storage_account", true),
+    ("azure_storage_access_key", true),
+    ("azure_endpoint", true),
+    ("s3_endpoint", true),
+    ("s3_region", true),
+    ("s3_access_key_id", true),
+    ("s3_secret_access_key", true),
+    ("s3_path_style", false),
+];
+
+// Options a backend is built from. When one side of a migration is built, its own options are
+// used over the regular ones, so both sides can use different accounts or paths.
+#[derive(Clone, Copy)]
+pub struct StorageOpts<'a> {
+    opts: &'a ArgMatches,
+    side: Option<(&'a ArgMatches, &'a str)>,
+}
+
+impl<'a> StorageOpts<'a> {
+    pub fn new(opts: &'a ArgMatches) -> StorageOpts<'a> {
+        StorageOpts { opts, side: None }
+    }
+
+    pub fn side(opts: &'a ArgMatches, side_opts: &'a ArgMatches, side: &'a str) -> StorageOpts<'a> {
+        StorageOpts {
+            opts,
+            side: Some((side_opts, side)),
+        }
+    }
+
+    // Only options in STORAGE_SIDE_OPTS are defined per side
+    fn side_name(&self, name: &str) -> Option<(&'a ArgMatches, String)> {
+        match self.side {
+            Some((m, side)) if STORAGE_SIDE_OPTS.iter().any(|(n, _)| *n == name) => {
+                Some((m, format!("{}_{}", side, name)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<&'a str> {
+        self.side_name(name)
+            .and_then(|(m, n)| m.value_of(n.as_str()))
+            .or_else(|| self.opts.value_of(name))
+    }
+
+    pub fn is_present(&self, name: &str) -> bool {
+        self.side_name(name)
+            .is_some_and(|(m, n)| m.is_present(n.as_str()))
+            || self.opts.is_present(name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    pub id: String,
+    pub modified: DateTime<Utc>,
+}
+
+// Pre-signed request for writing an object straight to storage, along with the headers the
+// client has to send with it
+#[derive(Clone, Debug, Serialize)]
+pub struct SignedUpload {
+    pub url: String,
+    pub headers: HashMap<String, String>,
+}
 
 #[async_trait]
 #[enum_dispatch(StorageClient)]
@@ -12,17 +127,161 @@ pub trait Storage {
     async fn insert_object<'a>(
         &mut self,
         id: &'a str,
-        data: Vec<u8>,
+        data: ObjectStream,
         content_type: &str,
         metadata: &HashMap<String, String>
     ) -> Result<&'a str, RestError>;
-    async fn fetch_object(&self, id: &str) -> Result<Vec<u8>, RestError>;
+    async fn fetch_object(&self, id: &str) -> Result<ObjectStream, RestError>;
+    // Read part of an object, end is inclusive as in HTTP ranges
+    async fn fetch_range(&self, id: &str, start: u64, end: u64) -> Result<ObjectStream, RestError>;
+    // Deleting an object that doesn't exist succeeds, as it does in S3
     async fn delete_object(&self, id: &str) -> Result<(), RestError>;
+    async fn list_objects(&self) -> Result<ObjectListing, RestError>;
+    // Short-lived URL for downloading an object straight from storage, if the backend can sign one
+    async fn signed_url(&self, id: &str, seconds: u64) -> Result<Option<String>, RestError>;
+    // Short-lived URL for writing an object straight to storage, if the backend can sign one
+    async fn signed_upload(
+        &self,
+        id: &str,
+        seconds: u64,
+        content_type: &str,
+    ) -> Result<Option<SignedUpload>, RestError>;
+    async fn object_size(&self, id: &str) -> Result<u64, RestError>;
 }
 
 #[derive(Clone, Debug)]
 #[enum_dispatch]
+#[allow(clippy::enum_variant_names)]
 pub enum StorageClient {
     GcsClient(GcsClient),
     AzureBlobClient(AzureBlobClient),
+    LocalClient(LocalClient),
+    S3Client(S3Client),
+    MemoryClient(MemoryClient),
+    MirrorClient(MirrorClient),
+}
+
+impl StorageClient {
+    // Build a client for the requested backend, or detect one from the available credentials
+    pub async fn from_opts(
+        kind: Option<&str>,
+        opts: &ArgMatches,
+    ) -> Result<StorageClient, RestError> {
+        let kind = match kind {
+            Some(k) => k,
+            None => StorageClient::detect(opts)?,
+        };
+        log::info!("\"Using {} storage backend\"", kind);
+        let primary =
+            StorageClient::build(kind, opts.value_of("bucket"), StorageOpts::new(opts)).await?;
+
+        // Optionally mirror every object into a second backend
+        match opts.value_of("mirror") {
+            Some(mirror) => {
+                log::info!("\"Mirroring objects to {} storage backend\"", mirror);
+                let bucket = opts.value_of("mirror_bucket").or(opts.value_of("bucket"));
+                let secondary =
+                    StorageClient::build(mirror, bucket, StorageOpts::new(opts)).await?;
+                Ok(StorageClient::MirrorClient(MirrorClient::new(
+                    primary,
+                    secondary,
+                    opts.is_present("mirror_async"),
+                )))
+            }
+            None => Ok(primary),
+        }
+    }
+
+    pub async fn build(
+        kind: &str,
+        bucket: Option<&str>,
+        opts: StorageOpts<'_>,
+    ) -> Result<StorageClient, RestError> {
+        let client = match kind {
+            "memory" => StorageClient::MemoryClient(MemoryClient::new()),
+            "local" => {
+                let path = match (opts.value_of("storage_path"), opts.value_of("data_dir")) {
+                    (Some(path), _) => path.to_owned(),
+                    (None, Some(dir)) => {
+                        Path::new(dir).join(DATA_DIR_OBJECTS).display().to_string()
+                    }
+                    (None, None) => {
+                        return Err(RestError::StorageConfig(
+                            "Set --storage_path or --data_dir for local storage".to_owned(),
+                        ))
+                    }
+                };
+                StorageClient::LocalClient(LocalClient::new(&path, bucket.unwrap_or_default())?)
+            }
+            "gcs" => {
+                StorageClient::GcsClient(GcsClient::from_opts(StorageClient::bucket(bucket)?, opts))
+            }
+            "azure" => StorageClient::AzureBlobClient(AzureBlobClient::from_opts(
+                StorageClient::bucket(bucket)?,
+                opts,
+            )?),
+            "s3" => {
+                let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+                if let Some(region) = opts.value_of("s3_region") {
+                    loader = loader.region(aws_config::Region::new(region.to_owned()));
+                }
+                if let (Some(key_id), Some(secret)) = (
+                    opts.value_of("s3_access_key_id"),
+                    opts.value_of("s3_secret_access_key"),
+                ) {
+                    loader = loader.credentials_provider(aws_sdk_s3::config::Credentials::new(
+                        key_id, secret, None, None, "tackd",
+                    ));
+                }
+                let sdk_config = loader.load().await;
+
+                let mut s3_config = aws_sdk_s3::config::Builder::from(&sdk_config)
+                    .force_path_style(opts.is_present("s3_path_style"));
+                if let Some(endpoint) = opts.value_of("s3_endpoint") {
+                    s3_config = s3_config.endpoint_url(endpoint);
+                }
+                StorageClient::S3Client(S3Client::new(
+                    StorageClient::bucket(bucket)?,
+                    aws_sdk_s3::Client::from_conf(s3_config.build()),
+                ))
+            }
+            _ => {
+                return Err(RestError::StorageConfig(format!(
+                    "Unknown storage backend: {}",
+                    kind
+                )))
+            }
+        };
+        Ok(client)
+    }
+
+    fn detect(opts: &ArgMatches) -> Result<&'static str, RestError> {
+        if opts.value_of("storage_path").is_some() || opts.value_of("data_dir").is_some() {
+            Ok("local")
+        } else if opts.value_of("gcs_endpoint").is_some()
+            || std::env::var("SERVICE_ACCOUNT_JSON").is_ok()
+            || std::env::var("GOOGLE_APPLICATION_CREDENTIALS").is_ok()
+        {
+            Ok("gcs")
+        } else if opts.value_of("azure_endpoint").is_some()
+            || opts.value_of("azure_connection_string").is_some()
+            || (opts.value_of("azure_storage_account").is_some()
+                && (opts.value_of("azure_storage_access_key").is_some()
+                    || opts.value_of("azure_sas_token").is_some()))
+        {
+            Ok("azure")
+        } else if opts.value_of("s3_endpoint").is_some() || opts.value_of("s3_region").is_some() {
+            Ok("s3")
+        } else {
+            Err(RestError::StorageConfig(
+                "No storage credentials found".to_owned(),
+            ))
+        }
+    }
+
+    fn bucket(bucket: Option<&str>) -> Result<&str, RestError> {
+        bucket.ok_or_else(|| {
+            RestError::StorageConfig("Set --bucket for cloud storage backends".to_owned())
+        })
+    }
 }
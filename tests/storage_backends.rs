@@ -0,0 +1,183 @@
+// Round trips through a running tackd against each storage backend. The emulator backed tests
+// only run when their endpoint is set, as in the emulators workflow, and are skipped otherwise.
+use rand::RngCore;
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+struct Server {
+    child: Child,
+    url: String,
+    _data_dir: TempDir,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct TempDir(std::path::PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+async fn start(args: &[&str]) -> Server {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let data_dir = TempDir(std::env::temp_dir().join(format!("tackd-test-{}", port)));
+    let child = Command::new(env!("CARGO_BIN_EXE_tackd"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--data_dir")
+        .arg(&data_dir.0)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Unable to start tackd");
+    let server = Server {
+        child,
+        url: format!("http://127.0.0.1:{}", port),
+        _data_dir: data_dir,
+    };
+
+    let client = reqwest::Client::new();
+    for _ in 0..100 {
+        if let Ok(response) = client.get(format!("{}/health", server.url)).send().await {
+            if response.status().is_success() {
+                return server;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("tackd did not become healthy");
+}
+
+async fn roundtrip(args: &[&str]) {
+    let server = start(args).await;
+    let client = reqwest::Client::new();
+
+    // Spans several encryption chunks, so ranges cross chunk boundaries
+    let mut payload = vec![0u8; 200_000];
+    rand::thread_rng().fill_bytes(&mut payload);
+
+    let response = client
+        .post(format!("{}/upload", server.url))
+        .body(payload.clone())
+        .send()
+        .await
+        .unwrap();
+    assert!(
+        response.status().is_success(),
+        "upload: {}",
+        response.status()
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    let id = body["message"]["data"]["id"].as_str().unwrap().to_owned();
+    let download = format!("{}/download/{}", server.url, id);
+
+    let response = client.get(&download).send().await.unwrap();
+    assert!(
+        response.status().is_success(),
+        "download: {}",
+        response.status()
+    );
+    assert_eq!(response.bytes().await.unwrap().as_ref(), &payload[..]);
+
+    let response = client
+        .get(&download)
+        .header("range", "bytes=65000-140999")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(
+        response.bytes().await.unwrap().as_ref(),
+        &payload[65000..141000]
+    );
+
+    let response = client
+        .get(format!("{}/download/{}", server.url, uuid::Uuid::new_v4()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+fn bucket() -> String {
+    std::env::var("TACKD_TEST_BUCKET").unwrap_or_else(|_| "tackd".to_owned())
+}
+
+#[tokio::test]
+async fn local_roundtrip() {
+    roundtrip(&["--storage", "local"]).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn gcs_emulator_roundtrip() {
+    let endpoint =
+        std::env::var("TACKD_TEST_GCS_ENDPOINT").expect("TACKD_TEST_GCS_ENDPOINT is set");
+    let bucket = bucket();
+    roundtrip(&[
+        "--storage",
+        "gcs",
+        "--gcs_endpoint",
+        &endpoint,
+        "--bucket",
+        &bucket,
+    ])
+    .await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn azure_emulator_roundtrip() {
+    let endpoint =
+        std::env::var("TACKD_TEST_AZURE_ENDPOINT").expect("TACKD_TEST_AZURE_ENDPOINT is set");
+    let bucket = bucket();
+    roundtrip(&[
+        "--storage",
+        "azure",
+        "--azure_endpoint",
+        &endpoint,
+        "--bucket",
+        &bucket,
+    ])
+    .await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn s3_emulator_roundtrip() {
+    let endpoint = std::env::var("TACKD_TEST_S3_ENDPOINT").expect("TACKD_TEST_S3_ENDPOINT is set");
+    let key_id =
+        std::env::var("TACKD_TEST_S3_ACCESS_KEY_ID").unwrap_or_else(|_| "minioadmin".to_owned());
+    let secret = std::env::var("TACKD_TEST_S3_SECRET_ACCESS_KEY")
+        .unwrap_or_else(|_| "minioadmin".to_owned());
+    let bucket = bucket();
+    roundtrip(&[
+        "--storage",
+        "s3",
+        "--s3_endpoint",
+        &endpoint,
+        "--s3_region",
+        "us-east-1",
+        "--s3_path_style",
+        "--s3_access_key_id",
+        &key_id,
+        "--s3_secret_access_key",
+        &secret,
+        "--bucket",
+        &bucket,
+    ])
+    .await;
+}